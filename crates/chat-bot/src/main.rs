@@ -0,0 +1,110 @@
+//! Example bot answering `!roll` and running `!standup`s
+
+mod roll;
+mod standup;
+
+use anyhow::Result;
+use chat_core::{config, run_bot, start_chat_client, Bot, BotAction, BotCommand, ChatMessage, PeerEvent, Settings};
+use clap::Parser;
+use standup::Standups;
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Parser)]
+#[command(name = "chat-bot")]
+#[command(about = "Example P2P chat bot with dice rolls and standups")]
+struct Args {
+    /// Port to listen on (default: chosen by the OS)
+    #[arg(short, long, env = "P2P_CHAT_PORT")]
+    port: Option<u16>,
+
+    /// Name the bot chats under (default: bot)
+    #[arg(short, long, env = "P2P_CHAT_USERNAME")]
+    username: Option<String>,
+
+    /// Bootstrap node address; repeat for several (default: built-in bootstrap node)
+    #[arg(short, long, env = "P2P_CHAT_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Vec<String>,
+
+    /// Path to the bot's keypair file (default: bot_key.dat)
+    #[arg(short, long, env = "P2P_CHAT_KEY_FILE")]
+    key_file: Option<String>,
+
+    /// Settings file (default: config.toml in the user's config directory, if present)
+    #[arg(long, env = "P2P_CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Profile from the settings file, e.g. to run a second identity
+    #[arg(long, env = "P2P_CHAT_PROFILE")]
+    profile: Option<String>,
+
+    /// Extra room to join besides the default one; may be repeated
+    #[arg(short, long = "room")]
+    rooms: Vec<String>,
+}
+
+struct ExampleBot {
+    rooms: Vec<String>,
+    standups: Standups,
+}
+
+impl Bot for ExampleBot {
+    fn on_start(&mut self) -> Vec<BotAction> {
+        self.rooms.iter().cloned().map(BotAction::JoinRoom).collect()
+    }
+
+    fn on_command(&mut self, command: &BotCommand, message: &ChatMessage) -> Vec<BotAction> {
+        match command.name.as_str() {
+            "roll" => {
+                let text = match roll::roll(&command.rest, &mut rand::thread_rng()) {
+                    Ok(result) => format!("{} rolled {}", message.sender, result),
+                    Err(e) => e.to_string(),
+                };
+                BotAction::respond(message, text).into_iter().collect()
+            }
+            "standup" => self.standups.on_command(command, message),
+            "help" => BotAction::respond(message, "Commands: !roll [NdM+K], !standup start|<update>|end").into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_peer_event(&mut self, event: &PeerEvent) -> Vec<BotAction> {
+        match event {
+            PeerEvent::JoinedRoom { room, .. } => self.standups.on_room_joined(room),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    // Built-in defaults, then the settings file, then environment and flags
+    let defaults = Settings {
+        username: Some("bot".to_string()),
+        key_file: Some("bot_key.dat".to_string()),
+        ..Default::default()
+    };
+    let flags = Settings {
+        username: args.username,
+        key_file: args.key_file,
+        port: args.port,
+        bootstrap: (!args.bootstrap.is_empty()).then_some(args.bootstrap),
+        ..Default::default()
+    };
+    let settings = defaults.merge(config::load(args.config.as_deref(), args.profile.as_deref())?).merge(flags);
+    let username = settings.username.clone().unwrap_or_default();
+    let config = settings.network_config()?;
+
+    let (client, events) = start_chat_client(config, username).await?;
+    let mut bot = ExampleBot { rooms: args.rooms, standups: Standups::default() };
+
+    tokio::select! {
+        result = run_bot(&mut bot, &client, events) => result?,
+        _ = tokio::signal::ctrl_c() => info!("Shutting down"),
+    }
+    Ok(client.shutdown().await?)
+}
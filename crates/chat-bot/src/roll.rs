@@ -0,0 +1,84 @@
+//! `!roll` dice command
+
+use anyhow::{anyhow, Result};
+use rand::Rng;
+use std::fmt;
+
+const MAX_DICE: u32 = 100;
+const MAX_SIDES: u32 = 1000;
+const MAX_MODIFIER: i64 = 1_000_000;
+
+/// Outcome of rolling dice written like `2d6+1`
+#[derive(Debug)]
+pub struct Roll {
+    pub spec: String,
+    pub rolls: Vec<u32>,
+    pub modifier: i64,
+}
+
+impl Roll {
+    pub fn total(&self) -> i64 {
+        self.rolls.iter().map(|&r| r as i64).sum::<i64>() + self.modifier
+    }
+}
+
+impl fmt::Display for Roll {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "🎲 {}: {:?}", self.spec, self.rolls)?;
+        if self.modifier != 0 {
+            write!(f, " {:+}", self.modifier)?;
+        }
+        write!(f, " = {}", self.total())
+    }
+}
+
+/// Roll dice for a spec like `d20`, `3d6` or `2d8-1`; an empty spec rolls `1d6`
+pub fn roll(spec: &str, rng: &mut impl Rng) -> Result<Roll> {
+    let spec = if spec.trim().is_empty() { "1d6" } else { spec.trim() };
+    let invalid = || anyhow!("Can't roll '{spec}', try something like 2d6+1");
+
+    let (count, rest) = spec.split_once(['d', 'D']).ok_or_else(invalid)?;
+    let (sides, modifier) = match rest.find(['+', '-']) {
+        Some(at) => (&rest[..at], rest[at..].parse::<i64>().map_err(|_| invalid())?),
+        None => (rest, 0),
+    };
+    let count = if count.is_empty() { 1 } else { count.parse::<u32>().map_err(|_| invalid())? };
+    let sides = sides.parse::<u32>().map_err(|_| invalid())?;
+    if !(1..=MAX_DICE).contains(&count) || !(2..=MAX_SIDES).contains(&sides) {
+        return Err(anyhow!("Roll at most {MAX_DICE} dice with 2 to {MAX_SIDES} sides"));
+    }
+    if modifier.unsigned_abs() > MAX_MODIFIER.unsigned_abs() {
+        return Err(anyhow!("Keep the modifier within ±{MAX_MODIFIER}"));
+    }
+
+    Ok(Roll {
+        spec: spec.to_string(),
+        rolls: (0..count).map(|_| rng.gen_range(1..=sides)).collect(),
+        modifier,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{rngs::StdRng, SeedableRng};
+
+    #[test]
+    fn test_roll_specs() {
+        let mut rng = StdRng::seed_from_u64(7);
+
+        let result = roll("3d6+2", &mut rng).unwrap();
+        assert_eq!(result.rolls.len(), 3);
+        assert!(result.rolls.iter().all(|r| (1..=6).contains(r)));
+        assert_eq!(result.total(), result.rolls.iter().sum::<u32>() as i64 + 2);
+
+        assert_eq!(roll("d20", &mut rng).unwrap().rolls.len(), 1);
+        assert_eq!(roll("", &mut rng).unwrap().spec, "1d6");
+        assert!(roll("0d6", &mut rng).is_err());
+        assert!(roll("2d1", &mut rng).is_err());
+        assert!(roll("banana", &mut rng).is_err());
+        assert!(roll("2d6+9223372036854775807", &mut rng).is_err());
+        assert!(roll("2d6-9223372036854775808", &mut rng).is_err());
+        assert_eq!(roll("1d6-1000000", &mut rng).unwrap().modifier, -1_000_000);
+    }
+}
@@ -0,0 +1,77 @@
+//! `!standup` command: collect one update per person and post a summary
+
+use chat_core::{BotAction, BotCommand, ChatMessage, MessageType};
+use std::collections::HashMap;
+
+const USAGE: &str = "Usage: !standup start | !standup <your update> | !standup end";
+
+/// A standup running in one room
+#[derive(Debug)]
+struct Standup {
+    started_by: String,
+    /// Latest update from each participant, in the order they first posted
+    updates: Vec<(String, String)>,
+}
+
+/// Open standups by room
+#[derive(Debug, Default)]
+pub struct Standups {
+    open: HashMap<String, Standup>,
+}
+
+impl Standups {
+    pub fn on_command(&mut self, command: &BotCommand, message: &ChatMessage) -> Vec<BotAction> {
+        if matches!(message.message_type, MessageType::Direct { .. }) {
+            return BotAction::respond(message, "Standups run in rooms, not direct messages").into_iter().collect();
+        }
+        let room = message.room_name().to_string();
+
+        match command.args.first().map(String::as_str) {
+            None => BotAction::respond(message, USAGE).into_iter().collect(),
+            Some("start") => {
+                if self.open.contains_key(&room) {
+                    return BotAction::respond(message, "A standup is already running here").into_iter().collect();
+                }
+                self.open.insert(room.clone(), Standup { started_by: message.sender.clone(), updates: Vec::new() });
+                let text = format!("📋 Standup started by {}. Post with !standup <update>, finish with !standup end", message.sender);
+                vec![BotAction::Send { room, text }]
+            }
+            Some("end") => match self.open.remove(&room) {
+                Some(standup) => vec![BotAction::Send { room, text: summary(&standup) }],
+                None => BotAction::respond(message, "No standup is running here").into_iter().collect(),
+            },
+            Some(_) => {
+                let Some(standup) = self.open.get_mut(&room) else {
+                    return BotAction::respond(message, "No standup is running here, start one with !standup start").into_iter().collect();
+                };
+                match standup.updates.iter_mut().find(|(sender, _)| *sender == message.sender) {
+                    Some((_, update)) => *update = command.rest.clone(),
+                    None => standup.updates.push((message.sender.clone(), command.rest.clone())),
+                }
+                vec![BotAction::React { target_id: message.id.clone(), emoji: "✅".to_string() }]
+            }
+        }
+    }
+
+    /// Point people arriving mid-standup at it
+    pub fn on_room_joined(&self, room: &str) -> Vec<BotAction> {
+        match self.open.get(room) {
+            Some(standup) => vec![BotAction::Send {
+                room: room.to_string(),
+                text: format!("👋 {} is running a standup, post yours with !standup <update>", standup.started_by),
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+fn summary(standup: &Standup) -> String {
+    if standup.updates.is_empty() {
+        return "📋 Standup ended with no updates".to_string();
+    }
+    let mut text = format!("📋 Standup summary ({} updates):", standup.updates.len());
+    for (sender, update) in &standup.updates {
+        text.push_str(&format!("\n• {sender}: {update}"));
+    }
+    text
+}
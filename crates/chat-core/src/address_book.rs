@@ -0,0 +1,248 @@
+//! Peers remembered across restarts, so we can rejoin the network without
+//! the bootstrap node
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Forget peers not seen for this many seconds
+pub const MAX_PEER_AGE_SECS: u64 = 14 * 24 * 60 * 60;
+/// Forget peers we failed to reach this many times in a row
+pub const MAX_CONSECUTIVE_FAILURES: u32 = 10;
+/// Peers kept at most; the least recently seen are forgotten first
+pub const MAX_KNOWN_PEERS: usize = 512;
+/// Addresses kept per peer
+const MAX_ADDRESSES: usize = 8;
+/// Shortest time between writes, as every connection changes the book
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A peer we have been connected to before
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnownPeer {
+    pub peer_id: String,
+    /// Listen addresses the peer reported through identify
+    pub addresses: Vec<String>,
+    pub last_seen: u64,
+    pub successes: u32,
+    pub failures: u32,
+    /// Failed dials since the last successful connection
+    pub consecutive_failures: u32,
+}
+
+impl KnownPeer {
+    /// Whether the peer is too old or too unreliable to keep
+    pub fn is_stale(&self, now: u64) -> bool {
+        now.saturating_sub(self.last_seen) > MAX_PEER_AGE_SECS
+            || self.consecutive_failures >= MAX_CONSECUTIVE_FAILURES
+    }
+}
+
+/// Known peers, optionally persisted to a file
+#[derive(Debug)]
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: HashMap<String, KnownPeer>,
+    dirty: bool,
+    last_save: Instant,
+}
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self { path: None, peers: HashMap::new(), dirty: false, last_save: Instant::now() }
+    }
+}
+
+impl AddressBook {
+    /// Open the address book stored at `path`, or an in-memory one if `None`
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path.map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+
+        let peers: Vec<KnownPeer> = if path.exists() {
+            // Only a cache, so a damaged file shouldn't keep us offline
+            serde_json::from_slice(&fs::read(&path)?).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable known peers file {}: {}", path.display(), e);
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        let mut book = Self {
+            path: Some(path),
+            peers: peers.into_iter().map(|peer| (peer.peer_id.clone(), peer)).collect(),
+            ..Self::default()
+        };
+        book.age(now());
+        if !book.peers.is_empty() {
+            info!("Loaded {} known peers", book.peers.len());
+        }
+        Ok(book)
+    }
+
+    /// Remember the addresses a peer listens on
+    pub fn record_addresses(&mut self, peer_id: &str, addresses: Vec<String>) {
+        let peer = self.entry(peer_id);
+        let mut addresses = addresses;
+        addresses.truncate(MAX_ADDRESSES);
+        if peer.addresses != addresses {
+            peer.addresses = addresses;
+            self.save();
+        }
+    }
+
+    /// Record a successful connection to a peer
+    pub fn record_connected(&mut self, peer_id: &str) {
+        let peer = self.entry(peer_id);
+        peer.last_seen = now();
+        peer.successes += 1;
+        peer.consecutive_failures = 0;
+        self.save();
+    }
+
+    /// Record the last moment a peer was connected
+    pub fn record_seen(&mut self, peer_id: &str) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.last_seen = now();
+            self.save();
+        }
+    }
+
+    /// Record a failed dial to a peer we already know
+    pub fn record_failure(&mut self, peer_id: &str) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.failures += 1;
+            peer.consecutive_failures += 1;
+            self.save();
+        }
+    }
+
+    /// Up to `limit` peers worth redialing, most recently seen first
+    pub fn recent(&self, limit: usize) -> Vec<&KnownPeer> {
+        let mut peers: Vec<&KnownPeer> = self.peers.values().filter(|peer| !peer.addresses.is_empty()).collect();
+        peers.sort_by_key(|peer| std::cmp::Reverse(peer.last_seen));
+        peers.truncate(limit);
+        peers
+    }
+
+    pub fn get(&self, peer_id: &str) -> Option<&KnownPeer> {
+        self.peers.get(peer_id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Write the address book to disk, reporting failures to the caller
+    pub fn flush(&mut self) -> Result<()> {
+        self.dirty = false;
+        self.last_save = Instant::now();
+        self.age(now());
+        match &self.path {
+            Some(path) => write_peers(path, &self.peers),
+            None => Ok(()),
+        }
+    }
+
+    /// Forget stale peers, and the least recently seen ones beyond
+    /// [`MAX_KNOWN_PEERS`]
+    fn age(&mut self, now: u64) {
+        self.peers.retain(|_, peer| !peer.is_stale(now));
+        if self.peers.len() > MAX_KNOWN_PEERS {
+            let mut last_seen: Vec<u64> = self.peers.values().map(|peer| peer.last_seen).collect();
+            last_seen.sort_unstable_by_key(|seen| std::cmp::Reverse(*seen));
+            let cutoff = last_seen[MAX_KNOWN_PEERS - 1];
+            self.peers.retain(|_, peer| peer.last_seen >= cutoff);
+        }
+    }
+
+    fn entry(&mut self, peer_id: &str) -> &mut KnownPeer {
+        self.peers.entry(peer_id.to_string()).or_insert_with(|| KnownPeer {
+            peer_id: peer_id.to_string(),
+            addresses: Vec::new(),
+            last_seen: now(),
+            successes: 0,
+            failures: 0,
+            consecutive_failures: 0,
+        })
+    }
+
+    /// Note a change, writing it out if the last save was a while ago.
+    /// Failures are logged rather than returned, since it is only a cache.
+    fn save(&mut self) {
+        self.dirty = true;
+        if self.path.is_some() && self.last_save.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.flush() {
+                warn!("Failed to save known peers: {}", e);
+            }
+        }
+    }
+
+    /// Whether there are changes not yet on disk
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+fn write_peers(path: &Path, peers: &HashMap<String, KnownPeer>) -> Result<()> {
+    let peers: Vec<&KnownPeer> = peers.values().collect();
+    // Write a sibling file and rename it, so readers never see half a file
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp, serde_json::to_vec(&peers)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_peers_and_aging() {
+        let mut book = AddressBook::default();
+        book.record_addresses("old", vec!["/ip4/10.0.0.1/tcp/4001".to_string()]);
+        book.record_connected("old");
+        book.record_addresses("new", vec!["/ip4/10.0.0.2/tcp/4001".to_string()]);
+        book.record_connected("new");
+        book.record_connected("no-address");
+        book.peers.get_mut("old").unwrap().last_seen -= 60;
+
+        let recent: Vec<&str> = book.recent(10).iter().map(|peer| peer.peer_id.as_str()).collect();
+        assert_eq!(recent, ["new", "old"]);
+
+        for _ in 0..MAX_CONSECUTIVE_FAILURES {
+            book.record_failure("new");
+        }
+        book.peers.get_mut("old").unwrap().last_seen -= MAX_PEER_AGE_SECS;
+        book.age(now());
+        assert_eq!(book.len(), 1);
+        assert!(book.get("no-address").is_some());
+        assert_eq!(book.get("no-address").unwrap().successes, 1);
+
+        // Changes are written in batches
+        let path = std::env::temp_dir().join(format!("peers-{}.json", uuid::Uuid::new_v4()));
+        let mut book = AddressBook::load(path.to_str()).unwrap();
+        book.record_connected("peer");
+        book.record_seen("peer");
+        assert!(book.is_dirty() && !path.exists());
+        book.flush().unwrap();
+        assert!(!book.is_dirty());
+        assert_eq!(AddressBook::load(path.to_str()).unwrap().len(), 1);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,140 @@
+//! Scheduling of DHT bootstrap rounds
+//!
+//! The routing table goes stale as peers come and go, so the network
+//! bootstraps at startup and then every [`DhtConfig::bootstrap_interval`].
+//! A failed round is retried sooner, backing off exponentially from
+//! [`BOOTSTRAP_RETRY_MIN`] up to the regular interval.
+//!
+//! [`DhtConfig::bootstrap_interval`]: crate::DhtConfig::bootstrap_interval
+
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Delay before retrying the first failed bootstrap
+pub const BOOTSTRAP_RETRY_MIN: Duration = Duration::from_secs(5);
+
+/// Default time between successful bootstrap rounds
+pub const DEFAULT_BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// What a successful round means for the application
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapProgress {
+    /// The first successful round since startup
+    First,
+    /// Success after this many failed rounds
+    Recovered { failures: u32 },
+    /// A routine refresh
+    Refreshed,
+}
+
+/// When the next bootstrap round is due
+#[derive(Debug)]
+pub struct BootstrapSchedule {
+    interval: Duration,
+    /// Failed rounds since the last success
+    failures: u32,
+    next: Instant,
+    running: bool,
+    /// Peers that answered during the running round
+    responses: u32,
+    succeeded_once: bool,
+}
+
+impl BootstrapSchedule {
+    /// A schedule whose first round is due immediately
+    pub fn new(interval: Duration) -> Self {
+        Self { interval, failures: 0, next: Instant::now(), running: false, responses: 0, succeeded_once: false }
+    }
+
+    /// When to start the next round; far in the future while one is running
+    pub fn next_round(&self) -> Instant {
+        if self.running {
+            Instant::now() + self.interval.max(BOOTSTRAP_RETRY_MIN)
+        } else {
+            self.next
+        }
+    }
+
+    /// Whether a round is in progress
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Failed rounds since the last success
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+
+    /// Whether the last round succeeded; bootstrap nodes only known by
+    /// address need dialing when it didn't
+    pub fn is_healthy(&self) -> bool {
+        self.succeeded_once && self.failures == 0
+    }
+
+    /// Record that a round has started
+    pub fn started(&mut self) {
+        self.running = true;
+        self.responses = 0;
+    }
+
+    /// Record peers answering one step of the running round
+    pub fn responded(&mut self, count: u32) {
+        self.responses += count;
+    }
+
+    /// Peers that answered during the running round
+    pub fn responses(&self) -> u32 {
+        self.responses
+    }
+
+    /// Record a successful round
+    pub fn succeeded(&mut self) -> BootstrapProgress {
+        let progress = match (self.succeeded_once, self.failures) {
+            (false, _) => BootstrapProgress::First,
+            (true, 0) => BootstrapProgress::Refreshed,
+            (true, failures) => BootstrapProgress::Recovered { failures },
+        };
+        self.succeeded_once = true;
+        self.failures = 0;
+        self.running = false;
+        self.next = Instant::now() + self.interval;
+        progress
+    }
+
+    /// Record a failed round, returning how long until the retry
+    pub fn failed(&mut self) -> Duration {
+        let delay = BOOTSTRAP_RETRY_MIN
+            .saturating_mul(1 << self.failures.min(16))
+            .min(self.interval.max(BOOTSTRAP_RETRY_MIN));
+        self.failures += 1;
+        self.running = false;
+        self.next = Instant::now() + delay;
+        delay
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_doubles_up_to_interval() {
+        let mut schedule = BootstrapSchedule::new(Duration::from_secs(60));
+        assert!(schedule.next_round() <= Instant::now());
+
+        let delays: Vec<u64> = (0..6).map(|_| schedule.failed().as_secs()).collect();
+        assert_eq!(delays, [5, 10, 20, 40, 60, 60]);
+        assert_eq!(schedule.failures(), 6);
+
+        schedule.started();
+        assert!(schedule.next_round() > Instant::now() + Duration::from_secs(59));
+        schedule.responded(2);
+        assert_eq!(schedule.responses(), 2);
+        assert_eq!(schedule.succeeded(), BootstrapProgress::First);
+        assert!(schedule.is_healthy());
+        assert_eq!(schedule.succeeded(), BootstrapProgress::Refreshed);
+
+        assert_eq!(schedule.failed(), BOOTSTRAP_RETRY_MIN);
+        assert_eq!(schedule.succeeded(), BootstrapProgress::Recovered { failures: 1 });
+    }
+}
@@ -0,0 +1,204 @@
+//! Bot API on top of [`ChatClient`]
+//!
+//! A bot implements [`Bot`] and returns [`BotAction`]s from its hooks;
+//! [`run_bot`] feeds it network events and carries out the actions.
+//! Messages starting with the bot's command prefix are parsed into a
+//! [`BotCommand`] and go to [`Bot::on_command`] instead of [`Bot::on_message`].
+
+use anyhow::Result;
+use tracing::{debug, warn};
+
+use crate::types::{ChatMessage, MessageType, NetworkEvent};
+use crate::events::EventStream;
+use crate::ChatClient;
+
+/// Prefix marking a message as a bot command unless the bot picks another
+pub const DEFAULT_COMMAND_PREFIX: &str = "!";
+
+/// Something a bot wants done in response to an event
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BotAction {
+    /// Broadcast a message to a room
+    Send { room: String, text: String },
+    /// Reply to a message, in the message's room
+    Reply { reply_to: String, text: String },
+    /// Send a direct message to a peer
+    Direct { peer_id: String, text: String },
+    /// React to a message with an emoji
+    React { target_id: String, emoji: String },
+    JoinRoom(String),
+    LeaveRoom(String),
+}
+
+impl BotAction {
+    /// Answer `message` where it was sent: by direct message if it was one,
+    /// otherwise with a reply in its room. Unsigned direct messages get no
+    /// answer, since a reply would show the private exchange to the room.
+    pub fn respond(message: &ChatMessage, text: impl Into<String>) -> Option<Self> {
+        match (&message.message_type, &message.sender_peer_id) {
+            (MessageType::Direct { .. }, Some(peer_id)) => {
+                Some(Self::Direct { peer_id: peer_id.clone(), text: text.into() })
+            }
+            (MessageType::Direct { .. }, None) => None,
+            _ => Some(Self::Reply { reply_to: message.id.clone(), text: text.into() }),
+        }
+    }
+}
+
+/// A command sent to a bot, e.g. `!roll 2d6`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BotCommand {
+    /// Command name without the prefix, e.g. `roll`
+    pub name: String,
+    /// Whitespace-separated arguments
+    pub args: Vec<String>,
+    /// Everything after the command name, untouched
+    pub rest: String,
+}
+
+/// Splits messages starting with a prefix into commands
+#[derive(Debug, Clone)]
+pub struct CommandDispatcher {
+    prefix: String,
+}
+
+impl CommandDispatcher {
+    pub fn new(prefix: impl Into<String>) -> Self {
+        Self { prefix: prefix.into() }
+    }
+
+    /// Parse `content` as a command, or `None` if it isn't one
+    pub fn parse(&self, content: &str) -> Option<BotCommand> {
+        let command = content.trim().strip_prefix(&self.prefix)?;
+        let (name, rest) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        if name.is_empty() {
+            return None;
+        }
+        let rest = rest.trim().to_string();
+        Some(BotCommand {
+            name: name.to_lowercase(),
+            args: rest.split_whitespace().map(str::to_string).collect(),
+            rest,
+        })
+    }
+}
+
+/// Peer activity a bot can react to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    Connected(String),
+    Disconnected(String),
+    JoinedRoom { room: String, peer_id: String },
+    LeftRoom { room: String, peer_id: String },
+}
+
+/// A chat bot. Every hook defaults to doing nothing.
+pub trait Bot: Send {
+    /// Prefix that marks a message as a command for this bot
+    fn command_prefix(&self) -> &str {
+        DEFAULT_COMMAND_PREFIX
+    }
+
+    /// Called once before any events, e.g. to join rooms
+    fn on_start(&mut self) -> Vec<BotAction> {
+        Vec::new()
+    }
+
+    /// Called for every received message that isn't a command
+    fn on_message(&mut self, _message: &ChatMessage) -> Vec<BotAction> {
+        Vec::new()
+    }
+
+    /// Called for received messages starting with [`Bot::command_prefix`]
+    fn on_command(&mut self, _command: &BotCommand, _message: &ChatMessage) -> Vec<BotAction> {
+        Vec::new()
+    }
+
+    /// Called when peers connect, disconnect, join or leave rooms
+    fn on_peer_event(&mut self, _event: &PeerEvent) -> Vec<BotAction> {
+        Vec::new()
+    }
+}
+
+async fn perform(client: &ChatClient, action: BotAction) -> crate::error::Result<()> {
+    match action {
+        BotAction::Send { room, text } => client.send_to_room(room, text).await,
+        BotAction::Reply { reply_to, text } => client.send_reply(reply_to, text).await,
+        BotAction::Direct { peer_id, text } => client.send_direct(peer_id, text).await,
+        BotAction::React { target_id, emoji } => client.send_reaction(target_id, emoji).await,
+        BotAction::JoinRoom(room) => client.join_room(room).await,
+        BotAction::LeaveRoom(room) => client.leave_room(room).await,
+    }
+}
+
+/// Drive `bot` with events from `events` until the network stops
+pub async fn run_bot<B: Bot>(
+    bot: &mut B,
+    client: &ChatClient,
+    mut events: EventStream,
+) -> Result<()> {
+    let dispatcher = CommandDispatcher::new(bot.command_prefix());
+    let mut actions = bot.on_start();
+
+    loop {
+        for action in actions.drain(..) {
+            debug!("Bot action: {:?}", action);
+            if let Err(e) = perform(client, action).await {
+                warn!("Bot action failed: {}", e);
+            }
+        }
+
+        let Some(event) = events.recv().await else { break };
+        actions = match event {
+            NetworkEvent::MessageReceived { message, .. } if !message.is_reaction() => {
+                match dispatcher.parse(&message.content) {
+                    Some(command) => bot.on_command(&command, &message),
+                    None => bot.on_message(&message),
+                }
+            }
+            NetworkEvent::PeerConnected(peer_id) => bot.on_peer_event(&PeerEvent::Connected(peer_id)),
+            NetworkEvent::PeerDisconnected(peer_id) => bot.on_peer_event(&PeerEvent::Disconnected(peer_id)),
+            NetworkEvent::RoomPeerJoined { room, peer_id } => {
+                bot.on_peer_event(&PeerEvent::JoinedRoom { room, peer_id })
+            }
+            NetworkEvent::RoomPeerLeft { room, peer_id } => bot.on_peer_event(&PeerEvent::LeftRoom { room, peer_id }),
+            // Synced history is not answered, or every restart would replay old commands
+            _ => Vec::new(),
+        };
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_commands() {
+        let dispatcher = CommandDispatcher::new("!");
+        let command = dispatcher.parse("!Roll  2d6 +1").unwrap();
+        assert_eq!(command.name, "roll");
+        assert_eq!(command.args, vec!["2d6", "+1"]);
+        assert_eq!(command.rest, "2d6 +1");
+
+        assert_eq!(dispatcher.parse("!standup").unwrap().args, Vec::<String>::new());
+        assert!(dispatcher.parse("hello !roll").is_none());
+        assert!(dispatcher.parse("! roll").is_none());
+    }
+
+    #[test]
+    fn test_respond_where_asked() {
+        let direct = MessageType::Direct { target_peer_id: "12D3KooBot".to_string() };
+        let mut message = ChatMessage::new("alice".to_string(), "!help".to_string(), direct);
+        assert_eq!(BotAction::respond(&message, "hi"), None);
+
+        message.sender_peer_id = Some("12D3KooAlice".to_string());
+        assert_eq!(
+            BotAction::respond(&message, "hi"),
+            Some(BotAction::Direct { peer_id: "12D3KooAlice".to_string(), text: "hi".to_string() })
+        );
+
+        let message = ChatMessage::new("alice".to_string(), "!help".to_string(), MessageType::Broadcast);
+        assert!(matches!(BotAction::respond(&message, "hi"), Some(BotAction::Reply { .. })));
+    }
+}
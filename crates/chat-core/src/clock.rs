@@ -0,0 +1,114 @@
+//! Logical clocks for causal ordering of messages
+//!
+//! Wall-clock timestamps come from each sender's own clock and can't be
+//! trusted to order messages. Every message also carries a Lamport time:
+//! a sender ticks its clock before sending and advances it past every time
+//! it receives, so a reply always sorts after the message it answers.
+
+use std::cmp::Ordering;
+
+use crate::types::ChatMessage;
+
+/// Wall-clock deviation, in seconds, beyond which a message is flagged
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+/// Largest Lamport time accepted from peers. Far beyond anything honest
+/// peers reach, and far enough below `u64::MAX` that the clock never wraps.
+pub const MAX_LAMPORT_TIME: u64 = 1 << 48;
+
+/// A Lamport clock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LamportClock {
+    time: u64,
+}
+
+impl LamportClock {
+    /// Start a clock at `time`, e.g. the latest time seen in stored history
+    pub fn new(time: u64) -> Self {
+        Self { time: time.min(MAX_LAMPORT_TIME) }
+    }
+
+    /// Advance the clock for a local event and return the new time. The
+    /// clock stops at [`MAX_LAMPORT_TIME`], so a peer that saw the largest
+    /// accepted time still sends times others accept.
+    pub fn tick(&mut self) -> u64 {
+        self.time = (self.time + 1).min(MAX_LAMPORT_TIME);
+        self.time
+    }
+
+    /// Merge a time received from another peer; implausible times are
+    /// capped at [`MAX_LAMPORT_TIME`]
+    pub fn observe(&mut self, remote: u64) {
+        self.time = self.time.max(remote.min(MAX_LAMPORT_TIME));
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+}
+
+/// Whether a Lamport time received from a peer is one we accept
+pub fn is_plausible(lamport: u64) -> bool {
+    lamport <= MAX_LAMPORT_TIME
+}
+
+/// Order messages causally: by Lamport time, then by wall-clock time and id
+/// to break ties between concurrent messages the same way on every peer
+pub fn causal_order(a: &ChatMessage, b: &ChatMessage) -> Ordering {
+    (a.lamport, a.timestamp, &a.id).cmp(&(b.lamport, b.timestamp, &b.id))
+}
+
+/// How far a message's wall-clock timestamp is from `now`, in seconds
+/// (positive when the sender's clock is ahead), if beyond [`MAX_CLOCK_SKEW_SECS`]
+pub fn clock_skew(message: &ChatMessage, now: u64) -> Option<i64> {
+    let skew = i128::from(message.timestamp) - i128::from(now);
+    // Timestamps too far off to fit count as skewed by as much as we can say
+    let skew = i64::try_from(skew).unwrap_or(if skew < 0 { i64::MIN } else { i64::MAX });
+    (skew.unsigned_abs() > MAX_CLOCK_SKEW_SECS).then_some(skew)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageType;
+
+    #[test]
+    fn test_replies_order_after_skewed_parents() {
+        let mut alice = LamportClock::default();
+        let mut bob = LamportClock::default();
+
+        // Alice's wall clock runs ten minutes fast
+        let mut question = ChatMessage::new("alice".to_string(), "lunch?".to_string(), MessageType::Broadcast);
+        question.lamport = alice.tick();
+        question.timestamp += 600;
+
+        bob.observe(question.lamport);
+        let mut answer = ChatMessage::new("bob".to_string(), "yes".to_string(), MessageType::Broadcast);
+        answer.lamport = bob.tick();
+
+        assert!(answer.timestamp < question.timestamp);
+        assert_eq!(causal_order(&question, &answer), Ordering::Less);
+        assert_eq!(clock_skew(&question, answer.timestamp), Some(600));
+        assert_eq!(clock_skew(&answer, answer.timestamp), None);
+    }
+
+    #[test]
+    fn test_absurd_remote_values() {
+        let mut clock = LamportClock::new(u64::MAX);
+        assert_eq!(clock.time(), MAX_LAMPORT_TIME);
+        clock.observe(u64::MAX);
+        assert_eq!(clock.tick(), MAX_LAMPORT_TIME);
+
+        // A peer that saw the largest accepted time can still be heard
+        let mut bob = LamportClock::default();
+        assert!(is_plausible(MAX_LAMPORT_TIME));
+        bob.observe(MAX_LAMPORT_TIME);
+        assert!(is_plausible(bob.tick()));
+        assert!(is_plausible(bob.tick()));
+        assert!(!is_plausible(MAX_LAMPORT_TIME + 1));
+
+        let mut message = ChatMessage::new("mallory".to_string(), "hi".to_string(), MessageType::Broadcast);
+        message.timestamp = u64::MAX;
+        assert_eq!(clock_skew(&message, 0), Some(i64::MAX));
+    }
+}
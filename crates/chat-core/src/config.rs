@@ -0,0 +1,342 @@
+//! Settings file with named profiles
+//!
+//! Settings come from, in increasing priority: built-in defaults, the
+//! config file, the selected profile in it, and finally environment
+//! variables and command-line flags, which the binaries merge on top with
+//! [`Settings::merge`].
+//!
+//! ```toml
+//! username = "alice"
+//! bootstrap = ["/ip4/203.0.113.7/tcp/4001/p2p/12D3KooW..."]
+//! rooms = ["rust"]
+//!
+//! [limits]
+//! max_message_size = 131072
+//!
+//! [profiles.work]
+//! username = "alice-work"
+//! listen = ["/ip4/0.0.0.0/tcp/4002"]
+//! ```
+
+use anyhow::{bail, Context, Result};
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::{network::NetworkConfig, room, wire::WireFormat};
+
+/// Name of the config file inside the user's config directory
+pub const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Where the config file lives when none is given, e.g.
+/// `~/.config/p2p-chat/config.toml` on Linux
+pub fn default_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("p2p-chat").join(CONFIG_FILE_NAME))
+}
+
+/// Everything that can be set in the config file; unset fields fall back to
+/// the layer below
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub username: Option<String>,
+    pub key_file: Option<String>,
+    /// Addresses to listen on; takes the place of `port`
+    pub listen: Option<Vec<String>>,
+    /// TCP port to listen on all interfaces
+    pub port: Option<u16>,
+    pub bootstrap: Option<Vec<String>>,
+    /// Seconds between DHT bootstrap rounds
+    pub bootstrap_interval_secs: Option<u64>,
+    /// Seconds between random walks looking for more peers
+    pub discovery_interval_secs: Option<u64>,
+    /// Connected peers after which discovery pauses
+    pub target_peers: Option<usize>,
+    /// Rooms to join at startup besides the default one
+    pub rooms: Option<Vec<String>>,
+    /// Message history file; an empty string keeps history in memory only
+    pub history_file: Option<String>,
+    /// Outbox file; an empty string keeps queued messages in memory only
+    pub outbox_file: Option<String>,
+    /// Known peers file; an empty string keeps them in memory only
+    pub peers_file: Option<String>,
+    /// Pinned contacts file; an empty string keeps them in memory only
+    pub contacts_file: Option<String>,
+    /// File for the DHT records hosted for other peers; unset or empty
+    /// keeps them in memory only
+    pub dht_records_file: Option<String>,
+    pub transport: TransportSettings,
+    pub limits: LimitSettings,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransportSettings {
+    /// `legacy` (bare JSON, the default), `cbor` or `json`
+    pub wire_format: Option<WireFormat>,
+    pub tcp_nodelay: Option<bool>,
+    /// Seconds to keep a connection open once no protocol is using it
+    pub idle_connection_timeout_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitSettings {
+    pub max_message_size: Option<usize>,
+    /// Compress messages above this many bytes; 0 disables compression
+    pub compression_threshold: Option<usize>,
+    pub event_buffer: Option<usize>,
+    pub command_buffer: Option<usize>,
+    pub sync_max_backfill_secs: Option<u64>,
+    pub max_dht_records: Option<usize>,
+}
+
+impl Settings {
+    /// Layer `over` on top of these settings; whatever `over` sets wins
+    pub fn merge(self, over: Settings) -> Settings {
+        // `listen` and `port` are two ways of saying the same thing
+        let (listen, port) = if over.listen.is_some() || over.port.is_some() {
+            (over.listen, over.port)
+        } else {
+            (self.listen, self.port)
+        };
+        Settings {
+            username: over.username.or(self.username),
+            key_file: over.key_file.or(self.key_file),
+            listen,
+            port,
+            bootstrap: over.bootstrap.or(self.bootstrap),
+            bootstrap_interval_secs: over.bootstrap_interval_secs.or(self.bootstrap_interval_secs),
+            discovery_interval_secs: over.discovery_interval_secs.or(self.discovery_interval_secs),
+            target_peers: over.target_peers.or(self.target_peers),
+            rooms: over.rooms.or(self.rooms),
+            history_file: over.history_file.or(self.history_file),
+            outbox_file: over.outbox_file.or(self.outbox_file),
+            peers_file: over.peers_file.or(self.peers_file),
+            contacts_file: over.contacts_file.or(self.contacts_file),
+            dht_records_file: over.dht_records_file.or(self.dht_records_file),
+            transport: TransportSettings {
+                wire_format: over.transport.wire_format.or(self.transport.wire_format),
+                tcp_nodelay: over.transport.tcp_nodelay.or(self.transport.tcp_nodelay),
+                idle_connection_timeout_secs: over
+                    .transport
+                    .idle_connection_timeout_secs
+                    .or(self.transport.idle_connection_timeout_secs),
+            },
+            limits: LimitSettings {
+                max_message_size: over.limits.max_message_size.or(self.limits.max_message_size),
+                compression_threshold: over.limits.compression_threshold.or(self.limits.compression_threshold),
+                event_buffer: over.limits.event_buffer.or(self.limits.event_buffer),
+                command_buffer: over.limits.command_buffer.or(self.limits.command_buffer),
+                sync_max_backfill_secs: over.limits.sync_max_backfill_secs.or(self.limits.sync_max_backfill_secs),
+                max_dht_records: over.limits.max_dht_records.or(self.limits.max_dht_records),
+            },
+        }
+    }
+
+    /// Build the network configuration, checking addresses and room names
+    pub fn network_config(&self) -> Result<NetworkConfig> {
+        let mut config = NetworkConfig::default();
+        if let Some(key_file) = &self.key_file {
+            config.key_file = key_file.clone();
+        }
+        if let Some(listen) = &self.listen {
+            config.listen_addresses = parse_addresses(listen, "listen")?;
+        }
+        if let Some(port) = self.port {
+            config.listen_port = port;
+        }
+        if let Some(bootstrap) = &self.bootstrap {
+            config.dht_config.bootstrap_nodes = parse_addresses(bootstrap, "bootstrap")?;
+        }
+        if let Some(secs) = self.bootstrap_interval_secs {
+            // Zero would re-bootstrap in a busy loop
+            if secs == 0 {
+                bail!("bootstrap_interval_secs must be at least 1");
+            }
+            config.dht_config.bootstrap_interval = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.discovery_interval_secs {
+            if secs == 0 {
+                bail!("discovery_interval_secs must be at least 1");
+            }
+            config.dht_config.discovery_interval = Duration::from_secs(secs);
+        }
+        if let Some(peers) = self.target_peers {
+            config.dht_config.target_peers = peers;
+        }
+        if let Some(rooms) = &self.rooms {
+            for room in rooms {
+                room::validate_room_name(room)?;
+            }
+            config.rooms = rooms.clone();
+        }
+        if let Some(file) = &self.history_file {
+            config.history_file = (!file.is_empty()).then(|| file.clone());
+        }
+        if let Some(file) = &self.outbox_file {
+            config.outbox_file = (!file.is_empty()).then(|| file.clone());
+        }
+        if let Some(file) = &self.peers_file {
+            config.peers_file = (!file.is_empty()).then(|| file.clone());
+        }
+        if let Some(file) = &self.contacts_file {
+            config.contacts_file = (!file.is_empty()).then(|| file.clone());
+        }
+        if let Some(file) = &self.dht_records_file {
+            config.dht_records_file = (!file.is_empty()).then(|| file.clone());
+        }
+
+        if let Some(format) = self.transport.wire_format {
+            config.wire_format = format;
+        }
+        config.tcp_nodelay = self.transport.tcp_nodelay;
+        if let Some(secs) = self.transport.idle_connection_timeout_secs {
+            config.idle_connection_timeout = Duration::from_secs(secs);
+        }
+
+        let limits = &self.limits;
+        if let Some(size) = limits.max_message_size {
+            config.max_message_size = size;
+        }
+        if let Some(threshold) = limits.compression_threshold {
+            config.compression_threshold = (threshold > 0).then_some(threshold);
+        }
+        if let Some(buffer) = limits.event_buffer {
+            config.event_buffer = buffer;
+        }
+        if let Some(buffer) = limits.command_buffer {
+            config.command_buffer = buffer;
+        }
+        if let Some(secs) = limits.sync_max_backfill_secs {
+            config.sync_max_backfill = Duration::from_secs(secs);
+        }
+        if let Some(max) = limits.max_dht_records {
+            config.dht_config.max_records = max;
+        }
+        Ok(config)
+    }
+}
+
+fn parse_addresses(addresses: &[String], what: &str) -> Result<Vec<Multiaddr>> {
+    addresses
+        .iter()
+        .map(|addr| addr.parse().with_context(|| format!("Invalid {what} address '{addr}'")))
+        .collect()
+}
+
+/// Load settings from `path`, or from the default location if it exists,
+/// applying the named profile.
+///
+/// A profile is a separate identity: unless it names its own key file it
+/// uses `<profile>_key.dat`, never the key of the base settings, and its
+/// state files are likewise `<profile>_history.jsonl` and so on.
+pub fn load(path: Option<&Path>, profile: Option<&str>) -> Result<Settings> {
+    let path = match path {
+        Some(path) => Some(path.to_path_buf()),
+        None => default_config_path().filter(|path| path.exists()),
+    };
+    let text = match &path {
+        Some(path) => fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?,
+        None => String::new(),
+    };
+    match path {
+        Some(path) => {
+            let settings = parse(&text, profile).with_context(|| format!("Invalid config file {}", path.display()))?;
+            tracing::info!("Loaded settings from {}", path.display());
+            Ok(settings)
+        }
+        None => parse(&text, profile),
+    }
+}
+
+fn parse(text: &str, profile: Option<&str>) -> Result<Settings> {
+    let mut table: toml::Table = text.parse()?;
+    let profiles = table.remove("profiles");
+    let base: Settings = table.try_into()?;
+    let Some(name) = profile else {
+        return Ok(base);
+    };
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("Invalid profile name '{name}'");
+    }
+
+    let profile = match profiles.as_ref().and_then(|profiles| profiles.get(name)) {
+        Some(profile) => profile.clone().try_into::<Settings>()?,
+        // Without a config file a profile still gets its own identity
+        None if text.is_empty() => Settings::default(),
+        None => bail!("No profile named '{name}'"),
+    };
+    // State files are per identity too, so two profiles running side by
+    // side never write to the same file
+    let defaults = NetworkConfig::default();
+    let own_file = |file: &Option<String>, default: Option<String>| {
+        file.clone().or_else(|| default.map(|default| format!("{name}_{default}")))
+    };
+    let files = Settings {
+        key_file: Some(profile.key_file.clone().unwrap_or_else(|| format!("{name}_key.dat"))),
+        history_file: own_file(&profile.history_file, defaults.history_file),
+        outbox_file: own_file(&profile.outbox_file, defaults.outbox_file),
+        peers_file: own_file(&profile.peers_file, defaults.peers_file),
+        contacts_file: own_file(&profile.contacts_file, defaults.contacts_file),
+        // Hosted DHT records are only kept on disk when the profile asks
+        dht_records_file: Some(profile.dht_records_file.clone().unwrap_or_default()),
+        ..Settings::default()
+    };
+    Ok(base.merge(profile).merge(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_layers_over_base() {
+        let text = r#"
+            username = "alice"
+            port = 4001
+            bootstrap = ["/ip4/127.0.0.1/tcp/4799"]
+
+            [limits]
+            compression_threshold = 0
+
+            [profiles.work]
+            username = "alice-work"
+            listen = ["/ip4/127.0.0.1/tcp/4002"]
+        "#;
+        let base = parse(text, None).unwrap();
+        assert_eq!(base.username.as_deref(), Some("alice"));
+        assert!(base.key_file.is_none());
+
+        let work = parse(text, Some("work")).unwrap();
+        assert_eq!(work.username.as_deref(), Some("alice-work"));
+        assert_eq!(work.key_file.as_deref(), Some("work_key.dat"));
+        assert_eq!(work.history_file.as_deref(), Some("work_history.jsonl"));
+        let config = work.network_config().unwrap();
+        assert_eq!(config.listen_addresses.len(), 1);
+        assert_eq!(config.dht_config.bootstrap_nodes.len(), 1);
+        assert_eq!(config.compression_threshold, None);
+
+        // Flags override the file
+        let flags = Settings { port: Some(5000), ..Settings::default() };
+        let config = work.merge(flags).network_config().unwrap();
+        assert!(config.listen_addresses.is_empty());
+        assert_eq!(config.listen_port, 5000);
+
+        // Unless a profile names its files, it never shares them with the base
+        let text = "history_file = \"shared.jsonl\"\n[profiles.home]\ncontacts_file = \"\"";
+        let config = parse(text, Some("home")).unwrap().network_config().unwrap();
+        assert_eq!(config.history_file.as_deref(), Some("home_history.jsonl"));
+        assert_eq!(config.outbox_file.as_deref(), Some("home_outbox.json"));
+        assert_eq!(config.peers_file.as_deref(), Some("home_peers.json"));
+        assert_eq!(config.contacts_file, None);
+        assert_eq!(config.dht_records_file, None);
+
+        assert!(parse(text, Some("work")).is_err());
+        assert!(parse("usernme = \"typo\"", None).is_err());
+        assert!(parse("bootstrap_interval_secs = 0", None).unwrap().network_config().is_err());
+        assert!(parse("discovery_interval_secs = 0", None).unwrap().network_config().is_err());
+    }
+}
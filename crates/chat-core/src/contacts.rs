@@ -0,0 +1,258 @@
+//! Contacts pinned on first use
+//!
+//! Usernames are self-chosen, so anyone can claim to be `alice`. The first
+//! key seen signing messages as a name is pinned to it, and messages under
+//! that name from any other key are flagged. Comparing safety numbers
+//! out of band upgrades a pinned contact to a verified one.
+
+use anyhow::{anyhow, Context, Result};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::signing;
+
+/// Names pinned to one key at most. Further names the key signs under are
+/// not pinned, so one peer cycling through names can't claim them all;
+/// evicting old pins instead would let their names be claimed again.
+pub const MAX_NAMES_PER_PEER: usize = 4;
+
+/// Names pinned in total. Many keys could otherwise grow the file without
+/// bound; names seen once the book is full are simply not pinned.
+pub const MAX_CONTACTS: usize = 10_000;
+
+/// Digits in a safety number, shown in groups of five
+const SAFETY_NUMBER_GROUPS: usize = 12;
+
+/// A username pinned to the key that first used it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Contact {
+    pub username: String,
+    pub peer_id: String,
+    /// Unix time the name was pinned
+    pub pinned_at: u64,
+    /// Whether the safety number was compared and confirmed
+    #[serde(default)]
+    pub verified: bool,
+}
+
+/// What a signed message tells us about its author's contact entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContactCheck {
+    /// The name was new and is now pinned to the author's key
+    Pinned,
+    /// The name is pinned to the author's key, or can't be pinned
+    Known,
+    /// The name is pinned to a different key
+    KeyChanged(Contact),
+}
+
+/// Pinned contacts, optionally persisted to a file
+#[derive(Debug, Default)]
+pub struct ContactBook {
+    path: Option<PathBuf>,
+    contacts: BTreeMap<String, Contact>,
+}
+
+impl ContactBook {
+    /// Open the contacts stored at `path`, or an in-memory list if `None`
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path.map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+
+        // Unlike the caches, a damaged file is an error: starting over would
+        // silently pin whichever keys show up next
+        let contacts: Vec<Contact> = if path.exists() {
+            serde_json::from_slice(&fs::read(&path)?)
+                .with_context(|| format!("Unreadable contacts file {}", path.display()))?
+        } else {
+            Vec::new()
+        };
+        if !contacts.is_empty() {
+            info!("Loaded {} contacts", contacts.len());
+        }
+        Ok(Self {
+            path: Some(path),
+            contacts: contacts.into_iter().map(|contact| (contact.username.clone(), contact)).collect(),
+        })
+    }
+
+    /// Check the name a verified message was signed under, pinning it to
+    /// the signer's key if nobody holds it yet
+    pub fn observe(&mut self, username: &str, peer_id: &str) -> ContactCheck {
+        if let Some(contact) = self.contacts.get(username) {
+            return if contact.peer_id == peer_id {
+                ContactCheck::Known
+            } else {
+                ContactCheck::KeyChanged(contact.clone())
+            };
+        }
+        if self.contacts.len() >= MAX_CONTACTS
+            || self.contacts.values().filter(|contact| contact.peer_id == peer_id).count() >= MAX_NAMES_PER_PEER
+        {
+            return ContactCheck::Known;
+        }
+        let contact = Contact {
+            username: username.to_string(),
+            peer_id: peer_id.to_string(),
+            pinned_at: now(),
+            verified: false,
+        };
+        self.contacts.insert(username.to_string(), contact);
+        // A pin lost in a crash would let another key take the name
+        self.save();
+        ContactCheck::Pinned
+    }
+
+    /// Mark a contact's key as confirmed out of band
+    pub fn verify(&mut self, username: &str) -> Option<&Contact> {
+        let contact = self.contacts.get_mut(username)?;
+        if !contact.verified {
+            contact.verified = true;
+            self.save();
+        }
+        self.contacts.get(username)
+    }
+
+    /// Unpin a contact, so the next key to use the name is pinned instead
+    pub fn forget(&mut self, username: &str) -> Option<Contact> {
+        let contact = self.contacts.remove(username)?;
+        self.save();
+        Some(contact)
+    }
+
+    pub fn get(&self, username: &str) -> Option<&Contact> {
+        self.contacts.get(username)
+    }
+
+    /// All contacts, ordered by username
+    pub fn list(&self) -> Vec<Contact> {
+        self.contacts.values().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.contacts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.contacts.is_empty()
+    }
+
+    /// Write the contacts to disk, reporting failures to the caller
+    pub fn flush(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_contacts(path, &self.contacts),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the contacts to disk, logging failures so that chatting
+    /// carries on; the change is written again with the next one
+    fn save(&self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to save contacts: {}", e);
+        }
+    }
+}
+
+/// Number two peers compare out of band to check they see each other's
+/// real keys. Both sides compute the same digits.
+pub fn safety_number(ours: &PeerId, theirs: &PeerId) -> Result<String> {
+    let key_of = |peer_id: &PeerId| {
+        signing::public_key_of(peer_id)
+            .map(|key| key.encode_protobuf())
+            .ok_or_else(|| anyhow!("Peer id {peer_id} does not embed its public key"))
+    };
+    let mut keys = [key_of(ours)?, key_of(theirs)?];
+    keys.sort();
+
+    let mut hasher = Sha512::new();
+    hasher.update(b"p2p-chat safety number");
+    for key in &keys {
+        hasher.update((key.len() as u32).to_be_bytes());
+        hasher.update(key);
+    }
+    let digest = hasher.finalize();
+    let groups: Vec<String> = digest
+        .chunks(5)
+        .take(SAFETY_NUMBER_GROUPS)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |value, byte| (value << 8) | u64::from(*byte));
+            format!("{:05}", value % 100_000)
+        })
+        .collect();
+    Ok(groups.join(" "))
+}
+
+fn write_contacts(path: &Path, contacts: &BTreeMap<String, Contact>) -> Result<()> {
+    let contacts: Vec<&Contact> = contacts.values().collect();
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp, serde_json::to_vec_pretty(&contacts)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::identity::Keypair;
+
+    #[test]
+    fn test_pinning_and_safety_numbers() {
+        let path = std::env::temp_dir().join(format!("contacts-{}.json", uuid::Uuid::new_v4()));
+        let path_str = path.to_str().unwrap();
+        let alice = Keypair::generate_ed25519().public().to_peer_id();
+        let mallory = Keypair::generate_ed25519().public().to_peer_id();
+
+        let mut book = ContactBook::load(Some(path_str)).unwrap();
+        assert_eq!(book.observe("alice", &alice.to_string()), ContactCheck::Pinned);
+        assert_eq!(book.observe("alice", &alice.to_string()), ContactCheck::Known);
+        // New pins are on disk right away
+        assert_eq!(ContactBook::load(Some(path_str)).unwrap().len(), 1);
+        assert!(book.verify("alice").unwrap().verified);
+        match book.observe("alice", &mallory.to_string()) {
+            ContactCheck::KeyChanged(pinned) => assert_eq!(pinned.peer_id, alice.to_string()),
+            other => panic!("expected a key change, got {other:?}"),
+        }
+
+        // Pins survive a restart, and forgetting frees the name
+        let mut book = ContactBook::load(Some(path_str)).unwrap();
+        assert!(book.get("alice").unwrap().verified);
+        book.forget("alice");
+        assert_eq!(book.observe("alice", &mallory.to_string()), ContactCheck::Pinned);
+        fs::remove_file(&path).unwrap();
+
+        // One key can't hoard names, and doesn't stop others pinning theirs
+        for i in 1..MAX_NAMES_PER_PEER {
+            assert_eq!(book.observe(&format!("mallory{i}"), &mallory.to_string()), ContactCheck::Pinned);
+        }
+        assert_eq!(book.observe("bob", &mallory.to_string()), ContactCheck::Known);
+        assert!(book.get("bob").is_none());
+        assert_eq!(book.observe("bob", &alice.to_string()), ContactCheck::Pinned);
+
+        // Nor can many keys fill the book without bound
+        let mut book = ContactBook::default();
+        for i in 0..MAX_CONTACTS {
+            book.observe(&format!("user{i}"), &format!("peer{i}"));
+        }
+        assert_eq!(book.observe("carol", "peer-carol"), ContactCheck::Known);
+        assert_eq!(book.len(), MAX_CONTACTS);
+
+        let number = safety_number(&alice, &mallory).unwrap();
+        assert_eq!(number, safety_number(&mallory, &alice).unwrap());
+        assert_eq!(number.len(), SAFETY_NUMBER_GROUPS * 6 - 1);
+        assert_ne!(number, safety_number(&alice, &PeerId::random()).unwrap_or_default());
+    }
+}
@@ -0,0 +1,84 @@
+//! Random-walk peer discovery
+//!
+//! Every [`DhtConfig::discovery_interval`] the network looks up the peers
+//! closest to a random ID and dials the ones it isn't connected to, until
+//! [`DhtConfig::target_peers`] connections are reached.
+//!
+//! [`DhtConfig::discovery_interval`]: crate::DhtConfig::discovery_interval
+//! [`DhtConfig::target_peers`]: crate::DhtConfig::target_peers
+
+use libp2p::kad::QueryId;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Default time between random walks
+pub const DEFAULT_DISCOVERY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default number of connected peers after which discovery pauses
+pub const DEFAULT_TARGET_PEERS: usize = 8;
+
+/// State of the random walk
+#[derive(Debug)]
+pub struct RandomWalk {
+    interval: Duration,
+    target_peers: usize,
+    next: Instant,
+    /// The lookup in flight, if any
+    query: Option<QueryId>,
+}
+
+impl RandomWalk {
+    /// A walk whose first step is due immediately
+    pub fn new(interval: Duration, target_peers: usize) -> Self {
+        Self { interval, target_peers, next: Instant::now(), query: None }
+    }
+
+    /// When to take the next step
+    pub fn next_step(&self) -> Instant {
+        self.next
+    }
+
+    /// How many more peers to dial with `connected` peers already connected
+    pub fn wanted(&self, connected: usize) -> usize {
+        self.target_peers.saturating_sub(connected)
+    }
+
+    /// Record a step, started as `query` if more peers were wanted, and
+    /// schedule the next one
+    pub fn stepped(&mut self, query: Option<QueryId>) {
+        self.query = query;
+        self.next = Instant::now() + self.interval;
+    }
+
+    /// Whether the step is still waiting for `query`, which ends it
+    pub fn finish(&mut self, query: QueryId) -> bool {
+        if self.query == Some(query) {
+            self.query = None;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether a lookup is in flight
+    pub fn is_walking(&self) -> bool {
+        self.query.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_pauses_at_target() {
+        let mut walk = RandomWalk::new(Duration::from_secs(30), 3);
+        assert!(walk.next_step() <= Instant::now());
+        assert_eq!(walk.wanted(1), 2);
+        assert_eq!(walk.wanted(5), 0);
+
+        walk.stepped(None);
+        assert!(!walk.is_walking());
+        assert!(walk.next_step() > Instant::now() + Duration::from_secs(29));
+    }
+}
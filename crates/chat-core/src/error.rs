@@ -0,0 +1,127 @@
+//! Errors returned by the chat network and client
+
+use libp2p::{gossipsub, kad, swarm::DialError};
+use thiserror::Error;
+
+/// Result type used throughout the network and client APIs
+pub type Result<T, E = ChatError> = std::result::Result<T, E>;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Everything that can go wrong talking to the chat network
+#[derive(Debug, Error)]
+pub enum ChatError {
+    /// The keypair file could not be read, decoded or written
+    #[error("Failed to load keypair from {path}: {source}")]
+    Key { path: String, source: BoxError },
+
+    /// The transport could not be built or could not listen
+    #[error("Failed to set up transport: {0}")]
+    Transport(BoxError),
+
+    /// The outbox or message history could not be opened or saved
+    #[error("Local storage error: {0:#}")]
+    Storage(anyhow::Error),
+
+    /// The message could not be encoded or signed
+    #[error("Failed to encode message: {0:#}")]
+    Encode(anyhow::Error),
+
+    /// Nobody is subscribed to the topic the message was published on
+    #[error("No peers subscribed to {topic}")]
+    NoPeers { topic: String },
+
+    /// gossipsub refused to publish the message
+    #[error("Failed to publish message: {0}")]
+    Publish(gossipsub::PublishError),
+
+    #[error("Failed to change topic subscription: {0}")]
+    Subscription(#[from] gossipsub::SubscriptionError),
+
+    #[error("Failed to dial peer: {0}")]
+    Dial(#[from] DialError),
+
+    /// The DHT has no peers to bootstrap from
+    #[error("DHT bootstrap failed: {0}")]
+    Bootstrap(#[from] kad::NoKnownPeers),
+
+    #[error("Invalid peer id '{0}'")]
+    InvalidPeerId(String),
+
+    /// No known peer has this username or peer id prefix
+    #[error("No known peer matches '{0}'")]
+    UnknownPeer(String),
+
+    /// Several known peers match a username or peer id prefix
+    #[error("'{query}' matches several peers: {}", candidates.join(", "))]
+    AmbiguousPeer { query: String, candidates: Vec<String> },
+
+    #[error("No contact named '{0}'")]
+    UnknownContact(String),
+
+    #[error("Invalid room name '{0}': use up to {max} letters, digits, '-' or '_'", max = crate::room::MAX_ROOM_NAME_LEN)]
+    InvalidRoom(String),
+
+    /// The network has stopped, so the client can no longer be used
+    #[error("Chat client is closed")]
+    ClientClosed,
+
+    /// The network task panicked or was cancelled
+    #[error("Network task failed: {0}")]
+    Task(#[from] tokio::task::JoinError),
+}
+
+impl ChatError {
+    /// Error for a message gossipsub refused to publish on `topic`
+    pub(crate) fn from_publish(error: gossipsub::PublishError, topic: &str) -> Self {
+        match error {
+            gossipsub::PublishError::InsufficientPeers => ChatError::NoPeers { topic: topic.to_string() },
+            error => ChatError::Publish(error),
+        }
+    }
+
+    /// Whether the caller passed something invalid, as opposed to the
+    /// network failing
+    pub fn is_invalid_input(&self) -> bool {
+        matches!(
+            self,
+            ChatError::InvalidPeerId(_)
+                | ChatError::UnknownPeer(_)
+                | ChatError::AmbiguousPeer { .. }
+                | ChatError::UnknownContact(_)
+                | ChatError::InvalidRoom(_)
+        )
+    }
+}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for ChatError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        ChatError::ClientClosed
+    }
+}
+
+impl From<tokio::sync::oneshot::error::RecvError> for ChatError {
+    fn from(_: tokio::sync::oneshot::error::RecvError) -> Self {
+        ChatError::ClientClosed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_errors_and_invalid_input() {
+        let no_peers = ChatError::from_publish(gossipsub::PublishError::InsufficientPeers, "chat");
+        assert!(matches!(&no_peers, ChatError::NoPeers { topic } if topic == "chat"));
+        let duplicate = ChatError::from_publish(gossipsub::PublishError::Duplicate, "chat");
+        assert!(matches!(duplicate, ChatError::Publish(gossipsub::PublishError::Duplicate)));
+
+        assert!(ChatError::InvalidPeerId("x".into()).is_invalid_input());
+        assert!(ChatError::UnknownPeer("carol".into()).is_invalid_input());
+        assert!(ChatError::AmbiguousPeer { query: "a".into(), candidates: Vec::new() }.is_invalid_input());
+        assert!(ChatError::InvalidRoom("bad room".into()).is_invalid_input());
+        assert!(!no_peers.is_invalid_input());
+        assert!(!ChatError::ClientClosed.is_invalid_input());
+    }
+}
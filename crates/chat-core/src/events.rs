@@ -0,0 +1,391 @@
+//! Event streams for observers of the chat network
+//!
+//! The network publishes every [`NetworkEvent`] on an [`EventBus`], so any
+//! number of consumers (the UI, a logger, bots) can each hold their own
+//! [`EventStream`]. Every stream has a bounded queue, and what happens when
+//! it fills up depends on the event:
+//!
+//! - presence updates (peers, rooms, DHT status) are dropped oldest first,
+//!   and the stream reports how many it lost with [`NetworkEvent::EventsDropped`];
+//! - chat message events are never dropped, and neither is the final
+//!   [`NetworkEvent::Stopped`]; instead the network stops taking in new
+//!   traffic until every stream has room again.
+//!
+//! A stream that is kept but never read therefore stalls the network; drop
+//! streams you no longer need. Streams serving remote clients, which may
+//! read as slowly as they like, should use
+//! [`EventStream::disconnect_when_full`] so they end instead.
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::Notify;
+use tracing::warn;
+
+use crate::types::{MessageType, NetworkEvent};
+
+/// Events queued per subscriber before backpressure kicks in
+pub const DEFAULT_EVENT_BUFFER: usize = 1024;
+
+/// Broad categories of [`NetworkEvent`] to filter on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    /// Peers being discovered, connecting or disconnecting, and peer lists
+    Peer,
+    /// Messages received, sent, queued, failed or synced
+    Message,
+    /// Peers joining or leaving rooms
+    Room,
+    /// DHT status
+    Dht,
+    /// Contacts being pinned or signing with unexpected keys
+    Contact,
+    /// The network stopping
+    Lifecycle,
+}
+
+impl NetworkEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            NetworkEvent::PeerDiscovered(_)
+            | NetworkEvent::PeerConnected(_)
+            | NetworkEvent::PeerDisconnected(_)
+            | NetworkEvent::PeerListUpdated(_)
+            // Only presence updates are ever dropped
+            | NetworkEvent::EventsDropped { .. } => EventKind::Peer,
+            NetworkEvent::MessageReceived { .. }
+            | NetworkEvent::MessageSent(_)
+            | NetworkEvent::MessageQueued(_)
+            | NetworkEvent::MessageFailed { .. }
+            | NetworkEvent::HistorySynced { .. } => EventKind::Message,
+            NetworkEvent::RoomPeerJoined { .. } | NetworkEvent::RoomPeerLeft { .. } => EventKind::Room,
+            NetworkEvent::DhtBootstrapped
+            | NetworkEvent::DhtBootstrapFailed { .. }
+            | NetworkEvent::DhtBootstrapRecovered { .. } => EventKind::Dht,
+            NetworkEvent::ContactPinned { .. } | NetworkEvent::ContactKeyChanged { .. } => EventKind::Contact,
+            NetworkEvent::Stopped { .. } => EventKind::Lifecycle,
+        }
+    }
+
+    /// Whether the event may be dropped for a subscriber that falls behind
+    pub fn is_droppable(&self) -> bool {
+        matches!(self.kind(), EventKind::Peer | EventKind::Room | EventKind::Dht)
+    }
+
+    /// Room the event happened in; `None` for events outside any room,
+    /// including direct messages
+    pub fn room(&self) -> Option<&str> {
+        let message = match self {
+            NetworkEvent::RoomPeerJoined { room, .. } | NetworkEvent::RoomPeerLeft { room, .. } => {
+                return Some(room);
+            }
+            NetworkEvent::MessageReceived { message, .. }
+            | NetworkEvent::MessageSent(message)
+            | NetworkEvent::MessageQueued(message)
+            | NetworkEvent::MessageFailed { message, .. } => message,
+            // A sync batch always comes from a single room
+            NetworkEvent::HistorySynced { messages, .. } => messages.first()?,
+            _ => return None,
+        };
+        match message.message_type {
+            MessageType::Direct { .. } => None,
+            _ => Some(message.room_name()),
+        }
+    }
+}
+
+/// Which events a stream delivers; the default delivers everything
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    kinds: Option<HashSet<EventKind>>,
+    room: Option<String>,
+}
+
+impl EventFilter {
+    /// Only deliver events of these kinds
+    pub fn kinds(mut self, kinds: impl IntoIterator<Item = EventKind>) -> Self {
+        self.kinds = Some(kinds.into_iter().collect());
+        self
+    }
+
+    /// Drop events from rooms other than `room`; events outside any room still pass
+    pub fn room(mut self, room: impl Into<String>) -> Self {
+        self.room = Some(room.into());
+        self
+    }
+
+    pub fn matches(&self, event: &NetworkEvent) -> bool {
+        let kind_matches = self.kinds.as_ref().is_none_or(|kinds| kinds.contains(&event.kind()));
+        let room_matches = match (&self.room, event.room()) {
+            (Some(wanted), Some(room)) => wanted == room,
+            _ => true,
+        };
+        kind_matches && room_matches
+    }
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    events: VecDeque<NetworkEvent>,
+    filter: EventFilter,
+    /// Dropped events not yet reported to the subscriber
+    unreported_drops: u64,
+    /// All events this subscriber has lost
+    dropped: u64,
+    /// End the stream rather than hold up the network when it fills up
+    disconnect_when_full: bool,
+    /// Whether the stream was ended for falling behind
+    lagged: bool,
+    closed: bool,
+}
+
+#[derive(Debug, Default)]
+struct Subscriber {
+    queue: Mutex<Queue>,
+    ready: Notify,
+}
+
+#[derive(Debug)]
+struct Shared {
+    subscribers: Mutex<Vec<Arc<Subscriber>>>,
+    capacity: usize,
+    dropped: AtomicU64,
+    /// Signalled whenever a subscriber takes an event off its queue
+    space: Notify,
+    closed: AtomicBool,
+}
+
+/// Fans network events out to every [`EventStream`]
+#[derive(Debug, Clone)]
+pub struct EventBus {
+    shared: Arc<Shared>,
+}
+
+impl EventBus {
+    /// A bus queueing up to `capacity` events per subscriber
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                subscribers: Mutex::new(Vec::new()),
+                capacity: capacity.max(1),
+                dropped: AtomicU64::new(0),
+                space: Notify::new(),
+                closed: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Start a new stream of events, beginning with the next one emitted
+    pub fn subscribe(&self) -> EventStream {
+        let subscriber = Arc::new(Subscriber::default());
+        subscriber.queue.lock().unwrap().closed = self.shared.closed.load(Ordering::Acquire);
+        self.shared.subscribers.lock().unwrap().push(subscriber.clone());
+        EventStream { subscriber, shared: self.shared.clone() }
+    }
+
+    /// Queue an event for every subscriber whose filter accepts it
+    pub fn emit(&self, event: NetworkEvent) {
+        let mut subscribers = self.shared.subscribers.lock().unwrap();
+        subscribers.retain(|subscriber| !subscriber.queue.lock().unwrap().closed);
+
+        for subscriber in subscribers.iter() {
+            let mut queue = subscriber.queue.lock().unwrap();
+            if !queue.filter.matches(&event) {
+                continue;
+            }
+            if queue.events.len() >= self.shared.capacity && !event.is_droppable() && queue.disconnect_when_full {
+                warn!("Event subscriber fell too far behind, ending its stream");
+                queue.events.clear();
+                queue.lagged = true;
+                queue.closed = true;
+                drop(queue);
+                subscriber.ready.notify_one();
+                continue;
+            }
+            if queue.events.len() >= self.shared.capacity && event.is_droppable() {
+                // Make room by dropping the oldest presence update, or this one if there is none
+                match queue.events.iter().position(NetworkEvent::is_droppable) {
+                    Some(oldest) => {
+                        queue.events.remove(oldest);
+                    }
+                    None => {
+                        queue.unreported_drops += 1;
+                        queue.dropped += 1;
+                        self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    }
+                }
+                queue.unreported_drops += 1;
+                queue.dropped += 1;
+                self.shared.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            // Chat events go in even past capacity; the network holds off
+            // new traffic until the subscriber catches up
+            queue.events.push_back(event.clone());
+            drop(queue);
+            subscriber.ready.notify_one();
+        }
+    }
+
+    /// Whether some subscriber's queue is full, so no new traffic should be taken in
+    pub fn is_saturated(&self) -> bool {
+        self.shared
+            .subscribers
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|subscriber| {
+                let queue = subscriber.queue.lock().unwrap();
+                !queue.closed && !queue.disconnect_when_full && queue.events.len() >= self.shared.capacity
+            })
+    }
+
+    /// Wait until some subscriber takes an event off its queue
+    pub async fn space_freed(&self) {
+        self.shared.space.notified().await;
+    }
+
+    /// Total events dropped across all subscribers
+    pub fn dropped(&self) -> u64 {
+        self.shared.dropped.load(Ordering::Relaxed)
+    }
+
+    /// End every stream once it has delivered what is already queued
+    pub fn close(&self) {
+        self.shared.closed.store(true, Ordering::Release);
+        for subscriber in self.shared.subscribers.lock().unwrap().iter() {
+            subscriber.queue.lock().unwrap().closed = true;
+            subscriber.ready.notify_one();
+        }
+    }
+}
+
+/// One subscriber's view of the network events
+#[derive(Debug)]
+pub struct EventStream {
+    subscriber: Arc<Subscriber>,
+    shared: Arc<Shared>,
+}
+
+impl EventStream {
+    /// Only deliver events matching `filter` from now on
+    pub fn with_filter(self, filter: EventFilter) -> Self {
+        let mut queue = self.subscriber.queue.lock().unwrap();
+        queue.events.retain(|event| filter.matches(event));
+        queue.filter = filter;
+        drop(queue);
+        self
+    }
+
+    /// End this stream instead of holding up the network when it is full
+    /// and a chat event arrives; see [`lagged`](Self::lagged)
+    pub fn disconnect_when_full(self) -> Self {
+        self.subscriber.queue.lock().unwrap().disconnect_when_full = true;
+        self
+    }
+
+    /// Whether the stream ended because it fell behind
+    pub fn lagged(&self) -> bool {
+        self.subscriber.queue.lock().unwrap().lagged
+    }
+
+    /// Next matching event, or `None` once the network has stopped or the
+    /// stream was ended for falling behind.
+    ///
+    /// After this stream lost presence updates, the next call returns
+    /// [`NetworkEvent::EventsDropped`] with how many were lost.
+    pub async fn recv(&mut self) -> Option<NetworkEvent> {
+        loop {
+            {
+                let mut queue = self.subscriber.queue.lock().unwrap();
+                if queue.unreported_drops > 0 {
+                    let count = std::mem::take(&mut queue.unreported_drops);
+                    warn!("Event subscriber fell behind, dropped {count} presence updates");
+                    return Some(NetworkEvent::EventsDropped { count });
+                }
+                if let Some(event) = queue.events.pop_front() {
+                    drop(queue);
+                    self.shared.space.notify_one();
+                    return Some(event);
+                }
+                if queue.closed {
+                    return None;
+                }
+            }
+            self.subscriber.ready.notified().await;
+        }
+    }
+
+    /// How many events this stream has lost because it fell behind
+    pub fn missed(&self) -> u64 {
+        self.subscriber.queue.lock().unwrap().dropped
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        // The bus forgets closed subscribers, and must not wait on this one
+        self.subscriber.queue.lock().unwrap().closed = true;
+        self.shared.space.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ChatMessage;
+
+    #[tokio::test]
+    async fn test_full_stream_drops_presence_but_keeps_messages() {
+        let bus = EventBus::new(2);
+        let mut stream = bus.subscribe().with_filter(EventFilter::default().room("ops"));
+
+        let mut in_ops = ChatMessage::new("alice".into(), "deploying".into(), MessageType::Broadcast);
+        in_ops.room = Some("ops".into());
+        let in_chat = ChatMessage::new("bob".into(), "lunch?".into(), MessageType::Broadcast);
+
+        bus.emit(NetworkEvent::PeerConnected("a".into()));
+        bus.emit(NetworkEvent::MessageSent(in_ops.clone()));
+        bus.emit(NetworkEvent::MessageSent(in_chat));
+        // Full: the oldest presence update makes way for the newer one
+        bus.emit(NetworkEvent::PeerConnected("b".into()));
+        // Messages are never dropped, even past capacity
+        bus.emit(NetworkEvent::MessageSent(in_ops.clone()));
+        assert!(bus.is_saturated());
+        bus.close();
+
+        assert!(matches!(stream.recv().await, Some(NetworkEvent::EventsDropped { count: 1 })));
+        assert!(matches!(stream.recv().await, Some(NetworkEvent::MessageSent(m)) if m.id == in_ops.id));
+        assert!(matches!(stream.recv().await, Some(NetworkEvent::PeerConnected(p)) if p == "b"));
+        assert!(matches!(stream.recv().await, Some(NetworkEvent::MessageSent(m)) if m.id == in_ops.id));
+        assert!(stream.recv().await.is_none());
+        assert_eq!((stream.missed(), bus.dropped()), (1, 1));
+
+        let filter = EventFilter::default().kinds([EventKind::Peer]);
+        assert!(!filter.matches(&NetworkEvent::DhtBootstrapped));
+        assert!(filter.matches(&NetworkEvent::PeerConnected("peer".into())));
+    }
+
+    #[tokio::test]
+    async fn test_slow_gateway_stream_is_disconnected() {
+        let bus = EventBus::new(2);
+        let mut slow = bus.subscribe().disconnect_when_full();
+        let mut fast = bus.subscribe();
+        let message = || NetworkEvent::MessageSent(ChatMessage::new("alice".into(), "hi".into(), MessageType::Broadcast));
+
+        for _ in 0..3 {
+            bus.emit(message());
+            assert!(fast.recv().await.is_some());
+            // The slow stream never holds up the network
+            assert!(!bus.is_saturated());
+        }
+        assert!(slow.lagged());
+        assert!(slow.recv().await.is_none());
+
+        // The fast stream is unaffected
+        bus.emit(message());
+        assert!(matches!(fast.recv().await, Some(NetworkEvent::MessageSent(_))));
+        assert!(!fast.lagged());
+    }
+}
@@ -0,0 +1,215 @@
+//! In-memory message history with reply threads and reactions
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use crate::clock::causal_order;
+use crate::types::{ChatMessage, MessageType};
+
+/// Messages seen during this session, numbered in arrival order
+#[derive(Debug, Default)]
+pub struct MessageHistory {
+    messages: Vec<ChatMessage>,
+    positions: HashMap<String, usize>,
+    /// Target message id -> emoji -> senders who reacted with it
+    reactions: HashMap<String, BTreeMap<String, BTreeSet<String>>>,
+}
+
+impl MessageHistory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a message.
+    ///
+    /// Returns the 1-based display number of a newly stored message. Reactions
+    /// are aggregated onto their target and duplicates are ignored, so both
+    /// return `None`.
+    pub fn insert(&mut self, message: ChatMessage) -> Option<usize> {
+        if let MessageType::Reaction { target_id, emoji } = &message.message_type {
+            self.reactions
+                .entry(target_id.clone())
+                .or_default()
+                .entry(emoji.clone())
+                .or_default()
+                .insert(message.sender.clone());
+            return None;
+        }
+
+        if self.positions.contains_key(&message.id) {
+            return None;
+        }
+
+        self.positions.insert(message.id.clone(), self.messages.len());
+        self.messages.push(message);
+        Some(self.messages.len())
+    }
+
+    /// Look up a message by its display number
+    pub fn get(&self, number: usize) -> Option<&ChatMessage> {
+        number.checked_sub(1).and_then(|i| self.messages.get(i))
+    }
+
+    /// Look up a message by id
+    pub fn find(&self, id: &str) -> Option<&ChatMessage> {
+        self.positions.get(id).map(|&i| &self.messages[i])
+    }
+
+    /// Display number of a message id
+    pub fn number_of(&self, id: &str) -> Option<usize> {
+        self.positions.get(id).map(|&i| i + 1)
+    }
+
+    /// Follow the `reply_to` chain up to the oldest known ancestor
+    pub fn root_of<'a>(&'a self, id: &'a str) -> &'a str {
+        let mut current = id;
+        // Bounded by the history size so a malicious reply cycle can't hang us
+        for _ in 0..=self.messages.len() {
+            match self.find(current).and_then(|m| m.reply_to.as_deref()) {
+                Some(parent) if self.positions.contains_key(parent) => current = parent,
+                _ => break,
+            }
+        }
+        current
+    }
+
+    /// The thread containing `id`: its root followed by all replies in
+    /// depth-first order, each paired with its nesting depth
+    pub fn thread(&self, id: &str) -> Vec<(usize, &ChatMessage)> {
+        let root = self.root_of(id);
+        let Some(root_message) = self.find(root) else {
+            return Vec::new();
+        };
+
+        let mut thread = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = vec![(0, root_message)];
+        while let Some((depth, message)) = stack.pop() {
+            if !visited.insert(message.id.as_str()) {
+                continue;
+            }
+            thread.push((depth, message));
+            // Push in reverse so replies come out in arrival order
+            for reply in self.replies_to(&message.id).into_iter().rev() {
+                stack.push((depth + 1, reply));
+            }
+        }
+        thread
+    }
+
+    /// Direct replies to a message in arrival order
+    pub fn replies_to(&self, id: &str) -> Vec<&ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|m| m.reply_to.as_deref() == Some(id))
+            .collect()
+    }
+
+    /// All messages in causal order, regardless of when they arrived
+    pub fn ordered(&self) -> Vec<&ChatMessage> {
+        let mut messages: Vec<&ChatMessage> = self.messages.iter().collect();
+        messages.sort_by(|a, b| causal_order(a, b));
+        messages
+    }
+
+    /// Display number of the first stored message that causally follows
+    /// `message`, meaning `message` arrived late. Messages without a
+    /// Lamport time can't be placed and are never late.
+    pub fn first_after(&self, message: &ChatMessage) -> Option<usize> {
+        if message.lamport == 0 || message.is_reaction() {
+            return None;
+        }
+        self.messages
+            .iter()
+            .position(|stored| stored.lamport != 0 && causal_order(stored, message).is_gt())
+            .map(|i| i + 1)
+    }
+
+    /// Aggregated reactions on a message as `(emoji, count)` pairs
+    pub fn reactions(&self, id: &str) -> Vec<(&str, usize)> {
+        self.reactions
+            .get(id)
+            .map(|by_emoji| {
+                by_emoji
+                    .iter()
+                    .map(|(emoji, senders)| (emoji.as_str(), senders.len()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sender: &str, content: &str, reply_to: Option<&str>) -> ChatMessage {
+        let mut message = ChatMessage::new(sender.to_string(), content.to_string(), MessageType::Broadcast);
+        message.reply_to = reply_to.map(str::to_string);
+        message
+    }
+
+    #[test]
+    fn test_thread_groups_replies_under_root() {
+        let mut history = MessageHistory::new();
+        let root = message("alice", "lunch?", None);
+        let reply = message("bob", "yes", Some(&root.id));
+        let nested = message("alice", "noon then", Some(&reply.id));
+        let other = message("carol", "unrelated", None);
+        let (root_id, nested_id) = (root.id.clone(), nested.id.clone());
+
+        for m in [root, reply, nested, other] {
+            history.insert(m);
+        }
+
+        let thread = history.thread(&nested_id);
+        let contents: Vec<_> = thread.iter().map(|(d, m)| (*d, m.content.as_str())).collect();
+        assert_eq!(contents, vec![(0, "lunch?"), (1, "yes"), (2, "noon then")]);
+        assert_eq!(history.root_of(&nested_id), root_id);
+    }
+
+    #[test]
+    fn test_reactions_are_aggregated_per_sender() {
+        let mut history = MessageHistory::new();
+        let target = message("alice", "shipped!", None);
+        let target_id = target.id.clone();
+        assert_eq!(history.insert(target), Some(1));
+
+        for sender in ["bob", "carol", "bob"] {
+            let reaction = ChatMessage::new(
+                sender.to_string(),
+                String::new(),
+                MessageType::Reaction { target_id: target_id.clone(), emoji: "🎉".to_string() },
+            );
+            assert_eq!(history.insert(reaction), None);
+        }
+
+        assert_eq!(history.reactions(&target_id), vec![("🎉", 2)]);
+        assert_eq!(history.len(), 1);
+    }
+
+    #[test]
+    fn test_late_messages_are_placed() {
+        let mut history = MessageHistory::new();
+        let mut question = message("alice", "lunch?", None);
+        question.lamport = 1;
+        let mut answer = message("bob", "yes", Some(&question.id));
+        answer.lamport = 2;
+
+        assert_eq!(history.insert(answer.clone()), Some(1));
+        assert_eq!(history.first_after(&question), Some(1));
+        assert_eq!(history.first_after(&answer), None);
+        history.insert(question);
+
+        let mut legacy = message("carol", "hello", None);
+        legacy.lamport = 0;
+        assert_eq!(history.first_after(&legacy), None);
+    }
+}
@@ -3,135 +3,411 @@
 //! This crate provides the core functionality for the P2P chat application,
 //! including protocols, types, storage, and crypto utilities.
 
+pub mod address_book;
+pub mod bootstrap;
+pub mod bot;
+pub mod clock;
+pub mod config;
+pub mod contacts;
+pub mod discovery;
+pub mod error;
+pub mod events;
+pub mod history;
+pub mod mentions;
 pub mod network;
+pub mod outbox;
+pub mod record_store;
+pub mod resolve;
+pub mod room;
+pub mod signing;
+pub mod storage;
+pub mod sync;
 pub mod types;
+pub mod wire;
 
+pub use bot::{run_bot, Bot, BotAction, BotCommand, CommandDispatcher, PeerEvent};
+pub use clock::LamportClock;
+pub use config::Settings;
+pub use contacts::Contact;
+pub use error::ChatError;
+pub use events::{EventBus, EventFilter, EventKind, EventStream};
+pub use history::*;
+pub use mentions::*;
 pub use network::*;
+pub use outbox::{Outbox, OutboxEntry};
+pub use room::*;
+pub use storage::MessageStore;
 pub use types::*;
+pub use wire::{WireFormat, PROTOCOL_VERSION};
 
-use tokio::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 /// Initialize the chat core library
-pub fn init() -> anyhow::Result<()> {
+pub fn init() -> error::Result<()> {
     tracing::info!("Chat core library initialized");
     Ok(())
 }
 
-/// Chat client handle for applications to interact with
+/// Chat client handle for applications to interact with.
+///
+/// Clones share the same network; each can [`subscribe`](ChatClient::subscribe)
+/// to its own stream of events. Commands go through a bounded queue, so
+/// sending waits while the network is busy.
+#[derive(Clone)]
 pub struct ChatClient {
-    command_sender: mpsc::UnboundedSender<ChatCommand>,
+    command_sender: mpsc::Sender<ChatCommand>,
+    events: EventBus,
+    /// The network task, taken by whichever clone shuts it down
+    task: Arc<Mutex<Option<JoinHandle<error::Result<()>>>>>,
 }
 
 /// Commands that can be sent to the chat network
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub enum ChatCommand {
     SendBroadcast(String),
     SendDirect { peer_id: String, message: String },
+    SendReply { reply_to: String, message: String },
+    SendReaction { target_id: String, emoji: String },
+    SendToRoom { room: String, message: String },
+    /// Publish a message built by the caller, who then knows its id up front
+    SendMessage(Box<ChatMessage>),
+    JoinRoom(String),
+    LeaveRoom(String),
     ListPeers,
-    GetPeerList,
+    /// Reply with the current peer list instead of emitting an event
+    GetPeerList(oneshot::Sender<Vec<PeerInfo>>),
+    /// Reply with the peer id a username or peer id prefix refers to
+    ResolvePeer { query: String, reply: oneshot::Sender<error::Result<String>> },
+    /// Reply with every pinned contact
+    GetContacts(oneshot::Sender<Vec<Contact>>),
+    /// Reply with the safety number shared with a contact
+    GetSafetyNumber { username: String, reply: oneshot::Sender<error::Result<String>> },
+    /// Mark a contact's key as confirmed and reply with the contact
+    VerifyContact { username: String, reply: oneshot::Sender<error::Result<Contact>> },
+    /// Unpin a contact and reply with what was pinned
+    ForgetContact { username: String, reply: oneshot::Sender<error::Result<Contact>> },
+    /// Reply with the newest stored messages on a topic
+    GetHistory { topic: String, limit: usize, reply: oneshot::Sender<Vec<ChatMessage>> },
+    /// Leave all topics, close connections and stop the network
+    Shutdown,
 }
 
 impl ChatClient {
+    /// Start a new stream of network events, beginning with the next one
+    pub fn subscribe(&self) -> EventStream {
+        self.events.subscribe()
+    }
+
+    /// Presence updates dropped so far because subscribers fell behind
+    pub fn dropped_events(&self) -> u64 {
+        self.events.dropped()
+    }
+
     /// Send a broadcast message to all peers
-    pub fn send_broadcast(&self, message: String) -> anyhow::Result<()> {
-        self.command_sender.send(ChatCommand::SendBroadcast(message))?;
+    pub async fn send_broadcast(&self, message: String) -> error::Result<()> {
+        self.command_sender.send(ChatCommand::SendBroadcast(message)).await?;
         Ok(())
     }
     
     /// Send a direct message to a specific peer
-    pub fn send_direct(&self, peer_id: String, message: String) -> anyhow::Result<()> {
-        self.command_sender.send(ChatCommand::SendDirect { peer_id, message })?;
+    pub async fn send_direct(&self, peer_id: String, message: String) -> error::Result<()> {
+        if peer_id.parse::<libp2p::PeerId>().is_err() {
+            return Err(ChatError::InvalidPeerId(peer_id));
+        }
+        self.command_sender.send(ChatCommand::SendDirect { peer_id, message }).await?;
         Ok(())
     }
     
+    /// Broadcast a reply to an earlier message
+    pub async fn send_reply(&self, reply_to: String, message: String) -> error::Result<()> {
+        self.command_sender.send(ChatCommand::SendReply { reply_to, message }).await?;
+        Ok(())
+    }
+
+    /// React to an earlier message with an emoji
+    pub async fn send_reaction(&self, target_id: String, emoji: String) -> error::Result<()> {
+        self.command_sender.send(ChatCommand::SendReaction { target_id, emoji }).await?;
+        Ok(())
+    }
+
+    /// Broadcast a message to the members of a room
+    pub async fn send_to_room(&self, room: String, message: String) -> error::Result<()> {
+        room::validate_room_name(&room)?;
+        self.command_sender.send(ChatCommand::SendToRoom { room, message }).await?;
+        Ok(())
+    }
+
+    /// Sign and publish a prepared message.
+    ///
+    /// Delivery is reported with `MessageSent`, `MessageQueued` or
+    /// `MessageFailed` events carrying the message's id.
+    pub async fn send_message(&self, message: ChatMessage) -> error::Result<()> {
+        if let Some(room) = &message.room {
+            room::validate_room_name(room)?;
+        }
+        self.command_sender.send(ChatCommand::SendMessage(Box::new(message))).await?;
+        Ok(())
+    }
+
+    /// Start receiving messages sent to a room
+    pub async fn join_room(&self, room: String) -> error::Result<()> {
+        room::validate_room_name(&room)?;
+        self.command_sender.send(ChatCommand::JoinRoom(room)).await?;
+        Ok(())
+    }
+
+    /// Stop receiving messages sent to a room
+    pub async fn leave_room(&self, room: String) -> error::Result<()> {
+        room::validate_room_name(&room)?;
+        self.command_sender.send(ChatCommand::LeaveRoom(room)).await?;
+        Ok(())
+    }
+
     /// Request the list of connected peers
-    pub fn list_peers(&self) -> anyhow::Result<()> {
-        self.command_sender.send(ChatCommand::ListPeers)?;
+    pub async fn list_peers(&self) -> error::Result<()> {
+        self.command_sender.send(ChatCommand::ListPeers).await?;
         Ok(())
     }
+
+    /// Fetch the list of connected peers
+    pub async fn peers(&self) -> error::Result<Vec<PeerInfo>> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::GetPeerList(reply)).await?;
+        Ok(response.await?)
+    }
+
+    /// Find the peer id of a connected or recently heard from peer by
+    /// username or peer id prefix; full peer ids are returned as is
+    pub async fn resolve_peer(&self, query: String) -> error::Result<String> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::ResolvePeer { query, reply }).await?;
+        response.await?
+    }
+
+    /// Fetch the usernames pinned to their keys so far
+    pub async fn contacts(&self) -> error::Result<Vec<Contact>> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::GetContacts(reply)).await?;
+        Ok(response.await?)
+    }
+
+    /// Safety number shared with a contact. Both sides see the same number
+    /// only if each has the other's real key pinned.
+    pub async fn safety_number(&self, username: String) -> error::Result<String> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::GetSafetyNumber { username, reply }).await?;
+        response.await?
+    }
+
+    /// Mark a contact as verified after comparing safety numbers
+    pub async fn verify_contact(&self, username: String) -> error::Result<Contact> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::VerifyContact { username, reply }).await?;
+        response.await?
+    }
+
+    /// Unpin a contact, for when they really changed keys; the next key to
+    /// sign as them is pinned instead
+    pub async fn forget_contact(&self, username: String) -> error::Result<Contact> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::ForgetContact { username, reply }).await?;
+        response.await?
+    }
+
+    /// Fetch the newest `limit` stored messages on a topic, in causal order
+    pub async fn history(&self, topic: String, limit: usize) -> error::Result<Vec<ChatMessage>> {
+        let (reply, response) = oneshot::channel();
+        self.command_sender.send(ChatCommand::GetHistory { topic, limit, reply }).await?;
+        Ok(response.await?)
+    }
+
+    /// Stop the network: leave every topic, save pending messages and close
+    /// all connections, then return how the network task ended.
+    ///
+    /// Every event stream receives [`NetworkEvent::Stopped`] and then ends.
+    /// Other clones of this client stop working; only the first call to
+    /// `shutdown` waits for the network, later ones fail.
+    pub async fn shutdown(&self) -> error::Result<()> {
+        let Some(task) = self.task.lock().unwrap().take() else {
+            return Err(ChatError::ClientClosed);
+        };
+        // The send fails if the network already stopped on its own
+        let _ = self.command_sender.send(ChatCommand::Shutdown).await;
+        task.await?
+    }
 }
 
-/// Initialize chat core with DHT networking and return a client handle
+/// Initialize chat core with DHT networking and return a client handle,
+/// together with an event stream that sees every event from startup on
 pub async fn start_chat_client(
     config: NetworkConfig, 
     username: String
-) -> anyhow::Result<(ChatClient, mpsc::UnboundedReceiver<NetworkEvent>)> {
+) -> error::Result<(ChatClient, EventStream)> {
     tracing::info!("Starting chat client with DHT networking");
     
     // Create command channel
-    let (command_sender, command_receiver) = mpsc::unbounded_channel();
+    let (command_sender, command_receiver) = mpsc::channel(config.command_buffer.max(1));
+    let rooms = config.rooms.clone();
     
     // Initialize network
     let (network, event_receiver) = init_network_with_dht(config).await?;
+    let events = network.events.clone();
     
     // Start the network task
-    tokio::spawn(async move {
-        if let Err(e) = run_chat_network(network, command_receiver, username).await {
+    let bus = events.clone();
+    let task = tokio::spawn(async move {
+        let result = run_chat_network(network, command_receiver, username, rooms).await;
+        if let Err(e) = &result {
             tracing::error!("Chat network error: {}", e);
         }
+        bus.emit(NetworkEvent::Stopped { error: result.as_ref().err().map(|e| e.to_string()) });
+        bus.close();
+        result
     });
     
-    let client = ChatClient { command_sender };
+    let client = ChatClient { command_sender, events, task: Arc::new(Mutex::new(Some(task))) };
     Ok((client, event_receiver))
 }
 
 /// Internal function to run the chat network
 async fn run_chat_network(
     mut network: P2pNetwork,
-    mut command_receiver: mpsc::UnboundedReceiver<ChatCommand>,
+    mut command_receiver: mpsc::Receiver<ChatCommand>,
     username: String,
-) -> anyhow::Result<()> {
+    rooms: Vec<String>,
+) -> error::Result<()> {
     use futures::stream::StreamExt;
     
     // Subscribe to chat messages
+    network.username = username.clone();
     network.subscribe_to_chat()?;
-    network.start_peer_discovery();
+    for room in rooms {
+        network.join_room(&room)?;
+    }
+
+    network.redial_known_peers();
+
+    // Let the application know about messages still waiting from a previous run
+    for entry in network.outbox.entries() {
+        network.events.emit(NetworkEvent::MessageQueued(entry.message.clone()));
+    }
+    // and about those that expired while we were offline
+    for entry in network.outbox.take_expired() {
+        let error = "Expired while waiting for peers".to_string();
+        network.events.emit(NetworkEvent::MessageFailed { message: entry.message, error });
+    }
+
+    let mut flush_timer = tokio::time::interval(network::FLUSH_INTERVAL);
+    flush_timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
     
     loop {
         tokio::select! {
-            // Handle swarm events
-            swarm_event = network.swarm.select_next_some() => {
+            // Handle swarm events, unless a subscriber is too far behind to take more
+            swarm_event = network.swarm.select_next_some(), if !network.events.is_saturated() => {
                 network.handle_swarm_event(swarm_event).await;
             }
+
+            // Wake up to resume the swarm once subscribers catch up
+            _ = network.events.space_freed(), if network.events.is_saturated() => {}
+
+            // Keep the DHT routing table fresh
+            _ = tokio::time::sleep_until(network.bootstrap.next_round()) => {
+                network.bootstrap_dht();
+            }
+
+            // Look for more peers while we have too few
+            _ = tokio::time::sleep_until(network.discovery.next_step()), if !network.discovery.is_walking() => {
+                network.start_peer_discovery();
+            }
+
+            // Save changes held back by throttling, however quiet the network is
+            _ = flush_timer.tick() => {
+                network.flush_throttled();
+            }
             
             // Handle commands from the client
             command = command_receiver.recv() => {
                 match command {
                     Some(ChatCommand::SendBroadcast(content)) => {
-                        let message = ChatMessage {
-                            id: uuid::Uuid::new_v4().to_string(),
-                            sender: username.clone(),
-                            content,
-                            timestamp: std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs(),
-                            message_type: MessageType::Broadcast,
-                        };
-                        let _ = network.publish_message(&message);
+                        let message = ChatMessage::new(username.clone(), content, MessageType::Broadcast);
+                        network.send_message(message);
                     }
                     Some(ChatCommand::SendDirect { peer_id, message: content }) => {
-                        let message = ChatMessage {
-                            id: uuid::Uuid::new_v4().to_string(),
-                            sender: username.clone(),
+                        let message = ChatMessage::new(
+                            username.clone(),
                             content,
-                            timestamp: std::time::SystemTime::now()
-                                .duration_since(std::time::UNIX_EPOCH)
-                                .unwrap_or_default()
-                                .as_secs(),
-                            message_type: MessageType::Direct { target_peer_id: peer_id },
-                        };
-                        let _ = network.publish_message(&message);
+                            MessageType::Direct { target_peer_id: peer_id },
+                        );
+                        network.send_message(message);
+                    }
+                    Some(ChatCommand::SendReply { reply_to, message: content }) => {
+                        let mut message = ChatMessage::new(username.clone(), content, MessageType::Broadcast);
+                        message.room = network.room_of(&reply_to);
+                        message.reply_to = Some(reply_to);
+                        network.send_message(message);
+                    }
+                    Some(ChatCommand::SendReaction { target_id, emoji }) => {
+                        let room = network.room_of(&target_id);
+                        let mut message = ChatMessage::new(
+                            username.clone(),
+                            String::new(),
+                            MessageType::Reaction { target_id, emoji },
+                        );
+                        message.room = room;
+                        network.send_message(message);
+                    }
+                    Some(ChatCommand::SendToRoom { room, message: content }) => {
+                        let mut message = ChatMessage::new(username.clone(), content, MessageType::Broadcast);
+                        message.room = (room != DEFAULT_ROOM).then_some(room);
+                        network.send_message(message);
+                    }
+                    Some(ChatCommand::SendMessage(message)) => {
+                        network.send_message(*message);
+                    }
+                    Some(ChatCommand::JoinRoom(room)) => {
+                        if let Err(e) = network.join_room(&room) {
+                            tracing::warn!("Failed to join room {}: {}", room, e);
+                        }
+                    }
+                    Some(ChatCommand::LeaveRoom(room)) => {
+                        if let Err(e) = network.leave_room(&room) {
+                            tracing::warn!("Failed to leave room {}: {}", room, e);
+                        }
                     }
                     Some(ChatCommand::ListPeers) => {
                         let peers = network.get_peer_list();
-                        let _ = network.event_sender.send(NetworkEvent::PeerListUpdated(peers));
+                        network.events.emit(NetworkEvent::PeerListUpdated(peers));
                     }
-                    Some(ChatCommand::GetPeerList) => {
-                        let peers = network.get_peer_list();
-                        let _ = network.event_sender.send(NetworkEvent::PeerListUpdated(peers));
+                    Some(ChatCommand::GetPeerList(reply)) => {
+                        let _ = reply.send(network.get_peer_list());
+                    }
+                    Some(ChatCommand::ResolvePeer { query, reply }) => {
+                        let _ = reply.send(network.resolve_peer(&query));
+                    }
+                    Some(ChatCommand::GetContacts(reply)) => {
+                        let _ = reply.send(network.contacts.list());
+                    }
+                    Some(ChatCommand::GetSafetyNumber { username, reply }) => {
+                        let _ = reply.send(network.safety_number(&username));
+                    }
+                    Some(ChatCommand::VerifyContact { username, reply }) => {
+                        let contact = network.contacts.verify(&username).cloned();
+                        let _ = reply.send(contact.ok_or(ChatError::UnknownContact(username)));
+                    }
+                    Some(ChatCommand::ForgetContact { username, reply }) => {
+                        let contact = network.forget_contact(&username);
+                        let _ = reply.send(contact.ok_or(ChatError::UnknownContact(username)));
+                    }
+                    Some(ChatCommand::GetHistory { topic, limit, reply }) => {
+                        let _ = reply.send(network.store.recent(&topic, limit));
+                    }
+                    // Every client handle being dropped also stops the network
+                    Some(ChatCommand::Shutdown) | None => {
+                        network.shutdown().await?;
+                        break;
                     }
-                    None => break,
                 }
             }
         }
@@ -148,4 +424,43 @@ mod tests {
     fn test_init() {
         assert!(init().is_ok());
     }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_outbox_and_reports_stopped() {
+        let dir = std::env::temp_dir().join(format!("chat-shutdown-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| Some(dir.join(name).to_str().unwrap().to_string());
+        let config = NetworkConfig {
+            listen_addresses: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            dht_config: DhtConfig { bootstrap_nodes: Vec::new(), ..DhtConfig::default() },
+            key_file: file("peer_key.dat").unwrap(),
+            outbox_file: file("outbox.json"),
+            history_file: None,
+            peers_file: None,
+            contacts_file: None,
+            ..NetworkConfig::default()
+        };
+        let (client, mut events) = start_chat_client(config, "alice".to_string()).await.unwrap();
+
+        // Nobody else is around, so the message waits in the outbox
+        client.send_broadcast("anyone there?".to_string()).await.unwrap();
+        loop {
+            match events.recv().await.unwrap() {
+                NetworkEvent::MessageQueued(message) => break assert_eq!(message.content, "anyone there?"),
+                _ => continue,
+            }
+        }
+        client.shutdown().await.unwrap();
+
+        let mut stopped = None;
+        while let Some(event) = events.recv().await {
+            if let NetworkEvent::Stopped { error } = event {
+                stopped = Some(error);
+            }
+        }
+        assert_eq!(stopped, Some(None));
+        let outbox = outbox::Outbox::load(file("outbox.json").as_deref()).unwrap();
+        assert_eq!(outbox.entries().len(), 1);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
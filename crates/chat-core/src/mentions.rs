@@ -0,0 +1,114 @@
+//! Parsing and matching of `@name` / `@peerid-prefix` mentions
+
+use serde::{Deserialize, Serialize};
+
+/// Base58 prefix shared by all Ed25519 peer ids
+const PEER_ID_PREFIX: &str = "12D3KooW";
+
+/// Shortest peer id prefix accepted as a mention, matching the prefix length shown in the UI
+pub const MIN_PEER_ID_MENTION_LEN: usize = 12;
+
+/// What a mention refers to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MentionKind {
+    /// A username, matched case-insensitively
+    Username,
+    /// A prefix of a peer id
+    PeerId,
+}
+
+/// A mention of a user inside a message
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Mention {
+    /// Mentioned name or peer id prefix, without the leading `@`
+    pub target: String,
+    pub kind: MentionKind,
+}
+
+impl Mention {
+    /// Whether this mention refers to the given user
+    pub fn matches(&self, username: &str, peer_id: &str) -> bool {
+        match self.kind {
+            MentionKind::Username => self.target.eq_ignore_ascii_case(username),
+            MentionKind::PeerId => {
+                self.target.len() >= MIN_PEER_ID_MENTION_LEN && peer_id.starts_with(&self.target)
+            }
+        }
+    }
+}
+
+fn is_mention_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Extract all mentions from message content.
+///
+/// An `@` only starts a mention at the beginning of the text or after a
+/// non-word character, so e-mail addresses are not picked up.
+pub fn parse_mentions(content: &str) -> Vec<Mention> {
+    let mut mentions: Vec<Mention> = Vec::new();
+    let mut previous = None;
+
+    for (i, c) in content.char_indices() {
+        let at_boundary = previous.is_none_or(|p: char| !is_mention_char(p) && p != '@');
+        previous = Some(c);
+        if c != '@' || !at_boundary {
+            continue;
+        }
+
+        let rest = &content[i + 1..];
+        let end = rest.find(|c: char| !is_mention_char(c)).unwrap_or(rest.len());
+        // Trailing punctuation ends a sentence rather than the name
+        let target = rest[..end].trim_end_matches(['.', '-']);
+        if target.is_empty() {
+            continue;
+        }
+
+        let kind = if target.starts_with(PEER_ID_PREFIX) {
+            MentionKind::PeerId
+        } else {
+            MentionKind::Username
+        };
+        let mention = Mention { target: target.to_string(), kind };
+        if !mentions.contains(&mention) {
+            mentions.push(mention);
+        }
+    }
+
+    mentions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_mentions() {
+        let mentions = parse_mentions("@alice can you ping @12D3KooWAbcdEf and mail bob@example.com? thanks @alice.");
+        assert_eq!(
+            mentions,
+            vec![
+                Mention { target: "alice".to_string(), kind: MentionKind::Username },
+                Mention { target: "12D3KooWAbcdEf".to_string(), kind: MentionKind::PeerId },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_mention_matching() {
+        let peer_id = "12D3KooWAbcdEfGhijKlmnop";
+        assert!(parse_mentions("hey @Alice")[0].matches("alice", peer_id));
+        assert!(parse_mentions("hey @12D3KooWAbcd")[0].matches("alice", peer_id));
+        // Too short to identify anyone
+        assert!(!parse_mentions("hey @12D3KooWA")[0].matches("alice", peer_id));
+        assert!(!parse_mentions("hey @bob")[0].matches("alice", peer_id));
+
+        // Only what the content says counts, whatever the metadata claims
+        let mut message = crate::ChatMessage::new("bob".into(), "hi all".into(), crate::MessageType::Broadcast);
+        message.mentions = parse_mentions("@alice");
+        assert!(!message.mentions_user("alice", peer_id));
+        message.content = "hi @alice".into();
+        message.mentions.clear();
+        assert!(message.mentions_user("alice", peer_id));
+    }
+}
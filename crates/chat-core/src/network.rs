@@ -1,41 +1,117 @@
 //! Network-related functionality for P2P chat
 
-use anyhow::Result;
 use futures::stream::StreamExt;
 use libp2p::{
     gossipsub::{self, MessageId, ValidationMode},
     identify,
-    kad::{self, store::MemoryStore, Behaviour as KademliaBehaviour, Event as KademliaEvent},
+    kad::{self, Behaviour as KademliaBehaviour, Event as KademliaEvent},
     noise,
     ping::{self, Event as PingEvent},
-    swarm::{NetworkBehaviour, SwarmEvent},
-    tcp, yamux, Multiaddr, PeerId, Swarm, Transport,
+    request_response::{self, OutboundRequestId, ProtocolSupport},
+    swarm::{
+        dial_opts::{DialOpts, PeerCondition},
+        NetworkBehaviour, SwarmEvent,
+    },
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm, Transport,
 };
 use std::fs;
 use std::path::Path;
 use std::{
-    collections::{hash_map::DefaultHasher, HashMap},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     hash::{Hash, Hasher},
     time::Duration,
 };
-use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 
-use crate::{types::*, DhtConfig, NetworkEvent};
+use crate::{
+    address_book::AddressBook,
+    bootstrap::{BootstrapProgress, BootstrapSchedule},
+    contacts::{self, Contact, ContactBook, ContactCheck},
+    clock::{self, LamportClock},
+    discovery::RandomWalk,
+    error::{ChatError, Result},
+    events::{self, EventBus, EventStream},
+    outbox::Outbox,
+    record_store::DhtStore,
+    resolve,
+    room, signing,
+    storage::{HistoryCursor, MessageStore},
+    sync::{self, SyncRequest, SyncResponse},
+    types::*,
+    wire, DhtConfig, NetworkEvent, WireFormat,
+};
+
+/// How long shutdown waits for peers to hear we are leaving
+pub const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+
+/// Commands queued for the network task by default
+pub const DEFAULT_COMMAND_BUFFER: usize = 256;
+
+/// How often changes held back by save throttling are written out
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
 
 /// Network configuration
 pub struct NetworkConfig {
+    /// Port to listen on all interfaces, used when `listen_addresses` is empty
     pub listen_port: u16,
+    pub listen_addresses: Vec<Multiaddr>,
     pub dht_config: DhtConfig,
     pub key_file: String,
+    /// Encoding for outgoing messages; incoming messages are accepted in any format
+    pub wire_format: WireFormat,
+    /// Compress messages larger than this many bytes; `None` disables compression
+    pub compression_threshold: Option<usize>,
+    /// Largest message gossipsub will send or accept, in bytes
+    pub max_message_size: usize,
+    /// File storing messages that are waiting to be published; `None` keeps them in memory only
+    pub outbox_file: Option<String>,
+    /// File storing message history; `None` keeps it in memory only
+    pub history_file: Option<String>,
+    /// File storing peers we have connected to, redialed at startup;
+    /// `None` keeps them in memory only
+    pub peers_file: Option<String>,
+    /// File storing contacts pinned to their keys; `None` keeps them in
+    /// memory only, so every restart pins afresh
+    pub contacts_file: Option<String>,
+    /// File storing the DHT records we host for other peers; `None` keeps
+    /// them in memory only. Mostly useful on long-running bootstrap nodes.
+    pub dht_records_file: Option<String>,
+    /// How far back to fetch history from peers when joining
+    pub sync_max_backfill: Duration,
+    /// Events queued per subscriber before presence updates are dropped
+    /// and the network stops taking in chat traffic
+    pub event_buffer: usize,
+    /// Commands queued for the network before `ChatClient` calls wait
+    pub command_buffer: usize,
+    /// Rooms joined at startup besides the default one
+    pub rooms: Vec<String>,
+    /// Disable Nagle's algorithm on TCP sockets; `None` keeps the OS default
+    pub tcp_nodelay: Option<bool>,
+    /// How long to keep a connection no protocol is using
+    pub idle_connection_timeout: Duration,
 }
 
 impl Default for NetworkConfig {
     fn default() -> Self {
         Self {
             listen_port: 0, // Let the OS choose
+            listen_addresses: Vec::new(),
             dht_config: DhtConfig::default(),
             key_file: "peer_key.dat".to_string(),
+            wire_format: WireFormat::default(),
+            compression_threshold: Some(wire::DEFAULT_COMPRESSION_THRESHOLD),
+            max_message_size: wire::DEFAULT_MAX_MESSAGE_SIZE,
+            outbox_file: Some("outbox.json".to_string()),
+            history_file: Some("history.jsonl".to_string()),
+            peers_file: Some("peers.json".to_string()),
+            contacts_file: Some("contacts.json".to_string()),
+            dht_records_file: None,
+            sync_max_backfill: Duration::from_secs(24 * 60 * 60),
+            event_buffer: events::DEFAULT_EVENT_BUFFER,
+            command_buffer: DEFAULT_COMMAND_BUFFER,
+            rooms: Vec::new(),
+            tcp_nodelay: None,
+            idle_connection_timeout: Duration::ZERO,
         }
     }
 }
@@ -45,9 +121,10 @@ impl Default for NetworkConfig {
 #[behaviour(to_swarm = "ChatBehaviourEvent")]
 pub struct ChatBehaviour {
     pub gossipsub: gossipsub::Behaviour,
-    pub kademlia: KademliaBehaviour<MemoryStore>,
+    pub kademlia: KademliaBehaviour<DhtStore>,
     pub identify: identify::Behaviour,
     pub ping: ping::Behaviour,
+    pub sync: request_response::cbor::Behaviour<SyncRequest, SyncResponse>,
 }
 
 #[derive(Debug)]
@@ -56,6 +133,7 @@ pub enum ChatBehaviourEvent {
     Kademlia(KademliaEvent),
     Identify(identify::Event),
     Ping(PingEvent),
+    Sync(request_response::Event<SyncRequest, SyncResponse>),
 }
 
 impl From<gossipsub::Event> for ChatBehaviourEvent {
@@ -82,46 +160,91 @@ impl From<PingEvent> for ChatBehaviourEvent {
     }
 }
 
+impl From<request_response::Event<SyncRequest, SyncResponse>> for ChatBehaviourEvent {
+    fn from(event: request_response::Event<SyncRequest, SyncResponse>) -> Self {
+        ChatBehaviourEvent::Sync(event)
+    }
+}
+
 /// P2P Network manager
 pub struct P2pNetwork {
     pub swarm: Swarm<ChatBehaviour>,
-    pub event_sender: mpsc::UnboundedSender<NetworkEvent>,
+    /// Publishes events to every subscriber
+    pub events: EventBus,
     pub connected_peers: HashMap<PeerId, PeerInfo>,
+    /// Local username, used to detect mentions
+    pub username: String,
+    pub codec: wire::Codec,
+    /// Messages waiting for subscribers on their topic
+    pub outbox: Outbox,
+    /// Messages sent and received, served to peers during history sync
+    pub store: MessageStore,
+    /// Peers remembered across restarts
+    pub address_book: AddressBook,
+    /// Usernames pinned to the keys that first signed messages with them
+    pub contacts: ContactBook,
+    /// Contacts already warned about signing from a new key, with that key
+    key_warnings: HashSet<(String, String)>,
+    /// Names peers last signed a message with, for looking them up by name
+    pub usernames: HashMap<PeerId, String>,
+    /// Peers we have already requested each topic's history from this session
+    pub synced_peers: HashSet<(PeerId, gossipsub::TopicHash)>,
+    pub sync_max_backfill: Duration,
+    pending_syncs: HashMap<OutboundRequestId, SyncRequest>,
+    /// Logical clock stamped on outgoing messages
+    pub clock: LamportClock,
+    /// When to next bootstrap the DHT
+    pub bootstrap: BootstrapSchedule,
+    /// Random walk looking for more peers to connect to
+    pub discovery: RandomWalk,
+    /// DHT lookups for the members of a room
+    room_lookups: HashMap<kad::QueryId, String>,
+    /// Bootstrap nodes, re-added before every round in case they were evicted
+    bootstrap_nodes: Vec<(Option<PeerId>, Multiaddr)>,
+    local_key: libp2p::identity::Keypair,
 }
 
 impl P2pNetwork {
     /// Load or create a persistent keypair
     fn load_or_create_keypair(key_file: &str) -> Result<libp2p::identity::Keypair> {
         
+        let key_error = |e: Box<dyn std::error::Error + Send + Sync>| ChatError::Key {
+            path: key_file.to_string(),
+            source: e,
+        };
         if Path::new(key_file).exists() {
             // Load existing keypair
-            let key_bytes = fs::read(key_file)?;
+            let key_bytes = fs::read(key_file).map_err(|e| key_error(e.into()))?;
             let keypair = libp2p::identity::Keypair::from_protobuf_encoding(&key_bytes)
-                .map_err(|e| anyhow::anyhow!("Failed to decode keypair: {}", e))?;
+                .map_err(|e| key_error(e.into()))?;
             info!("Loaded existing keypair from {}", key_file);
             Ok(keypair)
         } else {
             // Create new keypair and save it
             let keypair = libp2p::identity::Keypair::generate_ed25519();
             let key_bytes = keypair.to_protobuf_encoding()
-                .map_err(|e| anyhow::anyhow!("Failed to encode keypair: {}", e))?;
-            fs::write(key_file, &key_bytes)?;
+                .map_err(|e| key_error(e.into()))?;
+            fs::write(key_file, &key_bytes).map_err(|e| key_error(e.into()))?;
             info!("Created new keypair and saved to {}", key_file);
             Ok(keypair)
         }
     }
 
     /// Create a new P2P network instance
-    pub async fn new(config: NetworkConfig) -> Result<(Self, mpsc::UnboundedReceiver<NetworkEvent>)> {
+    pub async fn new(config: NetworkConfig) -> Result<(Self, EventStream)> {
         // Load or create a persistent keypair
         let local_key = Self::load_or_create_keypair(&config.key_file)?;
         let local_peer_id = PeerId::from(local_key.public());
         info!("Local peer id: {local_peer_id}");
 
         // Create transport
-        let transport = tcp::tokio::Transport::default()
+        let mut tcp_config = tcp::Config::default();
+        if let Some(nodelay) = config.tcp_nodelay {
+            tcp_config = tcp_config.nodelay(nodelay);
+        }
+        let transport = tcp::tokio::Transport::new(tcp_config)
             .upgrade(libp2p::core::upgrade::Version::V1Lazy)
-            .authenticate(noise::Config::new(&local_key)?)
+            .authenticate(noise::Config::new(&local_key).map_err(|e| ChatError::Transport(e.into()))?)
             .multiplex(yamux::Config::default())
             .boxed();
 
@@ -136,6 +259,7 @@ impl P2pNetwork {
             .heartbeat_interval(Duration::from_secs(10))
             .validation_mode(ValidationMode::Strict)
             .message_id_fn(message_id_fn)
+            .max_transmit_size(config.max_message_size)
             .build()
             .expect("Valid config");
 
@@ -145,23 +269,28 @@ impl P2pNetwork {
         ).expect("Valid gossipsub config");
 
         // Create Kademlia behavior
-        let mut kademlia = KademliaBehaviour::new(local_peer_id, MemoryStore::new(local_peer_id));
+        let store = DhtStore::open(local_peer_id, config.dht_records_file.as_deref(), config.dht_config.max_records)
+            .map_err(ChatError::Storage)?;
+        let mut kademlia = KademliaBehaviour::new(local_peer_id, store);
+        // Nothing confirms our external addresses, so Kademlia would otherwise
+        // stay a client and never answer other chat peers' queries
+        kademlia.set_mode(Some(kad::Mode::Server));
 
         // Add bootstrap nodes to Kademlia
+        let mut bootstrap_nodes = Vec::new();
         for addr in &config.dht_config.bootstrap_nodes {
-            if let Ok(peer_id) = addr.iter().find_map(|protocol| {
-                if let libp2p::multiaddr::Protocol::P2p(peer_id) = protocol {
-                    Some(peer_id)
-                } else {
-                    None
-                }
-            }).ok_or("No peer ID in bootstrap address") {
+            let peer_id = addr.iter().find_map(|protocol| match protocol {
+                libp2p::multiaddr::Protocol::P2p(peer_id) => Some(peer_id),
+                _ => None,
+            });
+            if let Some(peer_id) = peer_id {
                 kademlia.add_address(&peer_id, addr.clone());
                 info!("Added bootstrap node: {} at {}", peer_id, addr);
             } else {
-                // If no peer ID in multiaddr, we'll try to connect anyway
-                warn!("Bootstrap address without peer ID: {}", addr);
+                // Identify adds the node to Kademlia once we are connected
+                info!("Added bootstrap address without peer ID, will dial it: {}", addr);
             }
+            bootstrap_nodes.push((peer_id, addr.clone()));
         }
 
         // Create Identify behavior
@@ -173,28 +302,72 @@ impl P2pNetwork {
         // Create Ping behavior
         let ping = ping::Behaviour::new(ping::Config::new());
 
+        // Create history sync behavior
+        let sync = request_response::cbor::Behaviour::new(
+            [(StreamProtocol::new(sync::SYNC_PROTOCOL), ProtocolSupport::Full)],
+            request_response::Config::default(),
+        );
+
         // Combine behaviors
         let behaviour = ChatBehaviour {
             gossipsub,
             kademlia,
             identify,
             ping,
+            sync,
         };
 
         // Create swarm
-        let mut swarm = Swarm::new(transport, behaviour, local_peer_id, libp2p::swarm::Config::with_tokio_executor());
+        let swarm_config = libp2p::swarm::Config::with_tokio_executor()
+            .with_idle_connection_timeout(config.idle_connection_timeout);
+        let mut swarm = Swarm::new(transport, behaviour, local_peer_id, swarm_config);
+
+        // Listen on the configured addresses, or on all interfaces
+        let listen_addrs = if config.listen_addresses.is_empty() {
+            let listen_addr = format!("/ip4/0.0.0.0/tcp/{}", config.listen_port);
+            vec![listen_addr.parse().map_err(|e| ChatError::Transport(Box::new(e)))?]
+        } else {
+            config.listen_addresses.clone()
+        };
+        for listen_addr in listen_addrs {
+            swarm.listen_on(listen_addr).map_err(|e| ChatError::Transport(Box::new(e)))?;
+        }
 
-        // Listen on all interfaces
-        let listen_addr = format!("/ip4/0.0.0.0/tcp/{}", config.listen_port);
-        swarm.listen_on(listen_addr.parse()?)?;
+        let outbox = Outbox::load(config.outbox_file.as_deref()).map_err(ChatError::Storage)?;
+        let store = MessageStore::open(config.history_file.as_deref()).map_err(ChatError::Storage)?;
+        let clock = LamportClock::new(store.max_lamport());
+        let address_book = AddressBook::load(config.peers_file.as_deref()).map_err(ChatError::Storage)?;
+        let contacts = ContactBook::load(config.contacts_file.as_deref()).map_err(ChatError::Storage)?;
 
-        // Create event channel
-        let (event_sender, event_receiver) = mpsc::unbounded_channel();
+        // Create event bus, subscribing before anything is emitted
+        let events = EventBus::new(config.event_buffer);
+        let event_receiver = events.subscribe();
 
         let network = P2pNetwork {
             swarm,
-            event_sender,
+            events,
             connected_peers: HashMap::new(),
+            username: String::new(),
+            codec: wire::Codec {
+                format: config.wire_format,
+                compression_threshold: config.compression_threshold,
+                max_message_size: config.max_message_size,
+            },
+            outbox,
+            store,
+            address_book,
+            contacts,
+            key_warnings: HashSet::new(),
+            usernames: HashMap::new(),
+            synced_peers: HashSet::new(),
+            sync_max_backfill: config.sync_max_backfill,
+            pending_syncs: HashMap::new(),
+            clock,
+            bootstrap: BootstrapSchedule::new(config.dht_config.bootstrap_interval),
+            discovery: RandomWalk::new(config.dht_config.discovery_interval, config.dht_config.target_peers),
+            room_lookups: HashMap::new(),
+            bootstrap_nodes,
+            local_key,
         };
 
         Ok((network, event_receiver))
@@ -209,30 +382,41 @@ impl P2pNetwork {
             SwarmEvent::Behaviour(event) => {
                 self.handle_behaviour_event(event).await;
             }
-            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, num_established, .. } => {
+                let peer_info = self
+                    .connected_peers
+                    .entry(peer_id)
+                    .or_insert_with(|| PeerInfo::new(peer_id.to_string()));
+                peer_info.connections = num_established.get();
+                if peer_info.username.is_none() {
+                    peer_info.username = self.usernames.get(&peer_id).cloned();
+                }
+                if peer_info.connections > 1 {
+                    debug!("Opened connection {} to peer {peer_id}", peer_info.connections);
+                    return;
+                }
+
                 info!("Connected to peer: {peer_id}");
-                
-                // Add to connected peers if we have info about them
-                if let Some(_peer_info) = self.connected_peers.get(&peer_id) {
-                    let _ = self.event_sender.send(NetworkEvent::PeerConnected(peer_id.to_string()));
+                peer_info.direction = Some(if endpoint.is_dialer() {
+                    ConnectionDirection::Outbound
                 } else {
-                    // Create basic peer info for now
-                    let peer_info = PeerInfo {
-                        peer_id: peer_id.to_string(),
-                        addresses: vec![],
-                        last_seen: std::time::SystemTime::now()
-                            .duration_since(std::time::UNIX_EPOCH)
-                            .unwrap_or_default()
-                            .as_secs(),
-                    };
-                    self.connected_peers.insert(peer_id, peer_info);
-                    let _ = self.event_sender.send(NetworkEvent::PeerConnected(peer_id.to_string()));
-                }
+                    ConnectionDirection::Inbound
+                });
+                self.address_book.record_connected(&peer_id.to_string());
+                self.events.emit(NetworkEvent::PeerConnected(peer_id.to_string()));
             }
-            SwarmEvent::ConnectionClosed { peer_id, .. } => {
+            SwarmEvent::ConnectionClosed { peer_id, num_established, .. } => {
+                if num_established > 0 {
+                    if let Some(peer_info) = self.connected_peers.get_mut(&peer_id) {
+                        peer_info.connections = num_established;
+                    }
+                    return;
+                }
+
                 info!("Disconnected from peer: {peer_id}");
+                self.address_book.record_seen(&peer_id.to_string());
                 self.connected_peers.remove(&peer_id);
-                let _ = self.event_sender.send(NetworkEvent::PeerDisconnected(peer_id.to_string()));
+                self.events.emit(NetworkEvent::PeerDisconnected(peer_id.to_string()));
             }
             SwarmEvent::IncomingConnection { .. } => {
                 debug!("Incoming connection");
@@ -240,6 +424,7 @@ impl P2pNetwork {
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 if let Some(peer_id) = peer_id {
                     warn!("Outgoing connection error to {peer_id}: {error}");
+                    self.address_book.record_failure(&peer_id.to_string());
                 } else {
                     warn!("Outgoing connection error: {error}");
                 }
@@ -254,34 +439,178 @@ impl P2pNetwork {
     /// Start the network event loop
     pub async fn run(&mut self) -> Result<()> {
         info!("Starting P2P network event loop");
+        self.redial_known_peers();
+
+        loop {
+            tokio::select! {
+                event = self.swarm.select_next_some() => self.handle_swarm_event(event).await,
+                _ = tokio::time::sleep_until(self.bootstrap.next_round()) => self.bootstrap_dht(),
+                _ = tokio::time::sleep_until(self.discovery.next_step()), if !self.discovery.is_walking() => {
+                    self.start_peer_discovery()
+                }
+            }
+        }
+    }
 
-        // Start DHT bootstrap
-        if let Err(e) = self.swarm.behaviour_mut().kademlia.bootstrap() {
-            warn!("Failed to bootstrap DHT: {}", e);
+    /// Dial the peers we were most recently connected to and seed the DHT
+    /// with them, so we don't depend on the bootstrap nodes being up
+    pub fn redial_known_peers(&mut self) {
+        let limit = self.discovery.wanted(0);
+        let peers: Vec<(PeerId, Vec<Multiaddr>)> = self
+            .address_book
+            .recent(limit)
+            .into_iter()
+            .filter_map(|peer| {
+                let peer_id = peer.peer_id.parse().ok()?;
+                let addresses = peer.addresses.iter().filter_map(|addr| addr.parse().ok()).collect();
+                Some((peer_id, addresses))
+            })
+            .collect();
+        if peers.is_empty() {
+            return;
         }
 
-        loop {
-            let event = self.swarm.select_next_some().await;
-            self.handle_swarm_event(event).await;
+        info!("Redialing {} known peers", peers.len());
+        for (peer_id, addresses) in peers {
+            for addr in &addresses {
+                self.swarm.behaviour_mut().kademlia.add_address(&peer_id, addr.clone());
+            }
+            let opts = DialOpts::peer_id(peer_id)
+                .addresses(addresses)
+                .condition(PeerCondition::DisconnectedAndNotDialing)
+                .build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("Failed to redial known peer {peer_id}: {e}");
+            }
+        }
+    }
+
+    /// Write out DHT and known peer changes held back by throttling
+    pub fn flush_throttled(&mut self) {
+        let store = self.swarm.behaviour_mut().kademlia.store_mut();
+        if store.is_dirty() {
+            if let Err(e) = store.flush() {
+                warn!("Failed to save DHT records: {e}");
+            }
+        }
+        if self.address_book.is_dirty() {
+            if let Err(e) = self.address_book.flush() {
+                warn!("Failed to save known peers: {e}");
+            }
+        }
+    }
+
+    /// Start a DHT bootstrap round unless one is already running
+    pub fn bootstrap_dht(&mut self) {
+        if self.bootstrap.is_running() {
+            return;
+        }
+        let reseed = !self.bootstrap.is_healthy() && self.swarm.network_info().num_peers() == 0;
+        for (peer_id, addr) in &self.bootstrap_nodes {
+            match peer_id {
+                Some(peer_id) => {
+                    self.swarm.behaviour_mut().kademlia.add_address(peer_id, addr.clone());
+                }
+                // Without a peer ID all we can do is connect and let identify introduce it
+                None if reseed => {
+                    if let Err(e) = self.swarm.dial(addr.clone()) {
+                        debug!("Failed to dial bootstrap address {addr}: {e}");
+                    }
+                }
+                None => {}
+            }
         }
+        match self.swarm.behaviour_mut().kademlia.bootstrap() {
+            Ok(_) => {
+                debug!("Started DHT bootstrap");
+                self.bootstrap.started();
+            }
+            Err(e) => self.bootstrap_failed(ChatError::from(e).to_string()),
+        }
+    }
+
+    fn bootstrap_failed(&mut self, error: String) {
+        let retry_in = self.bootstrap.failed();
+        warn!("DHT bootstrap failed, retrying in {}s: {error}", retry_in.as_secs());
+        self.events.emit(NetworkEvent::DhtBootstrapFailed { error, retry_in_secs: retry_in.as_secs() });
     }
 
+
     /// Handle behavior-specific events
     async fn handle_behaviour_event(&mut self, event: ChatBehaviourEvent) {
         match event {
             // Kademlia events
             ChatBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryProgressed {
                 result: kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { peer, .. })),
+                step,
+                stats,
                 ..
             }) => {
-                info!("DHT bootstrap successful with peer: {peer}");
-                let _ = self.event_sender.send(NetworkEvent::DhtBootstrapped);
+                debug!("DHT bootstrap progressed with peer: {peer}");
+                self.bootstrap.responded(stats.num_successes());
+                if !step.last {
+                    return;
+                }
+                // The query finishes without error even when nobody answered
+                let responses = self.bootstrap.responses();
+                if responses == 0 {
+                    self.bootstrap_failed("no bootstrap node could be reached".to_string());
+                    return;
+                }
+                info!("DHT bootstrap complete, {responses} peers answered");
+                match self.bootstrap.succeeded() {
+                    BootstrapProgress::First => self.events.emit(NetworkEvent::DhtBootstrapped),
+                    BootstrapProgress::Recovered { failures } => {
+                        self.events.emit(NetworkEvent::DhtBootstrapRecovered { failures })
+                    }
+                    BootstrapProgress::Refreshed => return,
+                }
+                // Rooms joined while the DHT was unreachable were never announced
+                for room in self.joined_rooms() {
+                    self.find_room_members(&room);
+                }
             }
             ChatBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryProgressed {
                 result: kad::QueryResult::Bootstrap(Err(err)),
+                step,
+                ..
+            }) if step.last => {
+                self.bootstrap_failed(err.to_string());
+            }
+            ChatBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetClosestPeers(result),
                 ..
             }) => {
-                warn!("DHT bootstrap failed: {err}");
+                if !self.discovery.finish(id) {
+                    return;
+                }
+                // A timed out walk still found some peers worth trying
+                let peers = match result {
+                    Ok(kad::GetClosestPeersOk { peers, .. }) => peers,
+                    Err(kad::GetClosestPeersError::Timeout { peers, .. }) => peers,
+                };
+                self.dial_discovered(peers);
+            }
+            ChatBehaviourEvent::Kademlia(KademliaEvent::OutboundQueryProgressed {
+                id,
+                result: kad::QueryResult::GetProviders(result),
+                step,
+                ..
+            }) => {
+                let Some(room) = self.room_lookups.get(&id).cloned() else {
+                    return;
+                };
+                if step.last {
+                    self.room_lookups.remove(&id);
+                }
+                match result {
+                    Ok(kad::GetProvidersOk::FoundProviders { providers, .. }) => {
+                        self.dial_room_members(&room, providers);
+                    }
+                    Ok(kad::GetProvidersOk::FinishedWithNoAdditionalRecord { .. }) => {}
+                    Err(e) => debug!("Looking up members of room {room} failed: {e}"),
+                }
             }
             ChatBehaviourEvent::Kademlia(KademliaEvent::RoutingUpdated { peer, .. }) => {
                 debug!("DHT routing updated for peer: {peer}");
@@ -293,6 +622,7 @@ impl P2pNetwork {
                 
                 // Create address strings first
                 let addresses: Vec<String> = info.listen_addrs.iter().map(|a| a.to_string()).collect();
+                self.address_book.record_addresses(&peer_id.to_string(), addresses.clone());
                 
                 // Add peer to Kademlia
                 for addr in &info.listen_addrs {
@@ -300,19 +630,21 @@ impl P2pNetwork {
                     debug!("Added address for {peer_id}: {addr}");
                 }
 
-                // Send peer discovered event and store peer info
-                let peer_info = PeerInfo {
-                    peer_id: peer_id.to_string(),
-                    addresses,
-                    last_seen: std::time::SystemTime::now()
-                        .duration_since(std::time::UNIX_EPOCH)
-                        .unwrap_or_default()
-                        .as_secs(),
+                // Identify can finish just after the connection closed
+                let Some(peer_info) = self.connected_peers.get_mut(&peer_id) else {
+                    return;
                 };
-                
-                // Store peer info for later use
-                self.connected_peers.insert(peer_id, peer_info.clone());
-                let _ = self.event_sender.send(NetworkEvent::PeerDiscovered(peer_info));
+                peer_info.addresses = addresses;
+                peer_info.agent_version = Some(info.agent_version);
+                peer_info.protocol_version = Some(info.protocol_version);
+                peer_info.protocols = info.protocols.iter().map(|protocol| protocol.to_string()).collect();
+                peer_info.observed_addr = Some(info.observed_addr.to_string());
+                peer_info.last_seen = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let peer_info = peer_info.clone();
+                self.events.emit(NetworkEvent::PeerDiscovered(peer_info));
             }
 
             // Ping events
@@ -320,6 +652,9 @@ impl P2pNetwork {
                 match result {
                     Ok(rtt) => {
                         debug!("Ping to {peer}: {rtt:?}");
+                        if let Some(peer_info) = self.connected_peers.get_mut(&peer) {
+                            peer_info.rtt_ms = Some(rtt.as_millis() as u64);
+                        }
                     }
                     Err(err) => {
                         warn!("Ping to {peer} failed: {err}");
@@ -333,10 +668,112 @@ impl P2pNetwork {
                 message_id: _,
                 message,
             }) => {
-                if let Ok(chat_message) = serde_json::from_slice::<ChatMessage>(&message.data) {
-                    info!("Received chat message from {}: {}", chat_message.sender, chat_message.content);
-                    let _ = self.event_sender.send(NetworkEvent::MessageReceived(chat_message));
+                let chat_message = match self.codec.decode(&message.data) {
+                    Ok(Some(chat_message)) => chat_message,
+                    Ok(None) => return,
+                    Err(e) => {
+                        warn!("Dropping malformed message: {e}");
+                        return;
+                    }
+                };
+
+                // Signed messages must come from the peer that signed them
+                if let Some(claimed) = &chat_message.sender_peer_id {
+                    let source_matches = message.source.is_some_and(|source| source.to_string() == *claimed);
+                    if !source_matches || !signing::verify_message(&chat_message) {
+                        warn!("Dropping message {} with a forged author", chat_message.id);
+                        return;
+                    }
+                }
+
+                if !clock::is_plausible(chat_message.lamport) {
+                    warn!("Dropping message {} with an implausible Lamport time", chat_message.id);
+                    return;
                 }
+
+                // A message claiming another room would show up in the wrong place
+                if Self::topic_for(&chat_message) != message.topic.as_str() {
+                    warn!("Dropping message {} published outside its room", chat_message.id);
+                    return;
+                }
+
+                if !self.store.insert(message.topic.as_str(), &chat_message) {
+                    debug!("Ignoring already known message {}", chat_message.id);
+                    return;
+                }
+                self.clock.observe(chat_message.lamport);
+
+                // Only signed messages tell us reliably who a peer is
+                if let (Some(source), Some(_)) = (message.source, &chat_message.sender_peer_id) {
+                    if let Some(peer_info) = self.connected_peers.get_mut(&source) {
+                        peer_info.username = Some(chat_message.sender.clone());
+                    }
+                    self.usernames.insert(source, chat_message.sender.clone());
+                }
+                self.check_contact(&chat_message, true);
+
+                info!("Received chat message from {}: {}", chat_message.sender, chat_message.content);
+                let local_peer_id = self.swarm.local_peer_id().to_string();
+                let mentions_me = chat_message.mentions_user(&self.username, &local_peer_id);
+                let now = std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs();
+                let clock_skew = clock::clock_skew(&chat_message, now);
+                if let Some(skew) = clock_skew {
+                    warn!("Message {} is timestamped {skew}s away from our clock", chat_message.id);
+                }
+                self.events.emit(NetworkEvent::MessageReceived {
+                    message: chat_message,
+                    mentions_me,
+                    clock_skew,
+                });
+            }
+
+            ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed { peer_id, topic }) => {
+                debug!("Peer {peer_id} subscribed to {topic}");
+                self.flush_outbox(topic.as_str());
+
+                if !self.is_subscribed(&topic) {
+                    return;
+                }
+                if let Some(room) = room::room_of_topic(topic.as_str()) {
+                    self.events.emit(NetworkEvent::RoomPeerJoined {
+                        room: room.to_string(),
+                        peer_id: peer_id.to_string(),
+                    });
+                }
+                self.sync_with(peer_id, topic);
+            }
+
+            ChatBehaviourEvent::Gossipsub(gossipsub::Event::Unsubscribed { peer_id, topic }) => {
+                debug!("Peer {peer_id} unsubscribed from {topic}");
+                if let (true, Some(room)) = (self.is_subscribed(&topic), room::room_of_topic(topic.as_str())) {
+                    self.events.emit(NetworkEvent::RoomPeerLeft {
+                        room: room.to_string(),
+                        peer_id: peer_id.to_string(),
+                    });
+                }
+            }
+
+            // History sync events
+            ChatBehaviourEvent::Sync(request_response::Event::Message { peer, message }) => match message {
+                request_response::Message::Request { request, channel, .. } => {
+                    let response = self.serve_history(request);
+                    debug!("Serving {} history messages to {peer}", response.messages.len());
+                    if self.swarm.behaviour_mut().sync.send_response(channel, response).is_err() {
+                        debug!("History sync request from {peer} was dropped before we answered");
+                    }
+                }
+                request_response::Message::Response { request_id, response } => {
+                    if let Some(request) = self.pending_syncs.remove(&request_id) {
+                        self.handle_history(peer, request, response);
+                    }
+                }
+            },
+            ChatBehaviourEvent::Sync(request_response::Event::OutboundFailure { peer, request_id, error }) => {
+                self.pending_syncs.remove(&request_id);
+                warn!("History sync with {peer} failed: {error}");
             }
 
             _ => {}
@@ -352,28 +789,34 @@ impl P2pNetwork {
 
     /// Publish a chat message
     pub fn publish_message(&mut self, message: &ChatMessage) -> Result<()> {
+        let topic = gossipsub::IdentTopic::new(Self::topic_for(message));
+        let data = self.codec.encode(message).map_err(ChatError::Encode)?;
+
         match &message.message_type {
             MessageType::Broadcast => {
-                // Send to all peers via gossipsub
-                let topic = gossipsub::IdentTopic::new("chat");
-                let data = serde_json::to_vec(message)?;
-                
-                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                // Send to all members of the room via gossipsub
+                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
                     warn!("Failed to publish broadcast message: {e}");
-                    return Err(anyhow::anyhow!("Failed to publish broadcast message: {e}"));
+                    return Err(ChatError::from_publish(e, &topic.to_string()));
                 }
-                
-                info!("Published broadcast message: {}", message.content);
+
+                info!("Published broadcast message to {}: {}", message.room_name(), message.content);
+            }
+            MessageType::Reaction { target_id, emoji } => {
+                // Reactions travel on the room topic alongside regular messages
+                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
+                    warn!("Failed to publish reaction: {e}");
+                    return Err(ChatError::from_publish(e, &topic.to_string()));
+                }
+
+                info!("Published reaction {} to {}", emoji, target_id);
             }
             MessageType::Direct { target_peer_id } => {
                 // For direct messages, we'll use gossipsub with a specific topic for now
                 // In a production system, you might want to use request-response protocol
-                let topic = gossipsub::IdentTopic::new(&format!("direct-{}", target_peer_id));
-                let data = serde_json::to_vec(message)?;
-                
-                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic, data) {
+                if let Err(e) = self.swarm.behaviour_mut().gossipsub.publish(topic.clone(), data) {
                     warn!("Failed to publish direct message: {e}");
-                    return Err(anyhow::anyhow!("Failed to publish direct message: {e}"));
+                    return Err(ChatError::from_publish(e, &topic.to_string()));
                 }
                 
                 info!("Published direct message to {}: {}", target_peer_id, message.content);
@@ -383,6 +826,158 @@ impl P2pNetwork {
         Ok(())
     }
 
+    /// Gossipsub topic a message is published on
+    pub fn topic_for(message: &ChatMessage) -> String {
+        match &message.message_type {
+            MessageType::Broadcast | MessageType::Reaction { .. } => room::room_topic(message.room_name()),
+            MessageType::Direct { target_peer_id } => format!("direct-{}", target_peer_id),
+        }
+    }
+
+    /// Room of a stored message, so replies and reactions land next to it
+    pub fn room_of(&self, message_id: &str) -> Option<String> {
+        self.store.get(message_id).and_then(|message| message.room.clone())
+    }
+
+    /// Sign and publish a message, queueing it in the outbox if nobody is
+    /// subscribed to its topic yet
+    pub fn send_message(&mut self, mut message: ChatMessage) {
+        message.lamport = self.clock.tick();
+        if let Err(e) = signing::sign_message(&self.local_key, &mut message) {
+            self.events.emit(NetworkEvent::MessageFailed { message, error: e.to_string() });
+            return;
+        }
+
+        match self.publish_message(&message) {
+            Ok(()) | Err(ChatError::Publish(gossipsub::PublishError::Duplicate)) => self.record_sent(message),
+            Err(ChatError::NoPeers { .. }) => {
+                info!("No peers on topic yet, queueing message {}", message.id);
+                self.outbox.push(Self::topic_for(&message), message.clone(), 1);
+                self.events.emit(NetworkEvent::MessageQueued(message));
+            }
+            Err(e) => {
+                self.events.emit(NetworkEvent::MessageFailed {
+                    message,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+
+    /// Retry queued messages for a topic that just gained a subscriber
+    fn flush_outbox(&mut self, topic: &str) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        for mut entry in self.outbox.take_for_topic(topic) {
+            entry.attempts += 1;
+            let error = match self.publish_message(&entry.message) {
+                Ok(()) | Err(ChatError::Publish(gossipsub::PublishError::Duplicate)) => None,
+                Err(ChatError::NoPeers { .. }) if !entry.is_expired(now) => {
+                    self.outbox.requeue(entry);
+                    continue;
+                }
+                Err(e) => Some(e.to_string()),
+            };
+
+            match error {
+                None => self.record_sent(entry.message),
+                Some(error) => {
+                    warn!("Giving up on message {} after {} attempts", entry.message.id, entry.attempts);
+                    self.events.emit(NetworkEvent::MessageFailed { message: entry.message, error });
+                }
+            }
+        }
+    }
+
+    /// Store a published message and report it to the application
+    fn record_sent(&mut self, message: ChatMessage) {
+        self.store.insert(&Self::topic_for(&message), &message);
+        self.events.emit(NetworkEvent::MessageSent(message));
+    }
+
+    /// Ask a peer for a topic's history, once per session
+    fn sync_with(&mut self, peer_id: PeerId, topic: gossipsub::TopicHash) {
+        if sync::is_shareable_topic(topic.as_str()) && self.synced_peers.insert((peer_id, topic.clone())) {
+            self.request_history(peer_id, topic.into_string());
+        }
+    }
+
+    /// Ask a peer for the history on `topic` we are missing
+    fn request_history(&mut self, peer_id: PeerId, topic: String) {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let window_start = now.saturating_sub(self.sync_max_backfill.as_secs());
+        let since = self.store.latest_timestamp(&topic).map_or(window_start, |latest| latest.max(window_start));
+
+        info!("Requesting history for {topic} since {since} from {peer_id}");
+        let request = SyncRequest { topic, since, after: None, limit: sync::SYNC_BATCH_SIZE };
+        self.send_sync_request(peer_id, request);
+    }
+
+    fn send_sync_request(&mut self, peer_id: PeerId, request: SyncRequest) {
+        let request_id = self.swarm.behaviour_mut().sync.send_request(&peer_id, request.clone());
+        self.pending_syncs.insert(request_id, request);
+    }
+
+    /// Answer a peer's history request from local storage
+    fn serve_history(&self, request: SyncRequest) -> SyncResponse {
+        if !sync::is_shareable_topic(&request.topic) {
+            return SyncResponse { messages: Vec::new(), has_more: false };
+        }
+
+        let limit = request.limit.min(sync::SYNC_BATCH_SIZE);
+        let mut messages = self.store.since(&request.topic, request.since, request.after.as_ref(), limit + 1);
+        let has_more = messages.len() > limit;
+        messages.truncate(limit);
+        SyncResponse { messages, has_more }
+    }
+
+    /// Verify and merge a batch of history, fetching the next batch if there is one
+    fn handle_history(&mut self, peer_id: PeerId, request: SyncRequest, response: SyncResponse) {
+        let next_cursor = response.messages.last().map(HistoryCursor::of);
+
+        let mut merged = Vec::new();
+        for message in response.messages {
+            if Self::topic_for(&message) != request.topic
+                || !clock::is_plausible(message.lamport)
+                || !signing::verify_message(&message)
+            {
+                warn!("Dropping unverifiable history message {} from {peer_id}", message.id);
+                continue;
+            }
+            if self.store.insert(&request.topic, &message) {
+                self.clock.observe(message.lamport);
+                if let Some(author) = message.sender_peer_id.as_ref().and_then(|id| id.parse().ok()) {
+                    self.usernames.entry(author).or_insert_with(|| message.sender.clone());
+                }
+                // Synced history arrives in whatever order the peer chose, so
+                // it may only be checked against pins, never create them
+                self.check_contact(&message, false);
+                merged.push(message);
+            }
+        }
+        merged.sort_by(clock::causal_order);
+
+        info!("Merged {} history messages from {peer_id}", merged.len());
+        if !merged.is_empty() {
+            self.events.emit(NetworkEvent::HistorySynced {
+                peer_id: peer_id.to_string(),
+                messages: merged,
+            });
+        }
+
+        // Only page forward, so a misbehaving peer can't keep us looping
+        let advanced = next_cursor.as_ref().is_some_and(|next| request.after.as_ref() != Some(next));
+        if response.has_more && advanced {
+            self.send_sync_request(peer_id, SyncRequest { after: next_cursor, ..request });
+        }
+    }
+
     /// Subscribe to chat messages
     pub fn subscribe_to_chat(&mut self) -> Result<()> {
         // Subscribe to general chat topic for broadcasts
@@ -392,13 +987,215 @@ impl P2pNetwork {
         
         // Subscribe to direct messages for this peer
         let local_peer_id = *self.swarm.local_peer_id();
-        let direct_topic = gossipsub::IdentTopic::new(&format!("direct-{}", local_peer_id));
+        let direct_topic = gossipsub::IdentTopic::new(format!("direct-{}", local_peer_id));
         self.swarm.behaviour_mut().gossipsub.subscribe(&direct_topic)?;
         info!("Subscribed to direct message topic: direct-{}", local_peer_id);
         
     Ok(())
     }
 
+    /// Start receiving a room's messages; returns `false` if already joined
+    pub fn join_room(&mut self, room: &str) -> Result<bool> {
+        let topic = gossipsub::IdentTopic::new(room::room_topic(room));
+        let joined = self.swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+        if joined {
+            info!("Joined room {room}");
+            if room != room::DEFAULT_ROOM {
+                self.find_room_members(room);
+            }
+            // Peers that announced the room before we joined won't announce it again
+            let hash = topic.hash();
+            let members: Vec<PeerId> = self
+                .swarm
+                .behaviour()
+                .gossipsub
+                .all_peers()
+                .filter(|(_, topics)| topics.contains(&&hash))
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            for peer_id in members {
+                self.events.emit(NetworkEvent::RoomPeerJoined {
+                    room: room.to_string(),
+                    peer_id: peer_id.to_string(),
+                });
+                self.sync_with(peer_id, hash.clone());
+            }
+        }
+        Ok(joined)
+    }
+
+    /// Stop receiving a room's messages; returns `false` if not joined
+    pub fn leave_room(&mut self, room: &str) -> Result<bool> {
+        let topic = gossipsub::IdentTopic::new(room::room_topic(room));
+        let left = self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic).map_err(ChatError::Publish)?;
+        if left {
+            info!("Left room {room}");
+            self.swarm.behaviour_mut().kademlia.stop_providing(&room::room_provider_key(room));
+            self.room_lookups.retain(|_, lookup| lookup != room);
+        }
+        Ok(left)
+    }
+
+    /// Announce ourselves as a member of `room` in the DHT and look up the
+    /// other members, so we connect even when the DHT is large
+    fn find_room_members(&mut self, room: &str) {
+        let key = room::room_provider_key(room);
+        let kademlia = &mut self.swarm.behaviour_mut().kademlia;
+        if let Err(e) = kademlia.start_providing(key.clone()) {
+            warn!("Failed to announce membership of room {room}: {e}");
+        }
+        let query = kademlia.get_providers(key);
+        self.room_lookups.insert(query, room.to_string());
+    }
+
+    /// Dial room members found in the DHT that we aren't connected to
+    fn dial_room_members(&mut self, room: &str, providers: HashSet<PeerId>) {
+        let local_peer_id = *self.swarm.local_peer_id();
+        let members: Vec<PeerId> = providers
+            .into_iter()
+            .filter(|peer_id| *peer_id != local_peer_id && !self.swarm.is_connected(peer_id))
+            .collect();
+        if members.is_empty() {
+            return;
+        }
+
+        info!("Found {} members of room {room} in DHT, dialing", members.len());
+        for peer_id in members {
+            let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::DisconnectedAndNotDialing).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("Failed to dial member {peer_id} of room {room}: {e}");
+            }
+        }
+    }
+
+    /// Named rooms we are in, besides the default one
+    fn joined_rooms(&self) -> Vec<String> {
+        self.swarm
+            .behaviour()
+            .gossipsub
+            .topics()
+            .filter_map(|topic| room::room_of_topic(topic.as_str()))
+            .filter(|room| *room != room::DEFAULT_ROOM)
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Leave every topic, save pending messages and close all connections,
+    /// giving peers up to [`SHUTDOWN_GRACE`] to hear about it
+    pub async fn shutdown(&mut self) -> Result<()> {
+        use futures::stream::StreamExt;
+
+        info!("Shutting down network");
+        // Every step is tried even if an earlier one failed; the first
+        // failure is returned
+        let mut first_error = None;
+        let topics: Vec<gossipsub::TopicHash> = self.swarm.behaviour().gossipsub.topics().cloned().collect();
+        for topic in topics {
+            let topic = gossipsub::IdentTopic::new(topic.into_string());
+            let left = self.swarm.behaviour_mut().gossipsub.unsubscribe(&topic).map(|_| ()).map_err(ChatError::Publish);
+            keep_first_error(&mut first_error, "leave topic", left);
+        }
+        // History is appended as messages arrive; only the outbox may be behind
+        keep_first_error(&mut first_error, "save outbox", self.outbox.flush().map_err(ChatError::Storage));
+        let records = self.swarm.behaviour_mut().kademlia.store_mut().flush();
+        keep_first_error(&mut first_error, "save DHT records", records.map_err(ChatError::Storage));
+
+        // Let the unsubscriptions and anything already published go out before hanging up
+        let deadline = tokio::time::Instant::now() + SHUTDOWN_GRACE;
+        let flush_until = tokio::time::Instant::now() + SHUTDOWN_GRACE / 2;
+        while let Ok(event) = tokio::time::timeout_at(flush_until, self.swarm.select_next_some()).await {
+            debug!("Ignoring swarm event during shutdown: {event:?}");
+        }
+
+        for peer_id in self.connected_peers() {
+            self.address_book.record_seen(&peer_id.to_string());
+            let _ = self.swarm.disconnect_peer_id(peer_id);
+        }
+        while self.swarm.network_info().num_peers() > 0 {
+            if tokio::time::timeout_at(deadline, self.swarm.select_next_some()).await.is_err() {
+                warn!("Timed out waiting for connections to close");
+                break;
+            }
+        }
+        self.connected_peers.clear();
+        keep_first_error(&mut first_error, "save peers", self.address_book.flush().map_err(ChatError::Storage));
+        keep_first_error(&mut first_error, "save contacts", self.contacts.flush().map_err(ChatError::Storage));
+        info!("Network shut down");
+        first_error.map_or(Ok(()), Err)
+    }
+
+    /// Pin the username a verified message was signed under if `may_pin`,
+    /// or warn when the name is already pinned to another key
+    fn check_contact(&mut self, message: &ChatMessage, may_pin: bool) {
+        let Some(peer_id) = &message.sender_peer_id else {
+            return;
+        };
+        let local_peer_id = self.swarm.local_peer_id().to_string();
+        if *peer_id == local_peer_id {
+            return;
+        }
+        // Our own name is pinned to our own key
+        let check = if message.sender == self.username {
+            ContactCheck::KeyChanged(Contact {
+                username: self.username.clone(),
+                peer_id: local_peer_id,
+                pinned_at: 0,
+                verified: true,
+            })
+        } else if may_pin {
+            self.contacts.observe(&message.sender, peer_id)
+        } else {
+            match self.contacts.get(&message.sender) {
+                Some(pinned) if pinned.peer_id != *peer_id => ContactCheck::KeyChanged(pinned.clone()),
+                _ => ContactCheck::Known,
+            }
+        };
+        match check {
+            ContactCheck::Pinned => {
+                info!("Pinned contact {} to {peer_id}", message.sender);
+                self.events.emit(NetworkEvent::ContactPinned {
+                    username: message.sender.clone(),
+                    peer_id: peer_id.clone(),
+                });
+            }
+            ContactCheck::Known => {}
+            ContactCheck::KeyChanged(pinned) => {
+                // Once per session is enough to get noticed
+                if !self.key_warnings.insert((pinned.username.clone(), peer_id.clone())) {
+                    return;
+                }
+                warn!("{} is pinned to {} but signed a message as {peer_id}", pinned.username, pinned.peer_id);
+                self.events.emit(NetworkEvent::ContactKeyChanged {
+                    username: pinned.username,
+                    pinned_peer_id: pinned.peer_id,
+                    peer_id: peer_id.clone(),
+                    verified: pinned.verified,
+                });
+            }
+        }
+    }
+
+    /// Unpin a contact, so the next key to use the name is pinned instead
+    pub fn forget_contact(&mut self, username: &str) -> Option<Contact> {
+        let contact = self.contacts.forget(username)?;
+        // A new key on the name is worth a fresh warning
+        self.key_warnings.retain(|(warned, _)| warned != username);
+        Some(contact)
+    }
+
+    /// Safety number to compare with a contact to confirm their key
+    pub fn safety_number(&self, username: &str) -> Result<String> {
+        let contact = self.contacts.get(username).ok_or_else(|| ChatError::UnknownContact(username.to_string()))?;
+        let theirs: PeerId = contact.peer_id.parse().map_err(|_| ChatError::InvalidPeerId(contact.peer_id.clone()))?;
+        contacts::safety_number(self.swarm.local_peer_id(), &theirs)
+            .map_err(|_| ChatError::InvalidPeerId(contact.peer_id.clone()))
+    }
+
+    /// Whether we are subscribed to `topic`
+    fn is_subscribed(&self, topic: &gossipsub::TopicHash) -> bool {
+        self.swarm.behaviour().gossipsub.topics().any(|t| t == topic)
+    }
+
     /// Get connected peers
     pub fn connected_peers(&self) -> Vec<PeerId> {
         self.swarm.connected_peers().cloned().collect()
@@ -409,17 +1206,225 @@ impl P2pNetwork {
         self.connected_peers.values().cloned().collect()
     }
 
-    /// Start peer discovery in DHT
+    /// Resolve a peer id, a unique peer id prefix or the username of a
+    /// connected or recently heard from peer to a peer id
+    pub fn resolve_peer(&self, query: &str) -> Result<String> {
+        // A contact's name always means the key pinned to it
+        if let Some(contact) = self.contacts.get(query.trim().trim_start_matches('@')) {
+            return Ok(contact.peer_id.clone());
+        }
+        let mut known: HashMap<String, Option<&str>> = self
+            .usernames
+            .iter()
+            .map(|(peer_id, name)| (peer_id.to_string(), Some(name.as_str())))
+            .collect();
+        for peer_id in self.swarm.connected_peers() {
+            known.entry(peer_id.to_string()).or_insert(None);
+        }
+        resolve::resolve_peer(query, known.iter().map(|(peer_id, name)| (peer_id.as_str(), *name)))
+    }
+
+    /// Take a step of the random walk: look up the peers closest to a
+    /// random ID, unless we already have enough connections
     pub fn start_peer_discovery(&mut self) {
-        // Query for random peer IDs to discover peers
-        let random_peer_id = PeerId::random();
-        self.swarm.behaviour_mut().kademlia.get_closest_peers(random_peer_id);
-        info!("Started peer discovery in DHT");
+        let wanted = self.discovery.wanted(self.swarm.network_info().num_peers());
+        if wanted == 0 {
+            debug!("Enough peers connected, skipping discovery");
+            self.discovery.stepped(None);
+            return;
+        }
+        let query = self.swarm.behaviour_mut().kademlia.get_closest_peers(PeerId::random());
+        debug!("Started peer discovery in DHT, looking for {wanted} more peers");
+        self.discovery.stepped(Some(query));
+    }
+
+    /// Dial peers found by the random walk, up to the number still wanted
+    fn dial_discovered(&mut self, peers: Vec<PeerId>) {
+        let wanted = self.discovery.wanted(self.swarm.network_info().num_peers());
+        let local_peer_id = *self.swarm.local_peer_id();
+        let candidates: Vec<PeerId> = peers
+            .into_iter()
+            .filter(|peer_id| *peer_id != local_peer_id && !self.swarm.is_connected(peer_id))
+            .take(wanted)
+            .collect();
+        if candidates.is_empty() {
+            return;
+        }
+
+        info!("Discovered {} new peers in DHT, dialing", candidates.len());
+        for peer_id in candidates {
+            // Kademlia supplies the addresses it learned for the peer
+            let opts = DialOpts::peer_id(peer_id).condition(PeerCondition::DisconnectedAndNotDialing).build();
+            if let Err(e) = self.swarm.dial(opts) {
+                debug!("Failed to dial discovered peer {peer_id}: {e}");
+            }
+        }
+    }
+}
+
+/// Log a failed shutdown step, remembering it if it is the first
+fn keep_first_error(first_error: &mut Option<ChatError>, step: &str, result: Result<()>) {
+    if let Err(e) = result {
+        warn!("Failed to {} during shutdown: {}", step, e);
+        first_error.get_or_insert(e);
     }
 }
 
 /// Initialize network layer with DHT support
-pub async fn init_network_with_dht(config: NetworkConfig) -> Result<(P2pNetwork, mpsc::UnboundedReceiver<NetworkEvent>)> {
+pub async fn init_network_with_dht(config: NetworkConfig) -> Result<(P2pNetwork, EventStream)> {
     info!("Initializing network layer with DHT support");
     P2pNetwork::new(config).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libp2p::kad::store::RecordStore;
+
+    /// A network keeping everything in memory, with no bootstrap nodes
+    async fn test_network() -> P2pNetwork {
+        let key_file = std::env::temp_dir().join(format!("peer-key-{}.dat", uuid::Uuid::new_v4()));
+        let config = NetworkConfig {
+            listen_addresses: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            dht_config: DhtConfig { bootstrap_nodes: Vec::new(), ..DhtConfig::default() },
+            key_file: key_file.to_str().unwrap().to_string(),
+            outbox_file: None,
+            history_file: None,
+            peers_file: None,
+            contacts_file: None,
+            ..NetworkConfig::default()
+        };
+        let (network, _events) = init_network_with_dht(config).await.unwrap();
+        fs::remove_file(&key_file).unwrap();
+        network
+    }
+
+    #[tokio::test]
+    async fn test_room_lookups_follow_membership() {
+        let mut network = test_network().await;
+        let key = room::room_provider_key("rust");
+        let provided = |network: &mut P2pNetwork| {
+            network.swarm.behaviour_mut().kademlia.store_mut().provided().any(|record| record.key == key)
+        };
+
+        // Joining announces the room and looks up its members
+        assert!(network.join_room("rust").unwrap());
+        assert_eq!(network.room_lookups.values().collect::<Vec<_>>(), ["rust"]);
+        assert!(provided(&mut network));
+        // The default room is found through gossip alone
+        network.subscribe_to_chat().unwrap();
+        assert_eq!(network.room_lookups.len(), 1);
+
+        assert!(network.leave_room("rust").unwrap());
+        assert!(network.room_lookups.is_empty());
+        assert!(!provided(&mut network));
+    }
+
+    #[tokio::test]
+    async fn test_history_is_synced_per_topic() {
+        let mut network = test_network().await;
+        network.subscribe_to_chat().unwrap();
+        network.join_room("rust").unwrap();
+        let peer_id = PeerId::random();
+        let subscribed = |topic: &str| {
+            SwarmEvent::Behaviour(ChatBehaviourEvent::Gossipsub(gossipsub::Event::Subscribed {
+                peer_id,
+                topic: gossipsub::IdentTopic::new(topic).hash(),
+            }))
+        };
+
+        // A peer already synced on one topic is still asked for another
+        network.handle_swarm_event(subscribed("chat")).await;
+        network.handle_swarm_event(subscribed(&room::room_topic("rust"))).await;
+        network.handle_swarm_event(subscribed("chat")).await;
+        let mut requested: Vec<&str> = network.pending_syncs.values().map(|request| request.topic.as_str()).collect();
+        requested.sort();
+        assert_eq!(requested, ["chat", room::room_topic("rust").as_str()]);
+        assert_eq!(network.synced_peers.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_synced_history_never_pins() {
+        use libp2p::identity::Keypair;
+
+        let mut network = test_network().await;
+        let signed_by = |keypair: &Keypair, content: &str| {
+            let mut message = ChatMessage::new("alice".to_string(), content.to_string(), MessageType::Broadcast);
+            signing::sign_message(keypair, &mut message).unwrap();
+            message
+        };
+        let sync = |messages| {
+            let request = SyncRequest { topic: "chat".to_string(), since: 0, after: None, limit: sync::SYNC_BATCH_SIZE };
+            (request, SyncResponse { messages, has_more: false })
+        };
+        let (alice, mallory) = (Keypair::generate_ed25519(), Keypair::generate_ed25519());
+
+        // Whoever the peer lists first would otherwise claim the name
+        let (request, response) = sync(vec![signed_by(&mallory, "I'm alice"), signed_by(&alice, "hi")]);
+        network.handle_history(PeerId::random(), request, response);
+        assert_eq!(network.store.recent("chat", 10).len(), 2);
+        assert!(network.contacts.get("alice").is_none());
+
+        // but history is still checked against names pinned live
+        let alice_id = PeerId::from(alice.public()).to_string();
+        network.contacts.observe("alice", &alice_id);
+        let (request, response) = sync(vec![signed_by(&mallory, "still alice")]);
+        network.handle_history(PeerId::random(), request, response);
+        assert_eq!(network.contacts.get("alice").unwrap().peer_id, alice_id);
+        let mallory_id = PeerId::from(mallory.public()).to_string();
+        assert!(network.key_warnings.contains(&("alice".to_string(), mallory_id)));
+    }
+
+    #[tokio::test]
+    async fn test_peers_are_tracked_per_connection() {
+        use libp2p::core::{ConnectedPoint, Endpoint};
+        use libp2p::swarm::ConnectionId;
+        use std::num::NonZeroU32;
+
+        let mut network = test_network().await;
+        let mut events = network.events.subscribe();
+        let peer_id = PeerId::random();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            role_override: Endpoint::Dialer,
+        };
+        let established = |id, count| SwarmEvent::ConnectionEstablished {
+            peer_id,
+            connection_id: ConnectionId::new_unchecked(id),
+            endpoint: endpoint.clone(),
+            num_established: NonZeroU32::new(count).unwrap(),
+            concurrent_dial_errors: None,
+            established_in: Duration::ZERO,
+        };
+        let closed = |id, count| SwarmEvent::ConnectionClosed {
+            peer_id,
+            connection_id: ConnectionId::new_unchecked(id),
+            endpoint: endpoint.clone(),
+            num_established: count,
+            cause: None,
+        };
+
+        network.handle_swarm_event(established(1, 1)).await;
+        network.handle_swarm_event(established(2, 2)).await;
+        let peer = &network.connected_peers[&peer_id];
+        assert_eq!((peer.connections, peer.direction), (2, Some(ConnectionDirection::Outbound)));
+
+        // Closing one of two connections keeps the peer
+        network.handle_swarm_event(closed(1, 1)).await;
+        assert_eq!(network.connected_peers[&peer_id].connections, 1);
+        network.handle_swarm_event(closed(2, 0)).await;
+        assert!(network.connected_peers.is_empty());
+
+        // Only the first connection and the last close are reported
+        network.events.close();
+        let mut reported = Vec::new();
+        while let Some(event) = events.recv().await {
+            match event {
+                NetworkEvent::PeerConnected(_) => reported.push("connected"),
+                NetworkEvent::PeerDisconnected(_) => reported.push("disconnected"),
+                _ => {}
+            }
+        }
+        assert_eq!(reported, ["connected", "disconnected"]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Persistent queue of messages that could not be published yet
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+use crate::types::ChatMessage;
+
+/// Give up on a message after this many failed publish attempts
+pub const MAX_PUBLISH_ATTEMPTS: u32 = 5;
+/// Give up on a message that has been queued for longer than this many seconds
+pub const MAX_QUEUED_SECS: u64 = 24 * 60 * 60;
+
+/// A message waiting for peers on its topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutboxEntry {
+    pub topic: String,
+    pub message: ChatMessage,
+    pub attempts: u32,
+    pub queued_at: u64,
+}
+
+impl OutboxEntry {
+    /// Whether the entry has used up its attempts or waited too long
+    pub fn is_expired(&self, now: u64) -> bool {
+        self.attempts >= MAX_PUBLISH_ATTEMPTS || now.saturating_sub(self.queued_at) > MAX_QUEUED_SECS
+    }
+}
+
+/// Messages that failed to publish, optionally persisted to a file
+#[derive(Debug, Default)]
+pub struct Outbox {
+    path: Option<PathBuf>,
+    entries: Vec<OutboxEntry>,
+    /// Entries that expired while we were offline, not yet reported
+    expired: Vec<OutboxEntry>,
+}
+
+impl Outbox {
+    /// Open the outbox stored at `path`, or an in-memory one if `None`
+    pub fn load(path: Option<&str>) -> Result<Self> {
+        let Some(path) = path.map(PathBuf::from) else {
+            return Ok(Self::default());
+        };
+
+        let entries: Vec<OutboxEntry> = if path.exists() {
+            match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(entries) => entries,
+                // Keep the damaged file around so its messages can be recovered by hand
+                Err(e) => {
+                    let backup = PathBuf::from(format!("{}.bak", path.display()));
+                    warn!("Unreadable outbox {}, moving it to {}: {}", path.display(), backup.display(), e);
+                    fs::rename(&path, &backup)?;
+                    Vec::new()
+                }
+            }
+        } else {
+            Vec::new()
+        };
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let (expired, entries): (Vec<OutboxEntry>, Vec<OutboxEntry>) =
+            entries.into_iter().partition(|entry| entry.is_expired(now));
+        if !expired.is_empty() {
+            warn!("Dropped {} expired messages from the outbox", expired.len());
+        }
+        if !entries.is_empty() {
+            info!("Loaded {} pending messages from {}", entries.len(), path.display());
+        }
+
+        let outbox = Self { path: Some(path), entries, expired };
+        if !outbox.expired.is_empty() {
+            outbox.save();
+        }
+        Ok(outbox)
+    }
+
+    /// Queue a message for `topic`
+    pub fn push(&mut self, topic: String, message: ChatMessage, attempts: u32) {
+        let queued_at = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.entries.push(OutboxEntry { topic, message, attempts, queued_at });
+        self.save();
+    }
+
+    /// Put a previously taken entry back in the queue
+    pub fn requeue(&mut self, entry: OutboxEntry) {
+        self.entries.push(entry);
+        self.save();
+    }
+
+    /// Remove and return all entries waiting on `topic`
+    pub fn take_for_topic(&mut self, topic: &str) -> Vec<OutboxEntry> {
+        let (taken, kept) = std::mem::take(&mut self.entries)
+            .into_iter()
+            .partition(|entry| entry.topic == topic);
+        self.entries = kept;
+        if !taken.is_empty() {
+            self.save();
+        }
+        taken
+    }
+
+    /// Take the entries dropped on load for having expired, so they can be
+    /// reported as failed
+    pub fn take_expired(&mut self) -> Vec<OutboxEntry> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Pending entries in queue order
+    pub fn entries(&self) -> &[OutboxEntry] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Write the queue to disk, reporting failures to the caller
+    pub fn flush(&self) -> Result<()> {
+        match &self.path {
+            Some(path) => write_entries(path, &self.entries),
+            None => Ok(()),
+        }
+    }
+
+    /// Write the queue to disk. Failures are logged rather than returned so a
+    /// full disk never stops messages from being sent.
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = write_entries(path, &self.entries) {
+                warn!("Failed to save outbox to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn write_entries(path: &Path, entries: &[OutboxEntry]) -> Result<()> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp, serde_json::to_vec(entries)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageType;
+
+    #[test]
+    fn test_load_recovers_and_purges() {
+        let dir = std::env::temp_dir().join(format!("outbox-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("outbox.json");
+        let path_str = path.to_str().unwrap();
+
+        fs::write(&path, b"{not json").unwrap();
+        assert!(Outbox::load(Some(path_str)).unwrap().is_empty());
+        assert_eq!(fs::read(dir.join("outbox.json.bak")).unwrap(), b"{not json");
+
+        let mut outbox = Outbox::load(Some(path_str)).unwrap();
+        let message = || ChatMessage::new("alice".into(), "hi".into(), MessageType::Broadcast);
+        outbox.push("chat".into(), message(), 1);
+        outbox.push("chat".into(), message(), MAX_PUBLISH_ATTEMPTS);
+        let mut outbox = Outbox::load(Some(path_str)).unwrap();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox.entries()[0].attempts, 1);
+        assert_eq!(outbox.take_expired()[0].attempts, MAX_PUBLISH_ATTEMPTS);
+        assert!(outbox.take_expired().is_empty());
+
+        // Nothing taken, nothing written
+        fs::remove_file(&path).unwrap();
+        assert!(outbox.take_for_topic("room-rust").is_empty());
+        assert!(!path.exists());
+        assert_eq!(outbox.take_for_topic("chat").len(), 1);
+        assert!(path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
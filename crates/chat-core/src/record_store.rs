@@ -0,0 +1,283 @@
+//! Kademlia record store that can survive restarts
+//!
+//! Records and provider entries we host for other peers are kept in a
+//! [`MemoryStore`], which enforces the size limits, and written to a file
+//! when one is configured. Expiry and republishing are left to Kademlia,
+//! which drops expired entries and re-announces its own on a schedule.
+
+use anyhow::Result;
+use libp2p::kad::{
+    store::{self, MemoryStore, MemoryStoreConfig, RecordStore},
+    ProviderRecord, Record, RecordKey,
+};
+use libp2p::PeerId;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Default number of records and provider keys kept
+pub const DEFAULT_MAX_RECORDS: usize = 1024;
+
+/// Changes are written to disk at most this often; shutdown writes the rest
+const SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    value: Vec<u8>,
+    publisher: Option<String>,
+    /// Unix time the record expires at
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+    #[serde(with = "serde_bytes")]
+    key: Vec<u8>,
+    provider: String,
+    addresses: Vec<String>,
+    expires: Option<u64>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct StoredEntries {
+    records: Vec<StoredRecord>,
+    providers: Vec<StoredProvider>,
+}
+
+/// DHT records, optionally persisted to a file
+pub struct DhtStore {
+    inner: MemoryStore,
+    local_peer_id: PeerId,
+    path: Option<PathBuf>,
+    /// Keys with provider records, which `MemoryStore` can't list
+    provider_keys: HashSet<RecordKey>,
+    dirty: bool,
+    last_save: Instant,
+}
+
+impl DhtStore {
+    /// Open the store at `path`, or an in-memory one if `None`, keeping at
+    /// most `max_records` records and as many provider keys
+    pub fn open(local_peer_id: PeerId, path: Option<&str>, max_records: usize) -> Result<Self> {
+        let config = MemoryStoreConfig {
+            max_records,
+            max_provided_keys: max_records,
+            ..MemoryStoreConfig::default()
+        };
+        let mut store = Self {
+            inner: MemoryStore::with_config(local_peer_id, config),
+            local_peer_id,
+            path: path.map(PathBuf::from),
+            provider_keys: HashSet::new(),
+            dirty: false,
+            last_save: Instant::now(),
+        };
+        let Some(path) = store.path.clone() else {
+            return Ok(store);
+        };
+        if !path.exists() {
+            return Ok(store);
+        }
+
+        let entries: StoredEntries = serde_json::from_slice(&fs::read(&path)?).unwrap_or_else(|e| {
+            warn!("Ignoring unreadable DHT records file {}: {}", path.display(), e);
+            StoredEntries::default()
+        });
+        let now = unix_now();
+        let (mut records, mut providers) = (0, 0);
+        for stored in entries.records {
+            if stored.expires.is_some_and(|expires| expires <= now) {
+                continue;
+            }
+            let record = Record {
+                key: RecordKey::from(stored.key),
+                value: stored.value,
+                publisher: stored.publisher.and_then(|peer| peer.parse().ok()),
+                expires: stored.expires.map(|expires| to_instant(expires, now)),
+            };
+            if store.inner.put(record).is_ok() {
+                records += 1;
+            }
+        }
+        for stored in entries.providers {
+            let Ok(provider) = stored.provider.parse() else {
+                continue;
+            };
+            if stored.expires.is_some_and(|expires| expires <= now) {
+                continue;
+            }
+            let record = ProviderRecord {
+                key: RecordKey::from(stored.key),
+                provider,
+                expires: stored.expires.map(|expires| to_instant(expires, now)),
+                addresses: stored.addresses.iter().filter_map(|addr| addr.parse().ok()).collect(),
+            };
+            store.provider_keys.insert(record.key.clone());
+            if store.inner.add_provider(record).is_ok() {
+                providers += 1;
+            }
+        }
+        info!("Loaded {records} DHT records and {providers} provider records from {}", path.display());
+        Ok(store)
+    }
+
+    /// Write the store to disk, reporting failures to the caller
+    pub fn flush(&mut self) -> Result<()> {
+        self.dirty = false;
+        self.last_save = Instant::now();
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let now = unix_now();
+        let instant_now = Instant::now();
+        let records = self
+            .inner
+            .records()
+            .map(|record| StoredRecord {
+                key: record.key.to_vec(),
+                value: record.value.clone(),
+                publisher: record.publisher.map(|peer| peer.to_string()),
+                expires: record.expires.map(|expires| to_unix(expires, now, instant_now)),
+            })
+            .collect();
+        // Our own entries are announced again when we rejoin their rooms
+        let providers = self
+            .provider_keys
+            .iter()
+            .flat_map(|key| self.inner.providers(key))
+            .filter(|record| record.provider != self.local_peer_id)
+            .map(|record| StoredProvider {
+                key: record.key.to_vec(),
+                provider: record.provider.to_string(),
+                addresses: record.addresses.iter().map(|addr| addr.to_string()).collect(),
+                expires: record.expires.map(|expires| to_unix(expires, now, instant_now)),
+            })
+            .collect();
+        write_entries(path, &StoredEntries { records, providers })
+    }
+
+    /// Note a change, writing it out if the last save was a while ago.
+    /// Failures are logged rather than returned so the DHT keeps working.
+    fn changed(&mut self) {
+        self.dirty = true;
+        if self.path.is_some() && self.last_save.elapsed() >= SAVE_INTERVAL {
+            if let Err(e) = self.flush() {
+                warn!("Failed to save DHT records: {}", e);
+            }
+        }
+    }
+
+    /// Whether there are changes not yet on disk
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+}
+
+impl RecordStore for DhtStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, key: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.inner.get(key)
+    }
+
+    fn put(&mut self, record: Record) -> store::Result<()> {
+        self.inner.put(record)?;
+        self.changed();
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &RecordKey) {
+        self.inner.remove(key);
+        self.changed();
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.inner.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = record.key.clone();
+        self.inner.add_provider(record)?;
+        self.provider_keys.insert(key);
+        self.changed();
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.inner.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.inner.provided()
+    }
+
+    fn remove_provider(&mut self, key: &RecordKey, provider: &PeerId) {
+        self.inner.remove_provider(key, provider);
+        if self.inner.providers(key).is_empty() {
+            self.provider_keys.remove(key);
+        }
+        self.changed();
+    }
+}
+
+fn write_entries(path: &Path, entries: &StoredEntries) -> Result<()> {
+    let tmp = PathBuf::from(format!("{}.tmp", path.display()));
+    fs::write(&tmp, serde_json::to_vec(entries)?)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+fn to_instant(expires: u64, now: u64) -> Instant {
+    Instant::now() + Duration::from_secs(expires.saturating_sub(now))
+}
+
+fn to_unix(expires: Instant, now: u64, instant_now: Instant) -> u64 {
+    now + expires.saturating_duration_since(instant_now).as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_survive_reopen() {
+        let path = std::env::temp_dir().join(format!("dht-records-{}.json", uuid::Uuid::new_v4()));
+        let path_str = path.to_str().unwrap();
+        let local = PeerId::random();
+        let remote = PeerId::random();
+
+        let mut store = DhtStore::open(local, Some(path_str), DEFAULT_MAX_RECORDS).unwrap();
+        let mut record = Record::new(RecordKey::new(&"greeting"), b"hello".to_vec());
+        record.expires = Some(Instant::now() + Duration::from_secs(3600));
+        store.put(record).unwrap();
+        store.put(Record::new(RecordKey::new(&"expired"), Vec::new())).unwrap();
+        store.remove(&RecordKey::new(&"expired"));
+        let room = RecordKey::new(&"/p2p-chat/room/rust");
+        store.add_provider(ProviderRecord::new(room.clone(), remote, Vec::new())).unwrap();
+        store.add_provider(ProviderRecord::new(room.clone(), local, Vec::new())).unwrap();
+        assert!(store.is_dirty());
+        store.flush().unwrap();
+
+        let store = DhtStore::open(local, Some(path_str), DEFAULT_MAX_RECORDS).unwrap();
+        fs::remove_file(&path).unwrap();
+        let record = store.get(&RecordKey::new(&"greeting")).unwrap();
+        assert_eq!(record.value, b"hello");
+        assert!(record.expires.is_some());
+        assert!(store.get(&RecordKey::new(&"expired")).is_none());
+        let providers: Vec<PeerId> = store.providers(&room).iter().map(|record| record.provider).collect();
+        assert_eq!(providers, [remote]);
+    }
+}
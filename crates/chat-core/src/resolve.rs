@@ -0,0 +1,99 @@
+//! Turning what a user typed into a peer id
+//!
+//! UIs show peers by username and by short peer id prefixes, so those are
+//! what users type back: `alice`, `@alice` or `12D3KooWAbc`.
+
+use crate::error::{ChatError, Result};
+
+/// Length of the peer id prefixes UIs display
+pub const SHORT_PEER_ID_LEN: usize = 12;
+
+/// Resolve `query` to a peer id: a full peer id is taken as is, otherwise
+/// it must name exactly one of the `known` peers, given as
+/// `(peer_id, username)`, by username or by peer id prefix
+pub fn resolve_peer<'a>(query: &str, known: impl IntoIterator<Item = (&'a str, Option<&'a str>)>) -> Result<String> {
+    let query = query.trim().trim_start_matches('@');
+    if is_full_peer_id(query) {
+        return Ok(query.to_string());
+    }
+    if query.is_empty() {
+        return Err(ChatError::UnknownPeer(query.to_string()));
+    }
+
+    let known: Vec<(&str, Option<&str>)> = known.into_iter().collect();
+    let by_name = |exact: bool| -> Vec<(&str, Option<&str>)> {
+        known
+            .iter()
+            .filter(|(_, name)| {
+                name.is_some_and(|name| if exact { name == query } else { name.eq_ignore_ascii_case(query) })
+            })
+            .copied()
+            .collect()
+    };
+    let mut matches = by_name(true);
+    if matches.is_empty() {
+        matches = by_name(false);
+    }
+    if matches.is_empty() {
+        matches = known.iter().filter(|(peer_id, _)| peer_id.starts_with(query)).copied().collect();
+    }
+
+    match matches.as_slice() {
+        [] => Err(ChatError::UnknownPeer(query.to_string())),
+        [(peer_id, _)] => Ok(peer_id.to_string()),
+        _ => {
+            let mut candidates: Vec<String> = matches.iter().map(|(peer_id, name)| describe(peer_id, *name)).collect();
+            candidates.sort();
+            Err(ChatError::AmbiguousPeer { query: query.to_string(), candidates })
+        }
+    }
+}
+
+/// Whether `text` is a whole peer id rather than a prefix of one. Short
+/// prefixes often decode too, as ids of keys only a few bytes long.
+fn is_full_peer_id(text: &str) -> bool {
+    // The shortest real ids hold a 34 byte SHA-256 multihash of the key
+    text.parse::<libp2p::PeerId>().is_ok_and(|peer_id| peer_id.to_bytes().len() >= 34)
+}
+
+/// How a peer is listed among ambiguous candidates
+fn describe(peer_id: &str, username: Option<&str>) -> String {
+    let short = &peer_id[..SHORT_PEER_ID_LEN.min(peer_id.len())];
+    match username {
+        Some(name) => format!("{name} ({short})"),
+        None => short.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer_id() -> String {
+        libp2p::identity::Keypair::generate_ed25519().public().to_peer_id().to_string()
+    }
+
+    #[test]
+    fn test_resolve_names_and_prefixes() {
+        let alice = peer_id();
+        let bob = peer_id();
+        let known = || [(alice.as_str(), Some("alice")), (bob.as_str(), Some("bob"))];
+
+        assert_eq!(resolve_peer("alice", known()).unwrap(), alice);
+        assert_eq!(resolve_peer("@Bob", known()).unwrap(), bob);
+        assert_eq!(resolve_peer(&bob[..SHORT_PEER_ID_LEN], known()).unwrap(), bob);
+        // Unknown but well-formed ids go through untouched
+        let stranger = peer_id();
+        assert_eq!(resolve_peer(&stranger, known()).unwrap(), stranger);
+
+        assert!(matches!(resolve_peer("carol", known()), Err(ChatError::UnknownPeer(_))));
+        // Every peer id shares the 12D3KooW prefix
+        match resolve_peer("12D3KooW", known()) {
+            Err(ChatError::AmbiguousPeer { candidates, .. }) => {
+                assert_eq!(candidates.len(), 2);
+                assert!(candidates[0].starts_with("alice ("));
+            }
+            other => panic!("expected an ambiguous match, got {other:?}"),
+        }
+    }
+}
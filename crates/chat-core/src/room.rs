@@ -0,0 +1,66 @@
+//! Chat rooms and their gossipsub topics
+//!
+//! The default room is the original `"chat"` topic so older peers keep
+//! seeing broadcasts; every other room lives on its own `room-<name>` topic.
+//! Members of a room also provide its [`room_provider_key`] in the DHT, so
+//! peers joining later can find them.
+
+use crate::error::{ChatError, Result};
+
+/// Room every peer joins at startup
+pub const DEFAULT_ROOM: &str = "chat";
+
+const ROOM_TOPIC_PREFIX: &str = "room-";
+pub const MAX_ROOM_NAME_LEN: usize = 64;
+
+/// Gossipsub topic carrying a room's messages
+pub fn room_topic(room: &str) -> String {
+    if room == DEFAULT_ROOM {
+        DEFAULT_ROOM.to_string()
+    } else {
+        format!("{ROOM_TOPIC_PREFIX}{room}")
+    }
+}
+
+/// Room carried on a topic, if the topic belongs to a room
+pub fn room_of_topic(topic: &str) -> Option<&str> {
+    if topic == DEFAULT_ROOM {
+        Some(DEFAULT_ROOM)
+    } else {
+        topic.strip_prefix(ROOM_TOPIC_PREFIX)
+    }
+}
+
+/// DHT key the members of a room provide
+pub fn room_provider_key(room: &str) -> libp2p::kad::RecordKey {
+    libp2p::kad::RecordKey::new(&format!("/p2p-chat/room/{room}"))
+}
+
+/// Check that a room name is usable: short, and limited to letters, digits, `-` and `_`
+pub fn validate_room_name(room: &str) -> Result<()> {
+    let valid_chars = room.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if room.is_empty() || room.len() > MAX_ROOM_NAME_LEN || !valid_chars {
+        return Err(ChatError::InvalidRoom(room.to_string()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_topics() {
+        assert_eq!(room_topic(DEFAULT_ROOM), "chat");
+        assert_eq!(room_topic("rust"), "room-rust");
+        assert_eq!(room_of_topic("room-rust"), Some("rust"));
+        assert_eq!(room_of_topic("chat"), Some(DEFAULT_ROOM));
+        assert_eq!(room_of_topic("direct-12D3KooW"), None);
+        assert_eq!(room_provider_key("rust"), room_provider_key("rust"));
+        assert_ne!(room_provider_key("rust"), room_provider_key("go"));
+
+        assert!(validate_room_name("ci-alerts_2").is_ok());
+        assert!(validate_room_name("").is_err());
+        assert!(matches!(validate_room_name("has space"), Err(crate::ChatError::InvalidRoom(_))));
+    }
+}
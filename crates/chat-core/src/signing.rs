@@ -0,0 +1,118 @@
+//! Per-message signatures so messages stay verifiable when relayed by other peers
+//!
+//! Gossipsub signs each transmission, but messages handed over during history
+//! sync arrive from a peer other than their author. Each message therefore
+//! carries its author's peer id and an Ed25519 signature over its content.
+//!
+//! The signature covers the JSON the author serialized, which travels along
+//! in `signed_body`: peers on other builds know different fields, so
+//! re-serializing a decoded message would not reproduce the signed bytes.
+
+use anyhow::{anyhow, Result};
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+
+use crate::types::ChatMessage;
+
+/// Multihash code for peer ids that embed the public key directly
+const IDENTITY_MULTIHASH_CODE: u64 = 0x00;
+
+/// The message without its signature
+fn unsigned(message: &ChatMessage) -> ChatMessage {
+    let mut unsigned = message.clone();
+    unsigned.signature = None;
+    unsigned.signed_body = None;
+    unsigned
+}
+
+/// Stamp a message with our peer id and sign it
+pub fn sign_message(keypair: &Keypair, message: &mut ChatMessage) -> Result<()> {
+    message.sender_peer_id = Some(PeerId::from(keypair.public()).to_string());
+    let bytes = serde_json::to_vec(&unsigned(message))?;
+    let signature = keypair
+        .sign(&bytes)
+        .map_err(|e| anyhow!("Failed to sign message: {e}"))?;
+    message.signature = Some(signature);
+    message.signed_body = Some(bytes);
+    Ok(())
+}
+
+/// Recover the public key embedded in an Ed25519 peer id
+pub fn public_key_of(peer_id: &PeerId) -> Option<PublicKey> {
+    let multihash = peer_id.as_ref();
+    if multihash.code() != IDENTITY_MULTIHASH_CODE {
+        return None;
+    }
+    PublicKey::try_decode_protobuf(multihash.digest()).ok()
+}
+
+/// Check that a message was signed by the peer it claims to come from
+pub fn verify_message(message: &ChatMessage) -> bool {
+    let (Some(peer_id), Some(signature)) = (&message.sender_peer_id, &message.signature) else {
+        return false;
+    };
+    let Some(public_key) = peer_id.parse().ok().as_ref().and_then(public_key_of) else {
+        return false;
+    };
+    let Some(body) = &message.signed_body else {
+        // Peers predating `signed_body` signed the message as they encoded it
+        return serde_json::to_vec(&unsigned(message)).is_ok_and(|bytes| public_key.verify(&bytes, signature));
+    };
+    if !public_key.verify(body, signature) {
+        return false;
+    }
+    // The fields we act on must be the ones that were signed. Both sides are
+    // decoded by this build, so unknown fields and defaults drop out alike.
+    let Ok(signed) = serde_json::from_slice::<ChatMessage>(body) else {
+        return false;
+    };
+    match (serde_json::to_value(&signed), serde_json::to_value(unsigned(message))) {
+        (Ok(signed), Ok(received)) => signed == received,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageType;
+
+    #[test]
+    fn test_signature_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let mut message = ChatMessage::new("alice".to_string(), "hi".to_string(), MessageType::Broadcast);
+        sign_message(&keypair, &mut message).unwrap();
+        assert!(verify_message(&message));
+
+        let mut tampered = message.clone();
+        tampered.content = "bye".to_string();
+        assert!(!verify_message(&tampered));
+
+        let mut impostor = message;
+        impostor.sender_peer_id = Some(PeerId::from(Keypair::generate_ed25519().public()).to_string());
+        assert!(!verify_message(&impostor));
+    }
+
+    #[test]
+    fn test_verifies_fields_from_newer_peers() {
+        // A newer build signs a field this one doesn't have
+        let keypair = Keypair::generate_ed25519();
+        let mut body = serde_json::to_value(ChatMessage::new("alice".into(), "hi".into(), MessageType::Broadcast)).unwrap();
+        body["sender_peer_id"] = serde_json::json!(PeerId::from(keypair.public()).to_string());
+        body["edited_at"] = serde_json::json!(12);
+        let signed_body = serde_json::to_vec(&body).unwrap();
+        let signature = keypair.sign(&signed_body).unwrap();
+
+        let mut sent = body.clone();
+        sent["signature"] = serde_json::to_value(&signature).unwrap();
+        sent["signed_body"] = serde_json::to_value(&signed_body).unwrap();
+        let received: ChatMessage = serde_json::from_value(sent).unwrap();
+        assert!(verify_message(&received));
+
+        let mut tampered = received;
+        tampered.content = "bye".to_string();
+        assert!(!verify_message(&tampered));
+    }
+}
@@ -0,0 +1,157 @@
+//! Local message storage backing history sync
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+use crate::clock::causal_order;
+use crate::types::ChatMessage;
+
+/// A stored message together with the topic it was seen on
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StoredMessage {
+    topic: String,
+    message: ChatMessage,
+}
+
+/// Position in a topic's history, used to page through sync batches
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryCursor {
+    pub timestamp: u64,
+    pub id: String,
+}
+
+impl HistoryCursor {
+    pub fn of(message: &ChatMessage) -> Self {
+        Self { timestamp: message.timestamp, id: message.id.clone() }
+    }
+
+    fn key(&self) -> (u64, &str) {
+        (self.timestamp, &self.id)
+    }
+}
+
+/// Messages seen on each topic, deduplicated by id and appended to a
+/// JSON-lines file when one is configured
+#[derive(Debug, Default)]
+pub struct MessageStore {
+    path: Option<PathBuf>,
+    messages: Vec<StoredMessage>,
+    /// Position of each message in `messages`, by id
+    ids: HashMap<String, usize>,
+}
+
+impl MessageStore {
+    /// Open the store at `path`, or an in-memory one if `None`
+    pub fn open(path: Option<&str>) -> Result<Self> {
+        let mut store = Self { path: path.map(PathBuf::from), ..Self::default() };
+        let Some(path) = &store.path else {
+            return Ok(store);
+        };
+        if !path.exists() {
+            return Ok(store);
+        }
+
+        let mut messages = Vec::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            match serde_json::from_str::<StoredMessage>(&line?) {
+                Ok(stored) => messages.push(stored),
+                // A torn write at the end of the file shouldn't lose the rest
+                Err(e) => warn!("Skipping unreadable history entry: {e}"),
+            }
+        }
+        info!("Loaded {} messages from {}", messages.len(), path.display());
+
+        for stored in messages {
+            if !store.ids.contains_key(&stored.message.id) {
+                store.ids.insert(stored.message.id.clone(), store.messages.len());
+                store.messages.push(stored);
+            }
+        }
+        Ok(store)
+    }
+
+    /// Store a message, returning `false` if it was already known
+    pub fn insert(&mut self, topic: &str, message: &ChatMessage) -> bool {
+        if self.ids.contains_key(&message.id) {
+            return false;
+        }
+        self.ids.insert(message.id.clone(), self.messages.len());
+
+        let stored = StoredMessage { topic: topic.to_string(), message: message.clone() };
+        if let Some(path) = &self.path {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", serde_json::to_string(&stored)?));
+            if let Err(e) = result {
+                warn!("Failed to append to history file {}: {}", path.display(), e);
+            }
+        }
+        self.messages.push(stored);
+        true
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    /// Look up a stored message by id
+    pub fn get(&self, id: &str) -> Option<&ChatMessage> {
+        self.ids.get(id).map(|&index| &self.messages[index].message)
+    }
+
+    /// Messages on `topic` sent at or after `since`, oldest first, starting
+    /// after `cursor` and returning at most `limit` entries
+    pub fn since(&self, topic: &str, since: u64, cursor: Option<&HistoryCursor>, limit: usize) -> Vec<ChatMessage> {
+        let mut messages: Vec<&ChatMessage> = self
+            .messages
+            .iter()
+            .filter(|stored| stored.topic == topic && stored.message.timestamp >= since)
+            .map(|stored| &stored.message)
+            .filter(|message| cursor.is_none_or(|c| (message.timestamp, message.id.as_str()) > c.key()))
+            .collect();
+        messages.sort_by(|a, b| (a.timestamp, &a.id).cmp(&(b.timestamp, &b.id)));
+        messages.into_iter().take(limit).cloned().collect()
+    }
+
+    /// The newest `limit` messages on `topic`, in causal order
+    pub fn recent(&self, topic: &str, limit: usize) -> Vec<ChatMessage> {
+        let mut messages: Vec<&ChatMessage> = self
+            .messages
+            .iter()
+            .filter(|stored| stored.topic == topic)
+            .map(|stored| &stored.message)
+            .collect();
+        messages.sort_by(|a, b| causal_order(a, b));
+        let skip = messages.len().saturating_sub(limit);
+        messages.into_iter().skip(skip).cloned().collect()
+    }
+
+    /// Timestamp of the newest message stored for `topic`
+    pub fn latest_timestamp(&self, topic: &str) -> Option<u64> {
+        self.messages
+            .iter()
+            .filter(|stored| stored.topic == topic)
+            .map(|stored| stored.message.timestamp)
+            .max()
+    }
+
+    /// Highest Lamport time of any stored message
+    pub fn max_lamport(&self) -> u64 {
+        self.messages.iter().map(|stored| stored.message.lamport).max().unwrap_or(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+}
@@ -0,0 +1,42 @@
+//! History sync protocol between room members
+//!
+//! When a peer shows up on a topic we ask it for messages newer than the
+//! latest one we have (bounded by the configured backfill window) and page
+//! through its answers in batches. Every synced message must carry a valid
+//! author signature before it is merged into local storage.
+
+use serde::{Deserialize, Serialize};
+
+use crate::storage::HistoryCursor;
+use crate::types::ChatMessage;
+
+/// Protocol name advertised for history sync
+pub const SYNC_PROTOCOL: &str = "/p2p-chat/sync/1.0.0";
+
+/// Most messages returned in one batch
+pub const SYNC_BATCH_SIZE: usize = 100;
+
+/// Ask a peer for the history of a topic
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub topic: String,
+    /// Only messages sent at or after this Unix timestamp
+    pub since: u64,
+    /// Resume after this message when paging
+    pub after: Option<HistoryCursor>,
+    pub limit: usize,
+}
+
+/// One batch of history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub messages: Vec<ChatMessage>,
+    /// Whether another request would return more messages
+    pub has_more: bool,
+}
+
+/// Whether history for a topic may be handed to other peers.
+/// Direct message topics are private to the two participants.
+pub fn is_shareable_topic(topic: &str) -> bool {
+    !topic.starts_with("direct-")
+}
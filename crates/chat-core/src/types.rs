@@ -2,6 +2,10 @@
 
 use libp2p::Multiaddr;
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::mentions::{parse_mentions, Mention};
+use crate::room::DEFAULT_ROOM;
 
 /// A chat message
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -10,7 +14,72 @@ pub struct ChatMessage {
     pub sender: String,
     pub content: String,
     pub timestamp: u64,
+    /// Lamport time assigned by the sender; 0 from peers without logical clocks
+    #[serde(default)]
+    pub lamport: u64,
     pub message_type: MessageType,
+    /// Room the message was sent to; `None` is the default room
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    /// Id of the message this one replies to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<String>,
+    /// Users mentioned in the content
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+    /// Peer id of the author, set when the message is signed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_peer_id: Option<String>,
+    /// Author's signature over `signed_body`, or over the rest of the
+    /// message for peers that don't send one
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub signature: Option<Vec<u8>>,
+    /// The message exactly as its author signed it, since re-encoding it
+    /// here would lose fields this build doesn't know
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "serde_bytes")]
+    pub signed_body: Option<Vec<u8>>,
+}
+
+impl ChatMessage {
+    /// Create a new message with a fresh id and the current timestamp
+    pub fn new(sender: String, content: String, message_type: MessageType) -> Self {
+        let mentions = parse_mentions(&content);
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            sender,
+            content,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            lamport: 0,
+            message_type,
+            room: None,
+            reply_to: None,
+            mentions,
+            sender_peer_id: None,
+            signature: None,
+            signed_body: None,
+        }
+    }
+
+    /// Whether the given user is mentioned in this message's content.
+    ///
+    /// The content is parsed again rather than trusting `mentions`, which a
+    /// sender could fill with users the text never names.
+    pub fn mentions_user(&self, username: &str, peer_id: &str) -> bool {
+        parse_mentions(&self.content).iter().any(|m| m.matches(username, peer_id))
+    }
+
+    /// Room the message was sent to
+    pub fn room_name(&self) -> &str {
+        self.room.as_deref().unwrap_or(DEFAULT_ROOM)
+    }
+
+    /// Whether this message is a reaction rather than a regular message
+    pub fn is_reaction(&self) -> bool {
+        matches!(self.message_type, MessageType::Reaction { .. })
+    }
 }
 
 /// Types of messages
@@ -20,6 +89,8 @@ pub enum MessageType {
     Broadcast,
     /// Direct message to specific peer
     Direct { target_peer_id: String },
+    /// Emoji reaction to another message
+    Reaction { target_id: String, emoji: String },
 }
 
 /// User information
@@ -35,6 +106,14 @@ pub struct User {
 pub struct DhtConfig {
     pub bootstrap_nodes: Vec<Multiaddr>,
     pub local_port: u16,
+    /// Time between bootstrap rounds that keep the routing table fresh
+    pub bootstrap_interval: Duration,
+    /// Time between random walks looking for more peers
+    pub discovery_interval: Duration,
+    /// Connected peers after which the random walk pauses
+    pub target_peers: usize,
+    /// DHT records, and provider keys, hosted at most
+    pub max_records: usize,
 }
 
 impl Default for DhtConfig {
@@ -47,25 +126,115 @@ impl Default for DhtConfig {
         Self {
             bootstrap_nodes: vec![bootstrap_addr],
             local_port: 0, // Let OS choose
+            bootstrap_interval: crate::bootstrap::DEFAULT_BOOTSTRAP_INTERVAL,
+            discovery_interval: crate::discovery::DEFAULT_DISCOVERY_INTERVAL,
+            target_peers: crate::discovery::DEFAULT_TARGET_PEERS,
+            max_records: crate::record_store::DEFAULT_MAX_RECORDS,
         }
     }
 }
 
-/// Peer information for DHT
+/// Who opened a connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConnectionDirection {
+    /// We dialed the peer
+    Outbound,
+    /// The peer dialed us
+    Inbound,
+}
+
+/// A connected peer and what we have learned about it
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PeerInfo {
     pub peer_id: String,
+    /// Addresses the peer listens on, from identify
     pub addresses: Vec<String>,
     pub last_seen: u64,
+    /// Open connections to the peer
+    #[serde(default)]
+    pub connections: u32,
+    /// Direction of the first connection
+    #[serde(default)]
+    pub direction: Option<ConnectionDirection>,
+    #[serde(default)]
+    pub agent_version: Option<String>,
+    #[serde(default)]
+    pub protocol_version: Option<String>,
+    /// Protocols the peer supports
+    #[serde(default)]
+    pub protocols: Vec<String>,
+    /// Our own address as the peer sees it
+    #[serde(default)]
+    pub observed_addr: Option<String>,
+    /// Round-trip time of the last ping, in milliseconds
+    #[serde(default)]
+    pub rtt_ms: Option<u64>,
+    /// Name the peer last signed a message with
+    #[serde(default)]
+    pub username: Option<String>,
+}
+
+impl PeerInfo {
+    pub fn new(peer_id: String) -> Self {
+        Self {
+            peer_id,
+            addresses: Vec::new(),
+            last_seen: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            connections: 0,
+            direction: None,
+            agent_version: None,
+            protocol_version: None,
+            protocols: Vec::new(),
+            observed_addr: None,
+            rtt_ms: None,
+            username: None,
+        }
+    }
 }
 
 /// Network events that can occur
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
 pub enum NetworkEvent {
     PeerDiscovered(PeerInfo),
     PeerConnected(String),
     PeerDisconnected(String),
-    MessageReceived(ChatMessage),
+    MessageReceived {
+        message: ChatMessage,
+        mentions_me: bool,
+        /// Seconds the sender's clock is off from ours, when suspiciously large
+        clock_skew: Option<i64>,
+    },
+    MessageSent(ChatMessage),
+    /// No peers were subscribed, the message waits in the outbox
+    MessageQueued(ChatMessage),
+    /// The message could not be published and was dropped
+    MessageFailed { message: ChatMessage, error: String },
+    /// A peer joined a room we are in
+    RoomPeerJoined { room: String, peer_id: String },
+    /// A peer left a room we are in
+    RoomPeerLeft { room: String, peer_id: String },
+    /// Earlier messages received from a room member, in causal order
+    HistorySynced { peer_id: String, messages: Vec<ChatMessage> },
+    /// The first DHT bootstrap round since startup succeeded
     DhtBootstrapped,
+    /// A bootstrap round failed and will be retried after `retry_in_secs`
+    DhtBootstrapFailed { error: String, retry_in_secs: u64 },
+    /// Bootstrap succeeded again after `failures` failed rounds
+    DhtBootstrapRecovered { failures: u32 },
     PeerListUpdated(Vec<PeerInfo>),
+    /// A username was seen for the first time and pinned to its key
+    ContactPinned { username: String, peer_id: String },
+    /// A message was signed under a username pinned to another key,
+    /// which may be someone impersonating the contact
+    ContactKeyChanged { username: String, pinned_peer_id: String, peer_id: String, verified: bool },
+    /// This subscriber fell behind and missed `count` presence updates
+    EventsDropped { count: u64 },
+    /// The network has shut down, with the error that stopped it if any;
+    /// this is the last event on every stream
+    Stopped { error: Option<String> },
 }
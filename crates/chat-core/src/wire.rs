@@ -0,0 +1,300 @@
+//! Versioned wire format for messages sent over gossipsub
+//!
+//! Every payload starts with a one-byte frame header followed by an envelope
+//! carrying the protocol version, the message kind and the message body.
+//! Flags in the header describe how the envelope is encoded and whether it is
+//! zstd-compressed; peers skip frames with flags they don't know.
+//! Peers that predate the envelope send a bare JSON `ChatMessage`, which is
+//! recognised by its leading `{` and still accepted. It is also what this
+//! build sends by default, since those peers can't read envelopes; the
+//! envelope formats are opt-in until they have upgraded.
+
+use anyhow::{anyhow, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::debug;
+
+use crate::types::ChatMessage;
+
+/// Version of the envelope layout written by this build
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// Upper bits of the frame header. Never `{`, so legacy JSON stays distinguishable
+const FRAME_MARKER: u8 = 0xC0;
+const FRAME_MARKER_MASK: u8 = 0xF0;
+/// Envelope is JSON instead of CBOR
+const FLAG_JSON: u8 = 0x01;
+/// Envelope is zstd-compressed
+const FLAG_ZSTD: u8 = 0x02;
+const KNOWN_FLAGS: u8 = FLAG_JSON | FLAG_ZSTD;
+
+const ZSTD_LEVEL: i32 = 3;
+/// How much larger than the transmitted frame a decompressed envelope may be
+const MAX_EXPANSION: usize = 16;
+
+/// Default size above which envelopes are compressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+/// Default upper bound on a transmitted frame
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Encoding used for outgoing messages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// Bare JSON `ChatMessage` without an envelope or compression, which
+    /// peers predating the envelope can read
+    #[default]
+    Legacy,
+    /// Envelope in compact binary encoding
+    Cbor,
+    /// Envelope in human-readable encoding, useful when debugging
+    Json,
+}
+
+/// Kind of payload carried in an envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Chat,
+    /// A kind introduced by a newer peer
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    #[serde(rename = "v")]
+    version: u16,
+    #[serde(rename = "k")]
+    kind: MessageKind,
+    #[serde(rename = "b")]
+    body: T,
+}
+
+/// Encoder and decoder for wire frames
+#[derive(Debug, Clone, Copy)]
+pub struct Codec {
+    pub format: WireFormat,
+    /// Compress envelopes larger than this many bytes; `None` disables compression
+    pub compression_threshold: Option<usize>,
+    /// Largest frame we send or accept
+    pub max_message_size: usize,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Self {
+            format: WireFormat::default(),
+            compression_threshold: Some(DEFAULT_COMPRESSION_THRESHOLD),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+}
+
+impl Codec {
+    /// Encode a chat message for the wire
+    pub fn encode(&self, message: &ChatMessage) -> Result<Vec<u8>> {
+        if self.format == WireFormat::Legacy {
+            let data = serde_json::to_vec(message)?;
+            self.check_size(data.len())?;
+            return Ok(data);
+        }
+
+        let envelope = Envelope { version: PROTOCOL_VERSION, kind: MessageKind::Chat, body: message };
+        let (mut flags, mut body) = match self.format {
+            WireFormat::Legacy => unreachable!("legacy messages have no envelope"),
+            WireFormat::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(&envelope, &mut body)?;
+                (0, body)
+            }
+            WireFormat::Json => (FLAG_JSON, serde_json::to_vec(&envelope)?),
+        };
+
+        if self.compression_threshold.is_some_and(|threshold| body.len() > threshold) {
+            let compressed = zstd::bulk::compress(&body, ZSTD_LEVEL)?;
+            // Incompressible content isn't worth the receiver's effort
+            if compressed.len() < body.len() {
+                flags |= FLAG_ZSTD;
+                body = compressed;
+            }
+        }
+
+        self.check_size(body.len() + 1)?;
+
+        let mut data = Vec::with_capacity(body.len() + 1);
+        data.push(FRAME_MARKER | flags);
+        data.extend_from_slice(&body);
+        Ok(data)
+    }
+
+    fn check_size(&self, size: usize) -> Result<()> {
+        if size > self.max_message_size {
+            return Err(anyhow!("Message is {size} bytes, larger than the {} byte limit", self.max_message_size));
+        }
+        Ok(())
+    }
+
+    /// Decode a chat message from the wire.
+    ///
+    /// Returns `Ok(None)` for well-formed payloads this build doesn't understand,
+    /// such as unknown kinds from newer peers.
+    pub fn decode(&self, data: &[u8]) -> Result<Option<ChatMessage>> {
+        let Some((&header, body)) = data.split_first() else {
+            return Err(anyhow!("Empty payload"));
+        };
+
+        if header == b'{' {
+            return Ok(Some(serde_json::from_slice(data)?));
+        }
+        if header & FRAME_MARKER_MASK != FRAME_MARKER {
+            return Err(anyhow!("Unrecognised frame header {header:#04x}"));
+        }
+        let flags = header & !FRAME_MARKER_MASK;
+        if flags & !KNOWN_FLAGS != 0 {
+            debug!("Ignoring payload with unsupported frame flags {flags:#04x}");
+            return Ok(None);
+        }
+
+        let decompressed;
+        let body = if flags & FLAG_ZSTD != 0 {
+            decompressed = decompress(body, self.max_message_size * MAX_EXPANSION)?;
+            &decompressed[..]
+        } else {
+            body
+        };
+
+        if flags & FLAG_JSON != 0 {
+            let envelope: Envelope<serde_json::Value> = serde_json::from_slice(body)?;
+            open_envelope(envelope, |value| Ok(serde_json::from_value(value)?))
+        } else {
+            let envelope: Envelope<ciborium::Value> = ciborium::from_reader(body)?;
+            open_envelope(envelope, |value| Ok(value.deserialized()?))
+        }
+    }
+}
+
+/// Decompress a zstd frame of at most `limit` bytes. Streams the output
+/// rather than trusting the frame header, so a small frame can't make us
+/// allocate the whole limit up front.
+fn decompress(body: &[u8], limit: usize) -> Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut decompressed = Vec::new();
+    zstd::Decoder::new(body)?.take(limit as u64 + 1).read_to_end(&mut decompressed)?;
+    if decompressed.len() > limit {
+        return Err(anyhow!("Decompressed envelope exceeds {limit} bytes"));
+    }
+    Ok(decompressed)
+}
+
+fn open_envelope<V, T: DeserializeOwned>(
+    envelope: Envelope<V>,
+    deserialize: impl FnOnce(V) -> Result<T>,
+) -> Result<Option<T>> {
+    if envelope.kind == MessageKind::Unknown {
+        debug!("Ignoring unknown message kind from protocol version {}", envelope.version);
+        return Ok(None);
+    }
+
+    match deserialize(envelope.body) {
+        Ok(body) => Ok(Some(body)),
+        // Newer peers may change a body in ways we can't read; skip rather than fail
+        Err(e) if envelope.version > PROTOCOL_VERSION => {
+            debug!("Ignoring unreadable body from protocol version {}: {e}", envelope.version);
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::MessageType;
+
+    fn message() -> ChatMessage {
+        ChatMessage::new("alice".to_string(), "hello @bob".to_string(), MessageType::Broadcast)
+    }
+
+    fn codec(format: WireFormat) -> Codec {
+        Codec { format, ..Codec::default() }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let original = message();
+        for format in [WireFormat::Legacy, WireFormat::Cbor, WireFormat::Json] {
+            let data = codec(format).encode(&original).unwrap();
+            let decoded = codec(format).decode(&data).unwrap().unwrap();
+            assert_eq!(decoded.id, original.id);
+            assert_eq!(decoded.mentions, original.mentions);
+        }
+    }
+
+    #[test]
+    fn test_cbor_is_smaller_than_legacy_json() {
+        let original = message();
+        let cbor = codec(WireFormat::Cbor).encode(&original).unwrap();
+        let legacy = serde_json::to_vec(&original).unwrap();
+        assert!(cbor.len() < legacy.len());
+    }
+
+    #[test]
+    fn test_accepts_legacy_json() {
+        let original = message();
+        let data = serde_json::to_vec(&original).unwrap();
+        assert_eq!(codec(WireFormat::Cbor).decode(&data).unwrap().unwrap().id, original.id);
+        // Sent by default, so peers that predate the envelope can read it
+        let sent = Codec::default().encode(&original).unwrap();
+        assert_eq!(serde_json::from_slice::<ChatMessage>(&sent).unwrap().id, original.id);
+    }
+
+    #[test]
+    fn test_compresses_large_messages() {
+        let mut original = message();
+        original.content = "fn main() { println!(\"hello\"); }\n".repeat(200);
+
+        let data = codec(WireFormat::Cbor).encode(&original).unwrap();
+        assert_ne!(data[0] & FLAG_ZSTD, 0);
+        assert!(data.len() < original.content.len() / 4);
+        assert_eq!(Codec::default().decode(&data).unwrap().unwrap().content, original.content);
+
+        let uncompressed = Codec { compression_threshold: None, ..codec(WireFormat::Cbor) };
+        assert_eq!(uncompressed.encode(&original).unwrap()[0] & FLAG_ZSTD, 0);
+
+        let small = message();
+        assert_eq!(codec(WireFormat::Cbor).encode(&small).unwrap()[0] & FLAG_ZSTD, 0);
+    }
+
+    #[test]
+    fn test_rejects_oversized_messages() {
+        let mut original = message();
+        original.content = "x".repeat(4096);
+        let codec = Codec { compression_threshold: None, max_message_size: 1024, ..Codec::default() };
+        assert!(codec.encode(&original).is_err());
+    }
+
+    #[test]
+    fn test_rejects_decompression_bombs() {
+        let codec = Codec { max_message_size: 1024, ..Codec::default() };
+        let bomb = zstd::bulk::compress(&vec![0; 1024 * MAX_EXPANSION + 1], ZSTD_LEVEL).unwrap();
+        let mut data = vec![FRAME_MARKER | FLAG_ZSTD];
+        data.extend_from_slice(&bomb);
+        assert!(data.len() < 1024);
+        assert!(codec.decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_tolerates_newer_peers() {
+        let mut data = vec![FRAME_MARKER | FLAG_JSON];
+        data.extend_from_slice(br#"{"v":7,"k":"file_offer","b":{"name":"a.txt"}}"#);
+        assert!(Codec::default().decode(&data).unwrap().is_none());
+
+        let mut body = serde_json::to_value(message()).unwrap();
+        body["edited_at"] = serde_json::json!(12);
+        let envelope = serde_json::json!({ "v": 2, "k": "chat", "b": body, "extra": true });
+        let mut data = vec![FRAME_MARKER | FLAG_JSON];
+        data.extend_from_slice(&serde_json::to_vec(&envelope).unwrap());
+        assert!(Codec::default().decode(&data).unwrap().is_some());
+    }
+}
@@ -0,0 +1,291 @@
+//! HTTP and WebSocket gateway for browser front-ends
+//!
+//! REST endpoints under `/api` send messages and query peers and history;
+//! `/api/events` upgrades to a WebSocket that streams every network event
+//! as a JSON text frame.
+//!
+//! Every request must carry the gateway's token, as an `Authorization:
+//! Bearer` header or, for WebSockets which browsers can't add headers to,
+//! a `token` query parameter. Requests from pages served elsewhere are
+//! refused by their `Origin`, so other sites can't read the chat, unless
+//! the origin was allowed at startup; those pages get CORS headers and
+//! preflight answers so they can send the token.
+
+use anyhow::Result;
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, Request, State,
+    },
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use chat_core::{ChatClient, ChatError, EventStream};
+use serde::Deserialize;
+use std::net::SocketAddr;
+use tracing::{info, warn};
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+
+#[derive(Clone)]
+struct GatewayState {
+    client: ChatClient,
+}
+
+/// What requests must present to be served
+#[derive(Clone)]
+struct Access {
+    token: String,
+    /// Origins of pages allowed to use the gateway: its own, and those
+    /// allowed at startup
+    origins: Vec<String>,
+}
+
+/// Random token for a gateway started without one
+pub fn generate_token() -> String {
+    rand::random::<[u8; 32]>().iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Origins a browser reports for pages loaded from `addr`
+fn own_origins(addr: SocketAddr) -> Vec<String> {
+    let mut origins = vec![format!("http://{addr}")];
+    if addr.ip().is_loopback() {
+        origins.push(format!("http://localhost:{}", addr.port()));
+    }
+    origins
+}
+
+/// Compare tokens without leaking how much of a guess was right
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Let a page from `origin` read the response
+fn allow_origin(mut response: Response, origin: Option<HeaderValue>) -> Response {
+    if let Some(origin) = origin {
+        let headers = response.headers_mut();
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+    }
+    response
+}
+
+/// Refuse requests from foreign pages or without the token
+async fn authorize(State(access): State<Access>, request: Request, next: Next) -> Response {
+    // Browsers always send an Origin on cross-site and WebSocket requests
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    if let Some(origin) = &origin {
+        if !access.origins.iter().any(|allowed| origin.as_bytes() == allowed.as_bytes()) {
+            return (StatusCode::FORBIDDEN, "Requests from other sites are not allowed").into_response();
+        }
+        // Preflights carry no credentials; they ask whether the real request may
+        if request.method() == Method::OPTIONS {
+            let preflight = [
+                (header::ACCESS_CONTROL_ALLOW_METHODS, "GET, POST"),
+                (header::ACCESS_CONTROL_ALLOW_HEADERS, "authorization, content-type"),
+                (header::ACCESS_CONTROL_MAX_AGE, "600"),
+            ];
+            return allow_origin((StatusCode::NO_CONTENT, preflight).into_response(), Some(origin.clone()));
+        }
+    }
+
+    let bearer = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let query = request
+        .uri()
+        .query()
+        .into_iter()
+        .flat_map(|query| query.split('&'))
+        .find_map(|pair| pair.strip_prefix("token="));
+    let response = match bearer.or(query) {
+        Some(token) if tokens_match(token, &access.token) => next.run(request).await,
+        _ => (StatusCode::UNAUTHORIZED, "Missing or wrong gateway token").into_response(),
+    };
+    allow_origin(response, origin)
+}
+
+/// Body of `POST /api/messages`
+#[derive(Deserialize)]
+struct SendMessage {
+    message: String,
+    /// Send as a direct message to this peer instead of broadcasting
+    peer_id: Option<String>,
+    /// Id of the message being replied to
+    reply_to: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    /// Checked by [`authorize`]; listed so the query still parses
+    #[serde(rename = "token")]
+    _token: Option<String>,
+    topic: Option<String>,
+    limit: Option<usize>,
+}
+
+/// Maps client errors to a 500 with the error text as body
+struct GatewayError(ChatError);
+
+impl IntoResponse for GatewayError {
+    fn into_response(self) -> Response {
+        let status = match &self.0 {
+            error if error.is_invalid_input() => StatusCode::BAD_REQUEST,
+            ChatError::ClientClosed => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        (status, self.0.to_string()).into_response()
+    }
+}
+
+impl From<ChatError> for GatewayError {
+    fn from(error: ChatError) -> Self {
+        Self(error)
+    }
+}
+
+async fn send_message(
+    State(state): State<GatewayState>,
+    Json(body): Json<SendMessage>,
+) -> Result<StatusCode, GatewayError> {
+    match (body.peer_id, body.reply_to) {
+        // Replies are broadcast, so they can't also be direct messages
+        (Some(_), Some(_)) => return Ok(StatusCode::UNPROCESSABLE_ENTITY),
+        (Some(peer_id), None) => state.client.send_direct(peer_id, body.message).await?,
+        (None, Some(reply_to)) => state.client.send_reply(reply_to, body.message).await?,
+        (None, None) => state.client.send_broadcast(body.message).await?,
+    }
+    // Delivery is reported asynchronously on the event stream
+    Ok(StatusCode::ACCEPTED)
+}
+
+async fn list_peers(State(state): State<GatewayState>) -> Result<impl IntoResponse, GatewayError> {
+    Ok(Json(state.client.peers().await?))
+}
+
+async fn history(
+    State(state): State<GatewayState>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<impl IntoResponse, GatewayError> {
+    let topic = query.topic.unwrap_or_else(|| "chat".to_string());
+    let limit = query.limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+    Ok(Json(state.client.history(topic, limit).await?))
+}
+
+async fn event_stream(State(state): State<GatewayState>, upgrade: WebSocketUpgrade) -> Response {
+    // A browser that stops reading must not hold up the network
+    let events = state.client.subscribe().disconnect_when_full();
+    upgrade.on_upgrade(move |socket| stream_events(socket, events))
+}
+
+/// Forward network events to a WebSocket until either side goes away
+async fn stream_events(mut socket: WebSocket, mut events: EventStream) {
+    loop {
+        tokio::select! {
+            event = events.recv() => {
+                let Some(event) = event else {
+                    if events.lagged() {
+                        let _ = socket.send(Message::Close(Some(CloseFrame {
+                            code: close_code::AGAIN,
+                            reason: "Too far behind on events".into(),
+                        }))).await;
+                    }
+                    break;
+                };
+                let Ok(text) = serde_json::to_string(&event) else { continue };
+                if socket.send(Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            incoming = socket.recv() => {
+                // The stream is one-way; only watch for the browser closing it
+                match incoming {
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Serve the gateway on `addr`, to requests presenting `token` from its own
+/// pages or those of `allowed_origins`, until the process exits
+pub async fn serve(addr: SocketAddr, client: ChatClient, token: String, allowed_origins: Vec<String>) -> Result<()> {
+    if !addr.ip().is_loopback() {
+        warn!("HTTP gateway on {addr} is reachable from other machines over plain HTTP");
+    }
+    let mut origins = own_origins(addr);
+    origins.extend(allowed_origins.into_iter().map(|origin| origin.trim_end_matches('/').to_string()));
+    let access = Access { token, origins };
+
+    let app = Router::new()
+        .route("/api/messages", post(send_message))
+        .route("/api/peers", get(list_peers))
+        .route("/api/history", get(history))
+        .route("/api/events", get(event_stream))
+        .layer(middleware::from_fn_with_state(access, authorize))
+        .with_state(GatewayState { client });
+
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    info!("HTTP gateway listening on http://{}", listener.local_addr()?);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_and_origins() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert!(tokens_match(&token, &token.clone()));
+        assert!(!tokens_match(&token[1..], &token));
+        assert!(!tokens_match(&generate_token(), &token));
+
+        let origins = own_origins("127.0.0.1:8080".parse().unwrap());
+        assert_eq!(origins, ["http://127.0.0.1:8080", "http://localhost:8080"]);
+        assert_eq!(own_origins("192.0.2.1:80".parse().unwrap()), ["http://192.0.2.1:80"]);
+    }
+
+    #[tokio::test]
+    async fn test_allowed_origins_get_cors() {
+        use axum::body::Body;
+        use tower::ServiceExt;
+
+        let access = Access {
+            token: "secret".to_string(),
+            origins: vec!["http://127.0.0.1:8080".to_string(), "http://localhost:5173".to_string()],
+        };
+        let app = Router::new()
+            .route("/api/peers", get(|| async { "[]" }))
+            .layer(middleware::from_fn_with_state(access, authorize));
+        let send = |method: Method, origin: &str, token: Option<&str>| {
+            let mut request = Request::builder().method(method).uri("/api/peers").header(header::ORIGIN, origin);
+            if let Some(token) = token {
+                request = request.header(header::AUTHORIZATION, format!("Bearer {token}"));
+            }
+            app.clone().oneshot(request.body(Body::empty()).unwrap())
+        };
+
+        // A front-end served elsewhere on localhost may ask, then call
+        let preflight = send(Method::OPTIONS, "http://localhost:5173", None).await.unwrap();
+        assert_eq!(preflight.status(), StatusCode::NO_CONTENT);
+        assert_eq!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:5173");
+        assert!(preflight.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("authorization"));
+        let response = send(Method::GET, "http://localhost:5173", Some("secret")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "http://localhost:5173");
+
+        let response = send(Method::GET, "http://localhost:5173", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = send(Method::OPTIONS, "http://evil.example", None).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}
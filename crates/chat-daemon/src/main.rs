@@ -0,0 +1,164 @@
+#[cfg(feature = "http")]
+mod http;
+mod rpc;
+
+use anyhow::{bail, Result};
+use chat_core::{config, start_chat_client, Settings};
+use clap::Parser;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tracing::{info, warn};
+
+#[derive(Parser)]
+#[command(name = "chat-daemon")]
+#[command(about = "Headless P2P chat node controlled over a JSON-RPC Unix socket")]
+struct Args {
+    /// Port to listen on (default: chosen by the OS)
+    #[arg(short, long, env = "P2P_CHAT_PORT")]
+    port: Option<u16>,
+
+    /// Your username for the chat (default: Anonymous)
+    #[arg(short, long, env = "P2P_CHAT_USERNAME")]
+    username: Option<String>,
+
+    /// Bootstrap node address; repeat for several (default: built-in bootstrap node)
+    #[arg(short, long, env = "P2P_CHAT_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Vec<String>,
+
+    /// Path to the peer keypair file (default: peer_key.dat)
+    #[arg(short, long, env = "P2P_CHAT_KEY_FILE")]
+    key_file: Option<String>,
+
+    /// Settings file (default: config.toml in the user's config directory, if present)
+    #[arg(long, env = "P2P_CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Profile from the settings file, e.g. to run a second identity
+    #[arg(long, env = "P2P_CHAT_PROFILE")]
+    profile: Option<String>,
+
+    /// Path of the control socket (default: $XDG_RUNTIME_DIR/p2p-chat.sock)
+    #[arg(short, long)]
+    socket: Option<PathBuf>,
+
+    /// Also serve the HTTP/WebSocket gateway on this address, e.g. 127.0.0.1:8080
+    #[cfg(feature = "http")]
+    #[arg(long)]
+    http: Option<std::net::SocketAddr>,
+
+    /// Token HTTP clients must present (default: a random one, logged at startup)
+    #[cfg(feature = "http")]
+    #[arg(long, env = "P2P_CHAT_HTTP_TOKEN", hide_env_values = true)]
+    http_token: Option<String>,
+
+    /// Origin of a browser front-end served elsewhere that may use the
+    /// gateway, e.g. http://localhost:5173; repeat for several
+    #[cfg(feature = "http")]
+    #[arg(long, env = "P2P_CHAT_HTTP_ALLOW_ORIGIN", value_delimiter = ',')]
+    http_allow_origin: Vec<String>,
+}
+
+/// Default control socket location, private to the current user where possible
+fn default_socket_path() -> PathBuf {
+    std::env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir)
+        .join("p2p-chat.sock")
+}
+
+/// Listen on the control socket at `path`, replacing only a socket left
+/// behind by a previous run
+fn bind_control_socket(path: &Path) -> Result<UnixListener> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("{} exists and is not a socket", path.display());
+        }
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            bail!("Another daemon is already listening on {}", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+
+    // Bind in a directory only we can enter and move the socket into place
+    // once it is private, so nobody can connect in between
+    let staging = path.with_extension(format!("{}.tmp", std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("control.sock");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        std::fs::set_permissions(&staged, std::fs::Permissions::from_mode(0o600))?;
+        std::fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&staged);
+    std::fs::remove_dir(&staging)?;
+    Ok(bound?)
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    // Built-in defaults, then the settings file, then environment and flags
+    let defaults = Settings {
+        username: Some("Anonymous".to_string()),
+        key_file: Some("peer_key.dat".to_string()),
+        ..Default::default()
+    };
+    let flags = Settings {
+        username: args.username,
+        key_file: args.key_file,
+        port: args.port,
+        bootstrap: (!args.bootstrap.is_empty()).then_some(args.bootstrap),
+        ..Default::default()
+    };
+    let settings = defaults.merge(config::load(args.config.as_deref(), args.profile.as_deref())?).merge(flags);
+    let username = settings.username.clone().unwrap_or_default();
+    let config = settings.network_config()?;
+
+    let socket_path = args.socket.unwrap_or_else(default_socket_path);
+    let listener = bind_control_socket(&socket_path)?;
+    info!("Control socket listening on {}", socket_path.display());
+
+    // Control clients subscribe to events individually
+    let (client, _) = start_chat_client(config, username).await?;
+
+    #[cfg(feature = "http")]
+    if let Some(addr) = args.http {
+        let client = client.clone();
+        let token = args.http_token.unwrap_or_else(|| {
+            let token = http::generate_token();
+            info!("HTTP gateway token: {}", token);
+            token
+        });
+        let allowed_origins = args.http_allow_origin;
+        tokio::spawn(async move {
+            if let Err(e) = http::serve(addr, client, token, allowed_origins).await {
+                warn!("HTTP gateway error: {}", e);
+            }
+        });
+    }
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let client = client.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = rpc::serve_connection(stream, client).await {
+                        warn!("Control connection error: {}", e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                break;
+            }
+        }
+    }
+
+    let _ = std::fs::remove_file(&socket_path);
+    Ok(client.shutdown().await?)
+}
@@ -0,0 +1,324 @@
+//! JSON-RPC 2.0 control API served over a Unix domain socket
+//!
+//! Each line on the socket is one JSON-RPC request or response. After a
+//! client calls `subscribe`, network events are pushed to it as `event`
+//! notifications until it calls `unsubscribe` or disconnects.
+
+use anyhow::Result;
+use chat_core::{ChatClient, ChatError};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::UnixStream;
+use std::sync::Arc;
+use tokio::sync::{mpsc, Notify};
+use tokio::task::JoinHandle;
+use tracing::{debug, warn};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+/// Lines queued for a client before it counts as stalled and is disconnected
+const OUTGOING_BUFFER: usize = 256;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    jsonrpc: String,
+    /// Absent for notifications, which get no response
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    jsonrpc: &'static str,
+    id: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+}
+
+impl Response {
+    fn new(id: Value, outcome: std::result::Result<Value, RpcError>) -> Self {
+        match outcome {
+            Ok(result) => Self { jsonrpc: "2.0", id, result: Some(result), error: None },
+            Err(error) => Self { jsonrpc: "2.0", id, result: None, error: Some(error) },
+        }
+    }
+}
+
+fn rpc_error(code: i64, message: impl Into<String>) -> RpcError {
+    RpcError { code, message: message.into() }
+}
+
+#[derive(Deserialize)]
+struct SendBroadcastParams {
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SendDirectParams {
+    peer_id: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct SendReplyParams {
+    reply_to: String,
+    message: String,
+}
+
+#[derive(Deserialize)]
+struct GetHistoryParams {
+    #[serde(default = "default_topic")]
+    topic: String,
+    #[serde(default = "default_history_limit")]
+    limit: usize,
+}
+
+fn default_topic() -> String {
+    "chat".to_string()
+}
+
+fn default_history_limit() -> usize {
+    50
+}
+
+#[derive(Deserialize)]
+struct SendReactionParams {
+    target_id: String,
+    emoji: String,
+}
+
+fn params<T: DeserializeOwned>(mut params: Value) -> std::result::Result<T, RpcError> {
+    // Methods whose parameters are all optional may be called without any
+    if params.is_null() {
+        params = json!({});
+    }
+    serde_json::from_value(params).map_err(|e| rpc_error(INVALID_PARAMS, e.to_string()))
+}
+
+fn server_error(e: ChatError) -> RpcError {
+    let code = if e.is_invalid_input() { INVALID_PARAMS } else { SERVER_ERROR };
+    rpc_error(code, e.to_string())
+}
+
+/// State of one connected control client
+struct Connection {
+    client: ChatClient,
+    outgoing: mpsc::Sender<String>,
+    subscription: Option<JoinHandle<()>>,
+    /// Signalled when the client stops reading events
+    stalled: Arc<Notify>,
+}
+
+impl Connection {
+    async fn dispatch(&mut self, method: &str, params_value: Value) -> std::result::Result<Value, RpcError> {
+        match method {
+            "send_broadcast" => {
+                let p: SendBroadcastParams = params(params_value)?;
+                self.client.send_broadcast(p.message).await.map_err(server_error)?;
+                Ok(Value::Null)
+            }
+            "send_direct" => {
+                let p: SendDirectParams = params(params_value)?;
+                self.client.send_direct(p.peer_id, p.message).await.map_err(server_error)?;
+                Ok(Value::Null)
+            }
+            "send_reply" => {
+                let p: SendReplyParams = params(params_value)?;
+                self.client.send_reply(p.reply_to, p.message).await.map_err(server_error)?;
+                Ok(Value::Null)
+            }
+            "send_reaction" => {
+                let p: SendReactionParams = params(params_value)?;
+                self.client.send_reaction(p.target_id, p.emoji).await.map_err(server_error)?;
+                Ok(Value::Null)
+            }
+            "list_peers" => {
+                let peers = self.client.peers().await.map_err(server_error)?;
+                Ok(json!(peers))
+            }
+            "get_history" => {
+                let p: GetHistoryParams = params(params_value)?;
+                let messages = self.client.history(p.topic, p.limit).await.map_err(server_error)?;
+                Ok(json!(messages))
+            }
+            "subscribe" => {
+                if self.subscription.is_none() {
+                    self.subscription = Some(self.forward_events());
+                }
+                Ok(Value::Bool(true))
+            }
+            "unsubscribe" => {
+                if let Some(subscription) = self.subscription.take() {
+                    subscription.abort();
+                }
+                Ok(Value::Bool(true))
+            }
+            _ => Err(rpc_error(METHOD_NOT_FOUND, format!("Unknown method: {method}"))),
+        }
+    }
+
+    /// Push network events to this client as `event` notifications
+    fn forward_events(&self) -> JoinHandle<()> {
+        let mut events = self.client.subscribe();
+        let outgoing = self.outgoing.clone();
+        let stalled = self.stalled.clone();
+        tokio::spawn(async move {
+            while let Some(event) = events.recv().await {
+                let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+                // Waiting for a client that doesn't read would hold up the network
+                match outgoing.try_send(notification.to_string()) {
+                    Ok(()) => {}
+                    Err(mpsc::error::TrySendError::Full(_)) => {
+                        warn!("Disconnecting control client that stopped reading events");
+                        stalled.notify_one();
+                        break;
+                    }
+                    Err(mpsc::error::TrySendError::Closed(_)) => break,
+                }
+            }
+        })
+    }
+
+    /// Handle one line from the client, returning the response line if any
+    async fn handle_line(&mut self, line: &str) -> Option<String> {
+        let request: Request = match serde_json::from_str::<Value>(line) {
+            Err(e) => return Some(error_line(Value::Null, PARSE_ERROR, e.to_string())),
+            Ok(value) => match serde_json::from_value(value) {
+                Ok(request) => request,
+                Err(e) => return Some(error_line(Value::Null, INVALID_REQUEST, e.to_string())),
+            },
+        };
+        if request.jsonrpc != "2.0" {
+            return Some(error_line(request.id.unwrap_or_default(), INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+        }
+
+        debug!("RPC call: {}", request.method);
+        let outcome = self.dispatch(&request.method, request.params).await;
+        let id = request.id?;
+        serde_json::to_string(&Response::new(id, outcome)).ok()
+    }
+}
+
+fn error_line(id: Value, code: i64, message: impl Into<String>) -> String {
+    serde_json::to_string(&Response::new(id, Err(rpc_error(code, message)))).unwrap_or_default()
+}
+
+/// Serve one control connection until the client disconnects
+pub async fn serve_connection(stream: UnixStream, client: ChatClient) -> Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let (outgoing, mut outgoing_receiver) = mpsc::channel::<String>(OUTGOING_BUFFER);
+
+    let writer_task = tokio::spawn(async move {
+        while let Some(line) = outgoing_receiver.recv().await {
+            if writer.write_all(line.as_bytes()).await.is_err() || writer.write_all(b"\n").await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let stalled = Arc::new(Notify::new());
+    let mut connection = Connection { client, outgoing, subscription: None, stalled: stalled.clone() };
+    let mut lines = BufReader::new(reader).lines();
+    let mut is_stalled = false;
+    loop {
+        let line = tokio::select! {
+            line = lines.next_line() => line?,
+            _ = stalled.notified() => {
+                is_stalled = true;
+                break;
+            }
+        };
+        let Some(line) = line else { break };
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = connection.handle_line(&line).await {
+            if connection.outgoing.send(response).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    if let Some(subscription) = connection.subscription.take() {
+        subscription.abort();
+    }
+    drop(connection);
+    // A stalled client won't take what is still queued for it
+    if is_stalled {
+        writer_task.abort();
+    }
+    let _ = writer_task.await;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{start_chat_client, DhtConfig, NetworkConfig, NetworkEvent};
+
+    fn error_code(line: &str) -> i64 {
+        serde_json::from_str::<Value>(line).unwrap()["error"]["code"].as_i64().unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_handle_line() {
+        let key_file = std::env::temp_dir().join(format!("rpc-test-{}.key", std::process::id()));
+        let config = NetworkConfig {
+            listen_addresses: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            dht_config: DhtConfig { bootstrap_nodes: Vec::new(), ..DhtConfig::default() },
+            key_file: key_file.to_str().unwrap().to_string(),
+            outbox_file: None,
+            history_file: None,
+            peers_file: None,
+            contacts_file: None,
+            ..NetworkConfig::default()
+        };
+        let (client, mut events) = start_chat_client(config, "alice".to_string()).await.unwrap();
+        let (outgoing, _outgoing_receiver) = mpsc::channel(OUTGOING_BUFFER);
+        let mut connection =
+            Connection { client: client.clone(), outgoing, subscription: None, stalled: Arc::new(Notify::new()) };
+
+        let response = connection.handle_line("{not json").await.unwrap();
+        assert_eq!(error_code(&response), PARSE_ERROR);
+        let response = connection.handle_line(r#"{"jsonrpc":"1.0","id":1,"method":"list_peers"}"#).await.unwrap();
+        assert_eq!(error_code(&response), INVALID_REQUEST);
+        let response = connection.handle_line(r#"{"jsonrpc":"2.0","id":2,"method":"fly"}"#).await.unwrap();
+        assert_eq!(error_code(&response), METHOD_NOT_FOUND);
+
+        // A notification is carried out but not answered
+        let notification = r#"{"jsonrpc":"2.0","method":"send_broadcast","params":{"message":"quiet"}}"#;
+        assert!(connection.handle_line(notification).await.is_none());
+        let request = r#"{"jsonrpc":"2.0","id":3,"method":"send_broadcast","params":{"message":"hello"}}"#;
+        let response: Value = serde_json::from_str(&connection.handle_line(request).await.unwrap()).unwrap();
+        assert_eq!(response["id"], 3);
+        assert!(response.get("error").is_none());
+
+        // Both sends reach the network, which queues them for lack of peers
+        let mut queued = Vec::new();
+        while queued.len() < 2 {
+            if let Some(NetworkEvent::MessageQueued(message)) = events.recv().await {
+                queued.push(message.content);
+            }
+        }
+        assert_eq!(queued, ["quiet", "hello"]);
+
+        drop(connection);
+        client.shutdown().await.unwrap();
+        std::fs::remove_file(&key_file).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+//! What the bridge knows about nicknames and room membership
+
+use chat_core::{resolve, NetworkEvent, DEFAULT_ROOM};
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::irc::nick_from_username;
+
+pub type SharedDirectory = Arc<Mutex<Directory>>;
+
+/// Nicknames of remote peers, which peers are in which room, and which
+/// nicks and rooms the bridge's own IRC users hold
+#[derive(Debug, Default)]
+pub struct Directory {
+    /// Nick of each remote peer, from the username on its latest message
+    nicks: HashMap<String, String>,
+    /// Remote peers in each room
+    members: HashMap<String, HashSet<String>>,
+    /// Nicks of IRC users connected to the bridge
+    local_nicks: HashSet<String>,
+    /// IRC users in each room, so the room is only left when the last one parts
+    local_members: HashMap<String, HashSet<String>>,
+}
+
+impl Directory {
+    /// Track nicks and membership from a network event
+    pub fn observe(&mut self, event: &NetworkEvent) {
+        match event {
+            NetworkEvent::MessageReceived { message, .. } => {
+                if let Some(peer_id) = &message.sender_peer_id {
+                    self.nicks.insert(peer_id.clone(), nick_from_username(&message.sender));
+                }
+            }
+            NetworkEvent::RoomPeerJoined { room, peer_id } => {
+                self.members.entry(room.clone()).or_default().insert(peer_id.clone());
+            }
+            NetworkEvent::RoomPeerLeft { room, peer_id } => {
+                if let Some(members) = self.members.get_mut(room) {
+                    members.remove(peer_id);
+                }
+            }
+            NetworkEvent::PeerDisconnected(peer_id) => {
+                for members in self.members.values_mut() {
+                    members.remove(peer_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Nick shown for a remote peer
+    pub fn nick_of(&self, peer_id: &str) -> String {
+        match self.nicks.get(peer_id) {
+            Some(nick) => nick.clone(),
+            // Peers that haven't spoken yet go by the tail of their id
+            None => format!("p2p-{}", &peer_id[peer_id.len().saturating_sub(8)..]),
+        }
+    }
+
+    /// IRC prefix (`nick!user@host`) for a remote peer
+    pub fn prefix_of(&self, peer_id: &str) -> String {
+        format!("{}!{}@p2p", self.nick_of(peer_id), &peer_id[peer_id.len().saturating_sub(8)..])
+    }
+
+    /// Peer a nick shown by the bridge refers to; full peer ids and peer id
+    /// prefixes are accepted too. A nick several peers go by is an error.
+    pub fn peer_for_nick(&self, nick: &str) -> chat_core::error::Result<String> {
+        let peers: HashSet<&String> = self.nicks.keys().chain(self.members.values().flatten()).collect();
+        let known: Vec<(&str, String)> = peers.into_iter().map(|peer_id| (peer_id.as_str(), self.nick_of(peer_id))).collect();
+        resolve::resolve_peer(nick, known.iter().map(|(peer_id, nick)| (*peer_id, Some(nick.as_str()))))
+    }
+
+    /// Nicks of everyone in a room: remote members followed by local users
+    pub fn names(&self, room: &str) -> Vec<String> {
+        let remote = self.members.get(room).into_iter().flatten().map(|peer_id| self.nick_of(peer_id));
+        let local = self.local_members.get(room).into_iter().flatten().cloned();
+        remote.chain(local).collect()
+    }
+
+    /// Rooms a remote peer is known to be in
+    pub fn rooms_of(&self, peer_id: &str) -> Vec<String> {
+        self.members
+            .iter()
+            .filter(|(_, members)| members.contains(peer_id))
+            .map(|(room, _)| room.clone())
+            .collect()
+    }
+
+    /// Claim a nick for a local user, returning `false` if another one holds it
+    pub fn claim_nick(&mut self, nick: &str, previous: Option<&str>) -> bool {
+        if self.local_nicks.iter().any(|n| n.eq_ignore_ascii_case(nick) && Some(n.as_str()) != previous) {
+            return false;
+        }
+        if let Some(previous) = previous {
+            // Renaming keeps the user in their rooms
+            self.local_nicks.remove(previous);
+            for members in self.local_members.values_mut() {
+                if members.remove(previous) {
+                    members.insert(nick.to_string());
+                }
+            }
+        }
+        self.local_nicks.insert(nick.to_string());
+        true
+    }
+
+    pub fn release_nick(&mut self, nick: &str) {
+        self.local_nicks.remove(nick);
+        for members in self.local_members.values_mut() {
+            members.remove(nick);
+        }
+    }
+
+    pub fn is_local_nick(&self, nick: &str) -> bool {
+        self.local_nicks.iter().any(|n| n.eq_ignore_ascii_case(nick))
+    }
+
+    /// Record a local user joining, returning whether the bridge must join the room
+    pub fn join_local(&mut self, room: &str, nick: &str) -> bool {
+        let members = self.local_members.entry(room.to_string()).or_default();
+        let first = members.is_empty();
+        members.insert(nick.to_string());
+        first && room != DEFAULT_ROOM
+    }
+
+    /// Record a local user parting, returning whether the bridge should leave the room
+    pub fn part_local(&mut self, room: &str, nick: &str) -> bool {
+        let Some(members) = self.local_members.get_mut(room) else { return false };
+        members.remove(nick);
+        members.is_empty() && room != DEFAULT_ROOM
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chat_core::{ChatError, ChatMessage, MessageType};
+
+    #[test]
+    fn test_ambiguous_nicks_are_refused() {
+        let mut directory = Directory::default();
+        let mut said_hi = |peer_id: &str, username: &str| {
+            let mut message = ChatMessage::new(username.to_string(), "hi".to_string(), MessageType::Broadcast);
+            message.sender_peer_id = Some(peer_id.to_string());
+            directory.observe(&NetworkEvent::MessageReceived { message, mentions_me: false, clock_skew: None });
+        };
+        said_hi("12D3KooWAlice", "alice");
+        said_hi("12D3KooWBob1", "Bob Smith");
+        said_hi("12D3KooWBob2", "Bob_Smith");
+
+        assert_eq!(directory.peer_for_nick("ALICE").unwrap(), "12D3KooWAlice");
+        assert!(matches!(directory.peer_for_nick("Bob_Smith"), Err(ChatError::AmbiguousPeer { .. })));
+        assert!(matches!(directory.peer_for_nick("carol"), Err(ChatError::UnknownPeer(_))));
+    }
+}
@@ -0,0 +1,165 @@
+//! IRC protocol messages (RFC 1459 framing)
+
+use std::fmt;
+
+/// Name the bridge uses as the server prefix
+pub const SERVER_NAME: &str = "p2p-chat";
+
+pub const RPL_WELCOME: &str = "001";
+pub const RPL_YOURHOST: &str = "002";
+pub const RPL_CREATED: &str = "003";
+pub const RPL_MYINFO: &str = "004";
+pub const RPL_UMODEIS: &str = "221";
+pub const RPL_WHOISUSER: &str = "311";
+pub const RPL_ENDOFWHO: &str = "315";
+pub const RPL_ENDOFWHOIS: &str = "318";
+pub const RPL_WHOISCHANNELS: &str = "319";
+pub const RPL_CHANNELMODEIS: &str = "324";
+pub const RPL_NOTOPIC: &str = "331";
+pub const RPL_NAMREPLY: &str = "353";
+pub const RPL_ENDOFNAMES: &str = "366";
+pub const ERR_NOSUCHNICK: &str = "401";
+pub const ERR_NOSUCHCHANNEL: &str = "403";
+pub const ERR_CANNOTSENDTOCHAN: &str = "404";
+pub const ERR_UNKNOWNCOMMAND: &str = "421";
+pub const ERR_NOMOTD: &str = "422";
+pub const ERR_ERRONEUSNICKNAME: &str = "432";
+pub const ERR_NICKNAMEINUSE: &str = "433";
+pub const ERR_NOTONCHANNEL: &str = "442";
+pub const ERR_NOTREGISTERED: &str = "451";
+pub const ERR_NEEDMOREPARAMS: &str = "461";
+
+/// One IRC protocol line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrcMessage {
+    pub prefix: Option<String>,
+    pub command: String,
+    pub params: Vec<String>,
+}
+
+impl IrcMessage {
+    pub fn new(prefix: Option<String>, command: &str, params: Vec<String>) -> Self {
+        Self { prefix, command: command.to_string(), params }
+    }
+
+    /// A numeric or command sent from the server itself
+    pub fn server(command: &str, params: Vec<String>) -> Self {
+        Self::new(Some(SERVER_NAME.to_string()), command, params)
+    }
+
+    /// Parse a line without its trailing CRLF
+    pub fn parse(line: &str) -> Option<Self> {
+        let mut rest = line.trim_end_matches(['\r', '\n']);
+        let mut prefix = None;
+        if let Some(stripped) = rest.strip_prefix(':') {
+            let (p, r) = stripped.split_once(' ')?;
+            prefix = Some(p.to_string());
+            rest = r;
+        }
+
+        let mut params = Vec::new();
+        let (command, mut rest) = rest.trim_start().split_once(' ').unwrap_or((rest.trim_start(), ""));
+        if command.is_empty() {
+            return None;
+        }
+        loop {
+            rest = rest.trim_start_matches(' ');
+            if rest.is_empty() {
+                break;
+            }
+            if let Some(trailing) = rest.strip_prefix(':') {
+                params.push(trailing.to_string());
+                break;
+            }
+            let (param, r) = rest.split_once(' ').unwrap_or((rest, ""));
+            params.push(param.to_string());
+            rest = r;
+        }
+
+        Some(Self { prefix, command: command.to_ascii_uppercase(), params })
+    }
+
+    pub fn param(&self, index: usize) -> Option<&str> {
+        self.params.get(index).map(String::as_str)
+    }
+}
+
+impl fmt::Display for IrcMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(prefix) = &self.prefix {
+            write!(f, ":{prefix} ")?;
+        }
+        write!(f, "{}", self.command)?;
+        if let Some((last, middle)) = self.params.split_last() {
+            for param in middle {
+                write!(f, " {param}")?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(f, " :{last}")?;
+            } else {
+                write!(f, " {last}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether `nick` can be used as an IRC nickname
+pub fn is_valid_nick(nick: &str) -> bool {
+    !nick.is_empty()
+        && nick.len() <= 30
+        && !nick.starts_with(['#', '&', ':'])
+        && !nick.contains([' ', ',', '!', '@', '*', '?'])
+}
+
+/// Turn a chat username into something usable as a nickname
+pub fn nick_from_username(username: &str) -> String {
+    let nick: String = username
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "-_[]{}\\|^`".contains(c) { c } else { '_' })
+        .take(30)
+        .collect();
+    if nick.is_empty() { "_".to_string() } else { nick }
+}
+
+/// Make remote text safe to send as one IRC parameter: control characters
+/// could end the line early (`\r`, `\0`) or start CTCP requests (`\x01`)
+pub fn sanitize_text(text: &str) -> String {
+    text.chars()
+        .map(|c| match c {
+            '\t' => ' ',
+            c if c.is_control() => char::REPLACEMENT_CHARACTER,
+            c => c,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_format() {
+        let message = IrcMessage::parse(":alice!a@host privmsg #chat :hello there\r\n").unwrap();
+        assert_eq!(message.prefix.as_deref(), Some("alice!a@host"));
+        assert_eq!(message.command, "PRIVMSG");
+        assert_eq!(message.params, vec!["#chat", "hello there"]);
+        assert_eq!(message.to_string(), ":alice!a@host PRIVMSG #chat :hello there");
+
+        let message = IrcMessage::parse("JOIN #a,#b").unwrap();
+        assert_eq!(message.params, vec!["#a,#b"]);
+        assert_eq!(message.to_string(), "JOIN #a,#b");
+        assert!(IrcMessage::parse("").is_none());
+
+        assert_eq!(nick_from_username("Jane Doe"), "Jane_Doe");
+        assert!(!is_valid_nick("#chat"));
+    }
+
+    #[test]
+    fn test_sanitize_text() {
+        assert_eq!(sanitize_text("hi\tthere 👋"), "hi there 👋");
+        let hostile = sanitize_text("hi\rQUIT :bye\0\x01VERSION\x01");
+        assert!(!hostile.contains(|c: char| c.is_control()));
+        assert_eq!(hostile, "hi\u{fffd}QUIT :bye\u{fffd}\u{fffd}VERSION\u{fffd}");
+    }
+}
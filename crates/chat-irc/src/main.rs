@@ -0,0 +1,109 @@
+//! Bridge letting IRC clients take part in the p2p chat
+
+mod directory;
+mod irc;
+mod session;
+
+use anyhow::Result;
+use chat_core::{config, start_chat_client, Settings};
+use clap::Parser;
+use directory::Directory;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+#[derive(Parser)]
+#[command(name = "chat-irc")]
+#[command(about = "Local IRC server bridged to the P2P chat network")]
+struct Args {
+    /// Port to listen on for p2p connections (default: chosen by the OS)
+    #[arg(short, long, env = "P2P_CHAT_PORT")]
+    port: Option<u16>,
+
+    /// Username for messages the bridge itself sends (default: irc-bridge)
+    #[arg(short, long, env = "P2P_CHAT_USERNAME")]
+    username: Option<String>,
+
+    /// Bootstrap node address; repeat for several (default: built-in bootstrap node)
+    #[arg(short, long, env = "P2P_CHAT_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap: Vec<String>,
+
+    /// Path to the bridge's keypair file (default: irc_key.dat)
+    #[arg(short, long, env = "P2P_CHAT_KEY_FILE")]
+    key_file: Option<String>,
+
+    /// Settings file (default: config.toml in the user's config directory, if present)
+    #[arg(long, env = "P2P_CHAT_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Profile from the settings file, e.g. to run a second identity
+    #[arg(long, env = "P2P_CHAT_PROFILE")]
+    profile: Option<String>,
+
+    /// Address IRC clients connect to
+    #[arg(short, long, default_value = "127.0.0.1:6667")]
+    irc: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let args = Args::parse();
+
+    // Built-in defaults, then the settings file, then environment and flags
+    let defaults = Settings {
+        username: Some("irc-bridge".to_string()),
+        key_file: Some("irc_key.dat".to_string()),
+        ..Default::default()
+    };
+    let flags = Settings {
+        username: args.username,
+        key_file: args.key_file,
+        port: args.port,
+        bootstrap: (!args.bootstrap.is_empty()).then_some(args.bootstrap),
+        ..Default::default()
+    };
+    let settings = defaults.merge(config::load(args.config.as_deref(), args.profile.as_deref())?).merge(flags);
+    let username = settings.username.clone().unwrap_or_default();
+    let config = settings.network_config()?;
+
+    if !args.irc.ip().is_loopback() {
+        warn!("IRC server on {} is reachable from other machines and has no authentication", args.irc);
+    }
+    let listener = TcpListener::bind(args.irc).await?;
+    info!("IRC server listening on {}", listener.local_addr()?);
+
+    let (client, mut events) = start_chat_client(config, username).await?;
+    let directory = Arc::new(Mutex::new(Directory::default()));
+
+    // Keep track of nicks and room members for every session
+    let observed_directory = directory.clone();
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            observed_directory.lock().unwrap().observe(&event);
+        }
+    });
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, addr) = accepted?;
+                info!("IRC client connected from {addr}");
+                let (client, directory) = (client.clone(), directory.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = session::serve_connection(stream, client, directory).await {
+                        warn!("IRC connection error: {}", e);
+                    }
+                });
+            }
+            _ = tokio::signal::ctrl_c() => {
+                info!("Shutting down");
+                break;
+            }
+        }
+    }
+    Ok(client.shutdown().await?)
+}
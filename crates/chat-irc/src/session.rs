@@ -0,0 +1,407 @@
+//! One IRC client connected to the bridge
+//!
+//! Channels map to rooms (`#chat` is the default room) and a PRIVMSG to a
+//! nick becomes a direct message to that peer. Every IRC user of a bridge
+//! shares the bridge's peer id, so direct messages to it reach all of them.
+
+use anyhow::Result;
+use chat_core::{ChatClient, ChatError, ChatMessage, MessageType, NetworkEvent, DEFAULT_ROOM};
+use std::collections::HashSet;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tracing::{debug, warn};
+
+use crate::directory::SharedDirectory;
+use crate::irc::*;
+
+/// Channel name for a room
+fn channel_of(room: &str) -> String {
+    format!("#{room}")
+}
+
+/// Room named by a channel, if the channel is a valid room
+fn room_of(channel: &str) -> Option<String> {
+    let room = channel.strip_prefix('#')?;
+    chat_core::validate_room_name(room).ok()?;
+    Some(room.to_string())
+}
+
+struct Session {
+    client: ChatClient,
+    directory: SharedDirectory,
+    writer: OwnedWriteHalf,
+    nick: Option<String>,
+    /// Whether USER has been received
+    has_user: bool,
+    registered: bool,
+    /// Rooms this user has joined
+    rooms: HashSet<String>,
+    /// Ids of messages this user sent that are not confirmed yet
+    pending: HashSet<String>,
+}
+
+impl Session {
+    async fn send(&mut self, message: IrcMessage) -> Result<()> {
+        self.writer.write_all(format!("{message}\r\n").as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Send a numeric reply addressed to this user
+    async fn reply(&mut self, code: &str, params: &[&str]) -> Result<()> {
+        let mut all = vec![self.nick.clone().unwrap_or_else(|| "*".to_string())];
+        all.extend(params.iter().map(|p| p.to_string()));
+        self.send(IrcMessage::server(code, all)).await
+    }
+
+    fn own_prefix(&self) -> String {
+        let nick = self.nick.as_deref().unwrap_or("*");
+        format!("{nick}!{nick}@bridge")
+    }
+
+    /// Send chat content as PRIVMSGs, one per line
+    async fn privmsg(&mut self, prefix: String, target: &str, content: &str) -> Result<()> {
+        for line in content.lines().filter(|line| !line.is_empty()) {
+            let message = IrcMessage::new(Some(prefix.clone()), "PRIVMSG", vec![target.to_string(), sanitize_text(line)]);
+            self.send(message).await?;
+        }
+        Ok(())
+    }
+
+    /// Send a CTCP ACTION (`/me`) with remote text in it
+    async fn action(&mut self, prefix: String, target: &str, text: &str) -> Result<()> {
+        let action = format!("\x01ACTION {}\x01", sanitize_text(text));
+        self.send(IrcMessage::new(Some(prefix), "PRIVMSG", vec![target.to_string(), action])).await
+    }
+
+    /// Handle one line from the IRC client; returns `false` once it quits
+    async fn handle_line(&mut self, line: &str) -> Result<bool> {
+        let Some(message) = IrcMessage::parse(line) else { return Ok(true) };
+        debug!("IRC command: {}", message.command);
+
+        match message.command.as_str() {
+            "CAP" => {
+                // No capabilities are supported; answering lets clients finish negotiation
+                if message.param(0) == Some("LS") {
+                    self.send(IrcMessage::server("CAP", vec!["*".into(), "LS".into(), String::new()])).await?;
+                }
+            }
+            "PING" => {
+                let token = message.param(0).unwrap_or(SERVER_NAME).to_string();
+                self.send(IrcMessage::server("PONG", vec![SERVER_NAME.into(), token])).await?;
+            }
+            "NICK" => self.nick(message.param(0)).await?,
+            "USER" => {
+                self.has_user = true;
+                self.try_register().await?;
+            }
+            "QUIT" => return Ok(false),
+            _ if !self.registered => self.reply(ERR_NOTREGISTERED, &["You have not registered"]).await?,
+            "JOIN" => {
+                let Some(channels) = message.param(0).map(str::to_string) else {
+                    return self.reply(ERR_NEEDMOREPARAMS, &["JOIN", "Not enough parameters"]).await.map(|_| true);
+                };
+                for channel in channels.split(',') {
+                    self.join(channel).await?;
+                }
+            }
+            "PART" => {
+                let Some(channels) = message.param(0).map(str::to_string) else {
+                    return self.reply(ERR_NEEDMOREPARAMS, &["PART", "Not enough parameters"]).await.map(|_| true);
+                };
+                for channel in channels.split(',') {
+                    self.part(channel).await?;
+                }
+            }
+            "NAMES" => {
+                let channels = message.param(0).map(str::to_string).unwrap_or_default();
+                for channel in channels.split(',').filter(|c| !c.is_empty()) {
+                    self.names(channel).await?;
+                }
+            }
+            "PRIVMSG" | "NOTICE" => match (message.param(0), message.param(1)) {
+                (Some(target), Some(text)) => {
+                    let (target, text) = (target.to_string(), text.to_string());
+                    // NOTICEs must never trigger error replies
+                    self.privmsg_to_network(&target, &text, message.command == "PRIVMSG").await?;
+                }
+                _ => self.reply(ERR_NEEDMOREPARAMS, &[&message.command, "Not enough parameters"]).await?,
+            },
+            "WHOIS" => match message.params.last() {
+                Some(nick) => self.whois(&nick.clone()).await?,
+                None => self.reply(ERR_NEEDMOREPARAMS, &["WHOIS", "Not enough parameters"]).await?,
+            },
+            "WHO" => {
+                let mask = message.param(0).unwrap_or("*").to_string();
+                self.reply(RPL_ENDOFWHO, &[&mask, "End of WHO list"]).await?;
+            }
+            "MODE" => match message.param(0) {
+                Some(target) if target.starts_with('#') => {
+                    let target = target.to_string();
+                    self.reply(RPL_CHANNELMODEIS, &[&target, "+"]).await?;
+                }
+                Some(_) => self.reply(RPL_UMODEIS, &["+"]).await?,
+                None => self.reply(ERR_NEEDMOREPARAMS, &["MODE", "Not enough parameters"]).await?,
+            },
+            command => {
+                let command = command.to_string();
+                self.reply(ERR_UNKNOWNCOMMAND, &[&command, "Unknown command"]).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn nick(&mut self, nick: Option<&str>) -> Result<()> {
+        let Some(nick) = nick.filter(|n| is_valid_nick(n)) else {
+            let nick = nick.unwrap_or_default().to_string();
+            return self.reply(ERR_ERRONEUSNICKNAME, &[&nick, "Erroneous nickname"]).await;
+        };
+        let claimed = self.directory.lock().unwrap().claim_nick(nick, self.nick.as_deref());
+        if !claimed {
+            return self.reply(ERR_NICKNAMEINUSE, &[nick, "Nickname is already in use"]).await;
+        }
+
+        if self.registered {
+            let prefix = self.own_prefix();
+            self.send(IrcMessage::new(Some(prefix), "NICK", vec![nick.to_string()])).await?;
+        }
+        self.nick = Some(nick.to_string());
+        self.try_register().await
+    }
+
+    async fn try_register(&mut self) -> Result<()> {
+        if self.registered || !self.has_user || self.nick.is_none() {
+            return Ok(());
+        }
+        self.registered = true;
+        let nick = self.nick.clone().unwrap_or_default();
+        let version = env!("CARGO_PKG_VERSION");
+        self.reply(RPL_WELCOME, &[&format!("Welcome to the p2p chat bridge, {nick}")]).await?;
+        self.reply(RPL_YOURHOST, &[&format!("Your host is {SERVER_NAME}, running version {version}")]).await?;
+        self.reply(RPL_CREATED, &["This server bridges IRC to the p2p chat network"]).await?;
+        self.reply(RPL_MYINFO, &[SERVER_NAME, version, "i", "nt"]).await?;
+        self.reply(ERR_NOMOTD, &["Join #chat to talk to everyone, or #<room> for a room"]).await
+    }
+
+    async fn join(&mut self, channel: &str) -> Result<()> {
+        let Some(room) = room_of(channel) else {
+            return self.reply(ERR_NOSUCHCHANNEL, &[channel, "No such channel"]).await;
+        };
+        if self.rooms.contains(&room) {
+            return Ok(());
+        }
+        let nick = self.nick.clone().unwrap_or_default();
+        let must_join = self.directory.lock().unwrap().join_local(&room, &nick);
+        if must_join {
+            self.client.join_room(room.clone()).await?;
+        }
+        self.rooms.insert(room.clone());
+
+        let prefix = self.own_prefix();
+        self.send(IrcMessage::new(Some(prefix), "JOIN", vec![channel_of(&room)])).await?;
+        self.reply(RPL_NOTOPIC, &[&channel_of(&room), "No topic is set"]).await?;
+        self.names(&channel_of(&room)).await
+    }
+
+    async fn part(&mut self, channel: &str) -> Result<()> {
+        let Some(room) = room_of(channel).filter(|room| self.rooms.contains(room)) else {
+            return self.reply(ERR_NOTONCHANNEL, &[channel, "You're not on that channel"]).await;
+        };
+        self.leave(&room).await;
+        let prefix = self.own_prefix();
+        self.send(IrcMessage::new(Some(prefix), "PART", vec![channel_of(&room)])).await
+    }
+
+    /// Drop a room, leaving it on the network if no other IRC user is in it
+    async fn leave(&mut self, room: &str) {
+        self.rooms.remove(room);
+        let nick = self.nick.clone().unwrap_or_default();
+        let must_leave = self.directory.lock().unwrap().part_local(room, &nick);
+        if must_leave {
+            if let Err(e) = self.client.leave_room(room.to_string()).await {
+                warn!("Failed to leave room {}: {}", room, e);
+            }
+        }
+    }
+
+    async fn names(&mut self, channel: &str) -> Result<()> {
+        if let Some(room) = room_of(channel) {
+            let names = self.directory.lock().unwrap().names(&room).join(" ");
+            self.reply(RPL_NAMREPLY, &["=", channel, &names]).await?;
+        }
+        self.reply(RPL_ENDOFNAMES, &[channel, "End of NAMES list"]).await
+    }
+
+    /// Peer a nick refers to: a contact or peer the network knows by that
+    /// name, or else a peer the bridge shows under that nick
+    async fn resolve_nick(&self, nick: &str) -> chat_core::error::Result<String> {
+        match self.client.resolve_peer(nick.to_string()).await {
+            Err(ChatError::UnknownPeer(_)) => self.directory.lock().unwrap().peer_for_nick(nick),
+            resolved => resolved,
+        }
+    }
+
+    /// Tell the user a nick names no single peer
+    async fn no_such_nick(&mut self, nick: &str, error: &ChatError) -> Result<()> {
+        let text = match error {
+            ChatError::AmbiguousPeer { .. } => format!("Ambiguous nick: {error}"),
+            _ => "No such nick/channel".to_string(),
+        };
+        self.reply(ERR_NOSUCHNICK, &[nick, &sanitize_text(&text)]).await
+    }
+
+    async fn whois(&mut self, nick: &str) -> Result<()> {
+        let is_local = self.directory.lock().unwrap().is_local_nick(nick);
+        if is_local {
+            self.reply(RPL_WHOISUSER, &[nick, nick, "bridge", "*", "IRC user on this bridge"]).await?;
+            return self.reply(RPL_ENDOFWHOIS, &[nick, "End of WHOIS list"]).await;
+        }
+        match self.resolve_nick(nick).await {
+            Ok(peer_id) => {
+                let (nick, rooms) = {
+                    let directory = self.directory.lock().unwrap();
+                    let rooms: Vec<String> = directory.rooms_of(&peer_id).iter().map(|r| channel_of(r)).collect();
+                    (directory.nick_of(&peer_id), rooms.join(" "))
+                };
+                let user = &peer_id[peer_id.len().saturating_sub(8)..];
+                self.reply(RPL_WHOISUSER, &[&nick, user, "p2p", "*", &peer_id]).await?;
+                if !rooms.is_empty() {
+                    self.reply(RPL_WHOISCHANNELS, &[&nick, &rooms]).await?;
+                }
+            }
+            Err(e) => self.no_such_nick(nick, &e).await?,
+        }
+        self.reply(RPL_ENDOFWHOIS, &[nick, "End of WHOIS list"]).await
+    }
+
+    /// Publish a PRIVMSG from the IRC user as a chat message
+    async fn privmsg_to_network(&mut self, target: &str, text: &str, report_errors: bool) -> Result<()> {
+        let nick = self.nick.clone().unwrap_or_default();
+        let message = if target.starts_with('#') {
+            match room_of(target).filter(|room| self.rooms.contains(room)) {
+                Some(room) => {
+                    let mut message = ChatMessage::new(nick, text.to_string(), MessageType::Broadcast);
+                    message.room = (room != DEFAULT_ROOM).then_some(room);
+                    message
+                }
+                None if report_errors => {
+                    return self.reply(ERR_CANNOTSENDTOCHAN, &[target, "Cannot send to channel"]).await;
+                }
+                None => return Ok(()),
+            }
+        } else {
+            match self.resolve_nick(target).await {
+                Ok(peer_id) => ChatMessage::new(nick, text.to_string(), MessageType::Direct { target_peer_id: peer_id }),
+                Err(e) if report_errors => return self.no_such_nick(target, &e).await,
+                Err(_) => return Ok(()),
+            }
+        };
+
+        self.pending.insert(message.id.clone());
+        self.client.send_message(message).await?;
+        Ok(())
+    }
+
+    /// Relay a network event to the IRC client
+    async fn handle_event(&mut self, event: NetworkEvent) -> Result<()> {
+        let nick = self.nick.clone().unwrap_or_default();
+        match event {
+            NetworkEvent::MessageReceived { message, .. } => {
+                // The directory may not have seen this message yet, so name the sender from it
+                let user = message.sender_peer_id.as_deref().map_or("unsigned", |id| &id[id.len().saturating_sub(8)..]);
+                let prefix = format!("{}!{}@p2p", nick_from_username(&message.sender), user);
+                match &message.message_type {
+                    MessageType::Direct { .. } => self.privmsg(prefix, &nick, &message.content).await?,
+                    _ if !self.rooms.contains(message.room_name()) => {}
+                    MessageType::Reaction { emoji, .. } => {
+                        self.action(prefix, &channel_of(message.room_name()), &format!("reacted {emoji}")).await?;
+                    }
+                    MessageType::Broadcast => {
+                        self.privmsg(prefix, &channel_of(message.room_name()), &message.content).await?;
+                    }
+                }
+            }
+            NetworkEvent::MessageSent(message) => {
+                // Other IRC users on this bridge see what we send to rooms they are in
+                let ours = self.pending.remove(&message.id);
+                let in_room = !matches!(message.message_type, MessageType::Direct { .. })
+                    && self.rooms.contains(message.room_name());
+                if !ours && in_room {
+                    let sender = nick_from_username(&message.sender);
+                    let prefix = format!("{sender}!{sender}@bridge");
+                    self.privmsg(prefix, &channel_of(message.room_name()), &message.content).await?;
+                }
+            }
+            NetworkEvent::MessageQueued(message) if self.pending.contains(&message.id) => {
+                let text = "Nobody else is in the room yet, the message will be sent when someone joins";
+                self.send(IrcMessage::server("NOTICE", vec![nick, text.to_string()])).await?;
+            }
+            NetworkEvent::MessageFailed { message, error } if self.pending.remove(&message.id) => {
+                let text = format!("Message could not be sent: {error}");
+                self.send(IrcMessage::server("NOTICE", vec![nick, text])).await?;
+            }
+            NetworkEvent::RoomPeerJoined { room, peer_id } if self.rooms.contains(&room) => {
+                let prefix = self.directory.lock().unwrap().prefix_of(&peer_id);
+                self.send(IrcMessage::new(Some(prefix), "JOIN", vec![channel_of(&room)])).await?;
+            }
+            NetworkEvent::RoomPeerLeft { room, peer_id } if self.rooms.contains(&room) => {
+                let prefix = self.directory.lock().unwrap().prefix_of(&peer_id);
+                self.send(IrcMessage::new(Some(prefix), "PART", vec![channel_of(&room)])).await?;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+/// Serve one IRC client until it quits or disconnects
+pub async fn serve_connection(stream: TcpStream, client: ChatClient, directory: SharedDirectory) -> Result<()> {
+    // An IRC client that stops reading must not hold up the network
+    let mut events = client.subscribe().disconnect_when_full();
+    let (reader, writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut session = Session {
+        client,
+        directory,
+        writer,
+        nick: None,
+        has_user: false,
+        registered: false,
+        rooms: HashSet::new(),
+        pending: HashSet::new(),
+    };
+
+    let result = async {
+        loop {
+            tokio::select! {
+                line = lines.next_line() => {
+                    let Some(line) = line? else { break };
+                    if !session.handle_line(&line).await? {
+                        break;
+                    }
+                }
+                event = events.recv() => match event {
+                    Some(event) if session.registered => session.handle_event(event).await?,
+                    Some(_) => {}
+                    None => {
+                        if events.lagged() {
+                            warn!("IRC client fell too far behind, disconnecting it");
+                        }
+                        break;
+                    }
+                },
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    // Leave the network rooms nobody else on the bridge is using
+    for room in session.rooms.clone() {
+        session.leave(&room).await;
+    }
+    if let Some(nick) = &session.nick {
+        session.directory.lock().unwrap().release_nick(nick);
+    }
+    result
+}
@@ -1,29 +1,75 @@
 //! Application state and message handling
 
 use anyhow::Result;
-use chat_core::{ChatClient, MessageType, NetworkEvent};
+use chat_core::{
+    ChatClient, ChatMessage, ConnectionDirection, EventStream, MessageHistory, MessageType, NetworkEvent, PeerInfo,
+    DEFAULT_ROOM,
+};
+use std::collections::{BTreeMap, HashSet};
 use std::io::{self, Write};
-use tokio::io::{AsyncBufReadExt, BufReader};
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+use crate::input::LineReader;
+
+/// ANSI escapes used to highlight messages that mention us
+const HIGHLIGHT: &str = "\x1b[1;33m🔔 ";
+const RESET: &str = "\x1b[0m";
+
+/// Session state shared between the input loop and the event handler
+#[derive(Debug, Default)]
+pub struct SessionState {
+    pub history: MessageHistory,
+    /// Display numbers of messages that mention us
+    pub mentions: Vec<usize>,
+    /// Peers seen this session and their usernames, for tab completion
+    pub peers: BTreeMap<String, Option<String>>,
+    /// Usernames and the peers signing under them whose key is not the
+    /// one the name is pinned to, as `(username, peer_id)`
+    pub impostors: HashSet<(String, String)>,
+}
+
+impl SessionState {
+    /// Remember a peer, keeping the username we already know if none is given
+    pub fn saw_peer(&mut self, peer_id: &str, username: Option<&str>) {
+        let known = self.peers.entry(peer_id.to_string()).or_default();
+        if let Some(username) = username {
+            *known = Some(username.to_string());
+        }
+    }
+
+    /// Remember the author of a signed message
+    fn saw_author(&mut self, message: &ChatMessage) {
+        if let Some(peer_id) = &message.sender_peer_id {
+            self.saw_peer(peer_id, Some(&message.sender));
+        }
+    }
+}
+
+pub type SharedState = Arc<Mutex<SessionState>>;
 
 /// Main application state
 pub struct ChatApp {
     pub client: ChatClient,
+    pub state: SharedState,
 }
 
 impl ChatApp {
-    pub fn new(client: ChatClient) -> Self {
-        ChatApp { client }
+    pub fn new(client: ChatClient, state: SharedState) -> Self {
+        ChatApp { client, state }
+    }
+
+    /// Resolve a message number typed by the user to a message id
+    fn message_id(&self, number: &str) -> Option<String> {
+        let number = number.trim_start_matches('#').parse().ok()?;
+        self.state.lock().unwrap().history.get(number).map(|m| m.id.clone())
     }
 
     /// Handle user input and send commands
     pub async fn handle_user_input(&self) -> Result<()> {
-        let stdin = tokio::io::stdin();
-        let mut lines = BufReader::new(stdin).lines();
-        
-        print!("> ");
-        io::stdout().flush()?;
+        let mut lines = LineReader::spawn(self.state.clone())?;
         
-        while let Ok(Some(line)) = lines.next_line().await {
+        while let Some(line) = lines.next_line().await {
             let trimmed = line.trim();
             
             if trimmed == "quit" || trimmed == "exit" {
@@ -31,55 +77,308 @@ impl ChatApp {
             }
             
             if trimmed == "/peers" || trimmed == "/list" {
-                let _ = self.client.list_peers();
-            } else if trimmed.starts_with("/dm ") {
-                // Parse direct message: /dm <peer_id> <message>
-                let parts: Vec<&str> = trimmed[4..].splitn(2, ' ').collect();
+                let _ = self.client.list_peers().await;
+            } else if let Some(args) = trimmed.strip_prefix("/dm ") {
+                // Parse direct message: /dm <name or peer id> <message>
+                let parts: Vec<&str> = args.splitn(2, ' ').collect();
                 if parts.len() == 2 {
-                    let peer_id = parts[0].to_string();
                     let message = parts[1].to_string();
-                    if let Err(e) = self.client.send_direct(peer_id.clone(), message.clone()) {
+                    let sent = match self.client.resolve_peer(parts[0].to_string()).await {
+                        Ok(peer_id) => self.client.send_direct(peer_id, message).await,
+                        Err(e) => Err(e),
+                    };
+                    if let Err(e) = sent {
                         println!("❌ Failed to send direct message: {}", e);
-                    } else {
-                        println!("📤 You → {}: {}", &peer_id[..12.min(peer_id.len())], message);
                     }
                 } else {
-                    println!("Usage: /dm <peer_id> <message>");
-                    println!("Example: /dm 12D3KooW... Hello there!");
+                    println!("Usage: /dm <name or peer id> <message>");
+                    println!("Example: /dm alice Hello there! (Tab completes names and ids)");
+                }
+            } else if let Some(args) = trimmed.strip_prefix("/reply ") {
+                // Parse reply: /reply <number> <message>
+                match args.split_once(' ').and_then(|(n, m)| Some((self.message_id(n)?, m.trim()))) {
+                    Some((reply_to, message)) if !message.is_empty() => {
+                        if let Err(e) = self.client.send_reply(reply_to, message.to_string()).await {
+                            println!("❌ Failed to send reply: {}", e);
+                        }
+                    }
+                    _ => println!("Usage: /reply <message number> <message>"),
+                }
+            } else if let Some(args) = trimmed.strip_prefix("/react ") {
+                // Parse reaction: /react <number> <emoji>
+                match args.split_once(' ').and_then(|(n, e)| Some((self.message_id(n)?, e.trim()))) {
+                    Some((target_id, emoji)) if !emoji.is_empty() => {
+                        if let Err(e) = self.client.send_reaction(target_id, emoji.to_string()).await {
+                            println!("❌ Failed to send reaction: {}", e);
+                        }
+                    }
+                    _ => println!("Usage: /react <message number> <emoji>"),
+                }
+            } else if let Some(number) = trimmed.strip_prefix("/thread ") {
+                match self.message_id(number.trim()) {
+                    Some(id) => print_thread(&self.state.lock().unwrap().history, &id),
+                    None => println!("Usage: /thread <message number>"),
+                }
+            } else if let Some(room) = trimmed.strip_prefix("/join ") {
+                match self.client.join_room(room.trim().to_string()).await {
+                    Ok(()) => println!("🚪 Joined #{}", room.trim()),
+                    Err(e) => println!("❌ {}", e),
+                }
+            } else if let Some(room) = trimmed.strip_prefix("/leave ") {
+                match self.client.leave_room(room.trim().to_string()).await {
+                    Ok(()) => println!("🚪 Left #{}", room.trim()),
+                    Err(e) => println!("❌ {}", e),
+                }
+            } else if let Some(args) = trimmed.strip_prefix("/room ") {
+                // Parse room message: /room <room> <message>
+                match args.split_once(' ').map(|(room, m)| (room, m.trim())) {
+                    Some((room, message)) if !message.is_empty() => {
+                        if let Err(e) = self.client.send_to_room(room.to_string(), message.to_string()).await {
+                            println!("❌ Failed to send message: {}", e);
+                        }
+                    }
+                    _ => println!("Usage: /room <room> <message>"),
+                }
+            } else if trimmed == "/contacts" {
+                match self.client.contacts().await {
+                    Ok(contacts) if contacts.is_empty() => println!("No contacts pinned yet."),
+                    Ok(contacts) => {
+                        println!("📇 Contacts ({}):", contacts.len());
+                        for contact in contacts {
+                            let status = if contact.verified { "✅ verified" } else { "pinned" };
+                            println!("  {} ({}) {}", contact.username, short_id(&contact.peer_id), status);
+                        }
+                    }
+                    Err(e) => println!("❌ {}", e),
+                }
+            } else if let Some(args) = trimmed.strip_prefix("/verify ") {
+                // Parse verification: /verify <name> [confirm]
+                match args.split_whitespace().collect::<Vec<_>>().as_slice() {
+                    [name] => match self.client.safety_number(name.to_string()).await {
+                        Ok(number) => {
+                            println!("🔐 Safety number with {}:", name);
+                            println!("   {}", number);
+                            println!("Compare it with the number {} sees for you, in person or over another channel.", name);
+                            println!("If they match, run /verify {} confirm", name);
+                        }
+                        Err(e) => println!("❌ {}", e),
+                    },
+                    [name, "confirm"] => match self.client.verify_contact(name.to_string()).await {
+                        Ok(contact) => println!("✅ {} ({}) is verified", contact.username, short_id(&contact.peer_id)),
+                        Err(e) => println!("❌ {}", e),
+                    },
+                    _ => println!("Usage: /verify <name> [confirm]"),
+                }
+            } else if let Some(name) = trimmed.strip_prefix("/forget ") {
+                match self.client.forget_contact(name.trim().to_string()).await {
+                    Ok(contact) => {
+                        self.state.lock().unwrap().impostors.retain(|(username, _)| *username != contact.username);
+                        println!("🗑️  Forgot {} ({}); the next key to use the name is pinned", contact.username, short_id(&contact.peer_id));
+                    }
+                    Err(e) => println!("❌ {}", e),
                 }
+            } else if trimmed == "/history" {
+                print_history(&self.state.lock().unwrap().history);
+            } else if trimmed == "/mentions" {
+                print_mentions(&self.state.lock().unwrap());
             } else if !trimmed.is_empty() && !trimmed.starts_with('/') {
                 // Regular message - broadcast to all
-                if let Err(e) = self.client.send_broadcast(trimmed.to_string()) {
+                if let Err(e) = self.client.send_broadcast(trimmed.to_string()).await {
                     println!("❌ Failed to send message: {}", e);
-                } else {
-                    println!("📤 You (broadcast): {}", trimmed);
                 }
             } else if trimmed.starts_with('/') {
                 println!("Unknown command. Available commands:");
                 println!("  /peers or /list  - Show connected peers");
-                println!("  /dm <peer_id> <message> - Send direct message");
+                println!("  /dm <name or peer id> <message> - Send direct message");
+                println!("  /reply <n> <message> - Reply to message number n");
+                println!("  /react <n> <emoji> - React to message number n");
+                println!("  /thread <n> - Show the thread containing message n");
+                println!("  /history - Show all messages in causal order");
+                println!("  /mentions - List messages that mention you");
+                println!("  /contacts - List usernames pinned to their keys");
+                println!("  /verify <name> [confirm] - Compare safety numbers with a contact");
+                println!("  /forget <name> - Unpin a contact who changed keys");
+                println!("  /join <room> - Join a room");
+                println!("  /leave <room> - Leave a room");
+                println!("  /room <room> <message> - Send a message to a room");
                 println!("  quit or exit - Exit the chat");
             }
-            
-            print!("> ");
-            io::stdout().flush()?;
         }
         
         Ok(())
     }
 }
 
+/// Peer id prefix shown in place of the full id
+fn short_id(peer_id: &str) -> &str {
+    &peer_id[..12.min(peer_id.len())]
+}
+
+/// Print a whole thread with replies indented under their parent
+fn print_thread(history: &MessageHistory, id: &str) {
+    let thread = history.thread(id);
+    println!("🧵 Thread ({} messages):", thread.len());
+    for (depth, message) in thread {
+        let number = history.number_of(&message.id).unwrap_or_default();
+        println!("{}[{}] {}: {}{}", "  ".repeat(depth + 1), number, message.sender, message.content,
+            format_reactions(history, &message.id));
+    }
+}
+
+/// Format the reactions on a message as a trailing summary
+fn format_reactions(history: &MessageHistory, id: &str) -> String {
+    history
+        .reactions(id)
+        .iter()
+        .map(|(emoji, count)| format!(" {emoji}{count}"))
+        .collect()
+}
+
+/// Print every message in causal order, which may differ from arrival order
+fn print_history(history: &MessageHistory) {
+    if history.is_empty() {
+        println!("No messages yet.");
+        return;
+    }
+    println!("📜 History ({} messages):", history.len());
+    for message in history.ordered() {
+        let number = history.number_of(&message.id).unwrap_or_default();
+        println!("  [{}] {}: {}{}", number, message.sender, message.content, format_reactions(history, &message.id));
+    }
+}
+
+/// List the messages that mentioned us this session
+fn print_mentions(state: &SessionState) {
+    if state.mentions.is_empty() {
+        println!("No mentions yet.");
+        return;
+    }
+    println!("🔔 Mentions ({}):", state.mentions.len());
+    for &number in &state.mentions {
+        if let Some(message) = state.history.get(number) {
+            println!("  [{}] {}: {}", number, message.sender, message.content);
+        }
+    }
+}
+
+/// Run the user's notification hook for a mention.
+///
+/// The command is run through `sh -c` with the sender and content exposed as
+/// `CHAT_SENDER` and `CHAT_MESSAGE`, e.g. `notify-send "$CHAT_SENDER" "$CHAT_MESSAGE"`.
+fn notify_mention(command: &str, message: &ChatMessage) {
+    let result = tokio::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .env("CHAT_SENDER", &message.sender)
+        .env("CHAT_MESSAGE", &message.content)
+        .stdin(std::process::Stdio::null())
+        .spawn();
+    match result {
+        Ok(mut child) => {
+            tokio::spawn(async move {
+                let _ = child.wait().await;
+            });
+        }
+        Err(e) => warn!("Failed to run notification command: {}", e),
+    }
+}
+
+/// Print a chat message, recording it in the history.
+///
+/// Returns the message's display number if it was newly recorded.
+fn print_message(history: &mut MessageHistory, message: ChatMessage, sender: &str, highlight: bool) -> Option<usize> {
+    let (start, end) = if highlight { (HIGHLIGHT, RESET) } else { ("", "") };
+    let reply_to = message.reply_to.as_deref().and_then(|id| history.number_of(id));
+    let message_type = message.message_type.clone();
+    let content = message.content.clone();
+    let room = room_tag(&message);
+
+    let number = history.insert(message);
+    match (number, message_type) {
+        (_, MessageType::Reaction { target_id, emoji }) => {
+            match history.number_of(&target_id) {
+                Some(number) => println!("{} {} reacted to [{}]", emoji, sender, number),
+                None => println!("{} {} reacted to an unknown message", emoji, sender),
+            }
+        }
+        (None, _) => {}
+        (Some(number), MessageType::Direct { .. }) => {
+            println!("{start}📩 [{}] {} (DM): {}{end}", number, sender, content);
+        }
+        (Some(number), MessageType::Broadcast) => match reply_to {
+            Some(parent) => println!("{start}↪️  [{}] {room}{} → [{}]: {}{end}", number, sender, parent, content),
+            None => println!("{start}💬 [{}] {room}{}: {}{end}", number, sender, content),
+        },
+    }
+    number
+}
+
+/// Room prefix shown before messages outside the default room
+fn room_tag(message: &ChatMessage) -> String {
+    match message.room_name() {
+        DEFAULT_ROOM => String::new(),
+        room => format!("#{room} "),
+    }
+}
+
+/// How our own messages are labelled
+fn own_label(message: &ChatMessage) -> String {
+    match &message.message_type {
+        MessageType::Direct { target_peer_id } => {
+            format!("You → {}", &target_peer_id[..12.min(target_peer_id.len())])
+        }
+        _ => "You".to_string(),
+    }
+}
+
+/// Print a connected peer as listed by `/peers`
+fn print_peer(peer: &PeerInfo) {
+    let name = peer.username.as_deref().unwrap_or("?");
+    let mut details = Vec::new();
+    if let Some(direction) = peer.direction {
+        details.push(match direction {
+            ConnectionDirection::Outbound => "outbound".to_string(),
+            ConnectionDirection::Inbound => "inbound".to_string(),
+        });
+    }
+    if peer.connections > 1 {
+        details.push(format!("{} connections", peer.connections));
+    }
+    if let Some(rtt) = peer.rtt_ms {
+        details.push(format!("{} ms", rtt));
+    }
+    if let Some(agent) = &peer.agent_version {
+        details.push(agent.clone());
+    }
+    println!("  • {} {} ({})", name, peer.peer_id, details.join(", "));
+    if let Some(version) = &peer.protocol_version {
+        println!("      {}: {}", version, peer.protocols.join(" "));
+    }
+    for address in &peer.addresses {
+        println!("      listens on {}", address);
+    }
+    if let Some(observed) = &peer.observed_addr {
+        println!("      sees us at {}", observed);
+    }
+}
+
 /// Handle network events from the chat client
-pub async fn handle_network_events(mut event_receiver: tokio::sync::mpsc::UnboundedReceiver<NetworkEvent>) {
+pub async fn handle_network_events(
+    mut event_receiver: EventStream,
+    state: SharedState,
+    notify_command: Option<String>,
+) {
     while let Some(event) = event_receiver.recv().await {
         match event {
             NetworkEvent::PeerDiscovered(peer_info) => {
+                state.lock().unwrap().saw_peer(&peer_info.peer_id, peer_info.username.as_deref());
                 println!("🔍 Discovered peer: {} ({})", 
                     &peer_info.peer_id[..12.min(peer_info.peer_id.len())], peer_info.addresses.len());
                 print!("> ");
                 io::stdout().flush().unwrap();
             }
             NetworkEvent::PeerConnected(peer_id) => {
+                state.lock().unwrap().saw_peer(&peer_id, None);
                 println!("✅ Connected to peer: {}", &peer_id[..12.min(peer_id.len())]);
                 print!("> ");
                 io::stdout().flush().unwrap();
@@ -89,31 +388,137 @@ pub async fn handle_network_events(mut event_receiver: tokio::sync::mpsc::Unboun
                 print!("> ");
                 io::stdout().flush().unwrap();
             }
-            NetworkEvent::MessageReceived(message) => {
-                match message.message_type {
-                    MessageType::Broadcast => {
-                        println!("💬 {}: {}", message.sender, message.content);
+            NetworkEvent::MessageReceived { message, mentions_me, clock_skew } => {
+                let mut sender = match clock_skew {
+                    Some(skew) => format!("{} (⚠️ clock off by {}s)", message.sender, skew),
+                    None => message.sender.clone(),
+                };
+                if let Some(peer_id) = &message.sender_peer_id {
+                    let impostor = (message.sender.clone(), peer_id.clone());
+                    if state.lock().unwrap().impostors.contains(&impostor) {
+                        sender = format!("{} (⚠️ not the pinned key, {})", sender, short_id(peer_id));
+                    }
+                }
+                if mentions_me {
+                    if let Some(command) = &notify_command {
+                        notify_mention(command, &message);
                     }
-                    MessageType::Direct { .. } => {
-                        println!("📩 {} (DM): {}", message.sender, message.content);
+                }
+                let mut state = state.lock().unwrap();
+                state.saw_author(&message);
+                // Delayed messages are shown when they arrive, flagged with where they belong
+                if let Some(next) = state.history.first_after(&message) {
+                    sender = format!("{} (late, belongs before [{}])", sender, next);
+                }
+                let number = print_message(&mut state.history, message, &sender, mentions_me);
+                if let (true, Some(number)) = (mentions_me, number) {
+                    state.mentions.push(number);
+                }
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::MessageSent(message) => {
+                let mut state = state.lock().unwrap();
+                // Messages that waited in the outbox were already shown when queued
+                match state.history.number_of(&message.id) {
+                    Some(number) => println!("✅ [{}] delivered", number),
+                    None => {
+                        print_message(&mut state.history, message.clone(), &own_label(&message), false);
                     }
                 }
                 print!("> ");
                 io::stdout().flush().unwrap();
             }
+            NetworkEvent::MessageQueued(message) => {
+                let label = format!("{} (queued until peers join)", own_label(&message));
+                print_message(&mut state.lock().unwrap().history, message, &label, false);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::MessageFailed { message, error } => {
+                match state.lock().unwrap().history.number_of(&message.id) {
+                    Some(number) => println!("❌ [{}] could not be sent: {}", number, error),
+                    None => println!("❌ Failed to send \"{}\": {}", message.content, error),
+                }
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            // Everyone is in the default room, so only announce the others
+            NetworkEvent::RoomPeerJoined { room, peer_id } if room != DEFAULT_ROOM => {
+                println!("👋 {} joined #{}", &peer_id[..12.min(peer_id.len())], room);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::RoomPeerLeft { room, peer_id } if room != DEFAULT_ROOM => {
+                println!("👋 {} left #{}", &peer_id[..12.min(peer_id.len())], room);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::RoomPeerJoined { .. } | NetworkEvent::RoomPeerLeft { .. } => {}
+            NetworkEvent::HistorySynced { peer_id, messages } => {
+                println!("📜 {} earlier messages from {}:", messages.len(), &peer_id[..12.min(peer_id.len())]);
+                let mut state = state.lock().unwrap();
+                for message in messages {
+                    state.saw_author(&message);
+                    let sender = message.sender.clone();
+                    print_message(&mut state.history, message, &sender, false);
+                }
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
             NetworkEvent::DhtBootstrapped => {
                 println!("🌐 DHT bootstrap successful! You can now discover and connect to peers.");
-                println!("Commands: /peers (list peers), /dm <peer_id> <message> (direct message)");
+                println!("Commands: /peers (list peers), /dm <name or peer id> <message> (direct message)");
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::DhtBootstrapFailed { error, retry_in_secs } => {
+                println!("⚠️  DHT bootstrap failed ({}), retrying in {}s", error, retry_in_secs);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::DhtBootstrapRecovered { failures } => {
+                println!("🌐 DHT reachable again after {} failed attempts", failures);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::ContactPinned { username, peer_id } => {
+                println!("📇 Pinned {} to key {}", username, short_id(&peer_id));
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::ContactKeyChanged { username, pinned_peer_id, peer_id, verified } => {
+                state.lock().unwrap().impostors.insert((username.clone(), peer_id.clone()));
+                let pinned = if verified { "verified" } else { "pinned" };
+                println!(
+                    "⚠️  {} is writing from key {}, not the {} key {}. This may be someone else using the name.",
+                    username,
+                    short_id(&peer_id),
+                    pinned,
+                    short_id(&pinned_peer_id)
+                );
+                println!("   If {} really changed keys, run /forget {} and then /verify {}", username, username, username);
+                print!("> ");
+                io::stdout().flush().unwrap();
+            }
+            NetworkEvent::EventsDropped { count } => {
+                println!("⚠️  Falling behind, skipped {} peer updates", count);
                 print!("> ");
                 io::stdout().flush().unwrap();
             }
+            NetworkEvent::Stopped { error } => match error {
+                Some(e) => println!("🛑 Network stopped: {}", e),
+                None => println!("🛑 Network stopped"),
+            },
             NetworkEvent::PeerListUpdated(peers) => {
                 if peers.is_empty() {
                     println!("No peers connected yet.");
                 } else {
                     println!("📋 Connected peers ({}):", peers.len());
+                    let mut state = state.lock().unwrap();
                     for peer in peers {
-                        println!("  • {} ({})", &peer.peer_id[..12.min(peer.peer_id.len())], peer.peer_id);
+                        state.saw_peer(&peer.peer_id, peer.username.as_deref());
+                        print_peer(&peer);
                     }
                 }
                 print!("> ");
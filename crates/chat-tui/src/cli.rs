@@ -0,0 +1,116 @@
+//! Non-interactive `send` and `listen` modes for scripts and CI jobs
+
+use anyhow::Result;
+use chat_core::{
+    start_chat_client, ChatMessage, EventFilter, EventKind, EventStream, MessageType, NetworkConfig, NetworkEvent,
+    DEFAULT_ROOM,
+};
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Nobody joined the room before the timeout
+const EXIT_NO_PEERS: u8 = 2;
+/// The message was rejected or could not be published
+const EXIT_SEND_FAILED: u8 = 3;
+/// The message was handed to the network but not confirmed in time
+const EXIT_UNCONFIRMED: u8 = 4;
+
+/// Wait until an event matching `done` arrives, or `deadline` passes
+async fn wait_for<T>(
+    events: &mut EventStream,
+    deadline: Instant,
+    mut done: impl FnMut(NetworkEvent) -> Option<T>,
+) -> Option<T> {
+    loop {
+        match tokio::time::timeout_at(deadline, events.recv()).await {
+            Ok(Some(event)) => {
+                if let Some(result) = done(event) {
+                    return Some(result);
+                }
+            }
+            Ok(None) | Err(_) => return None,
+        }
+    }
+}
+
+/// Publish one message to `room` once a member is reachable, and report
+/// the outcome through the exit code
+pub async fn send(config: NetworkConfig, username: String, room: String, content: String, timeout: Duration) -> Result<ExitCode> {
+    let deadline = Instant::now() + timeout;
+    let (client, mut events) = start_chat_client(config, username.clone()).await?;
+    if room != DEFAULT_ROOM {
+        client.join_room(room.clone()).await?;
+    }
+
+    let joined = wait_for(&mut events, deadline, |event| match event {
+        NetworkEvent::RoomPeerJoined { room: joined, .. } if joined == room => Some(()),
+        _ => None,
+    })
+    .await;
+    if joined.is_none() {
+        eprintln!("No peers joined room {room} within {}s", timeout.as_secs());
+        return Ok(ExitCode::from(EXIT_NO_PEERS));
+    }
+
+    let mut message = ChatMessage::new(username, content, MessageType::Broadcast);
+    message.room = (room != DEFAULT_ROOM).then_some(room);
+    let id = message.id.clone();
+    client.send_message(message).await?;
+
+    let outcome = wait_for(&mut events, deadline, |event| match event {
+        NetworkEvent::MessageSent(message) if message.id == id => Some(Ok(())),
+        NetworkEvent::MessageQueued(message) if message.id == id => {
+            Some(Err("no peers left in the room".to_string()))
+        }
+        NetworkEvent::MessageFailed { message, error } if message.id == id => Some(Err(error)),
+        _ => None,
+    })
+    .await;
+    match outcome {
+        Some(Ok(())) => {
+            // Shutting down lets the message leave our connections before we exit
+            client.shutdown().await?;
+            println!("{id}");
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Err(error)) => {
+            eprintln!("Failed to send message: {error}");
+            Ok(ExitCode::from(EXIT_SEND_FAILED))
+        }
+        None => {
+            eprintln!("Message was not confirmed within {}s", timeout.as_secs());
+            Ok(ExitCode::from(EXIT_UNCONFIRMED))
+        }
+    }
+}
+
+/// Print messages received in `room` and direct messages to us until the
+/// process is interrupted, one per line
+pub async fn listen(config: NetworkConfig, username: String, room: String, json: bool) -> Result<ExitCode> {
+    let (client, events) = start_chat_client(config, username).await?;
+    if room != DEFAULT_ROOM {
+        client.join_room(room.clone()).await?;
+    }
+    let mut events = events.with_filter(EventFilter::default().kinds([EventKind::Message]).room(room.clone()));
+
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            _ = tokio::signal::ctrl_c() => break,
+        };
+        let Some(event) = event else { break };
+        let NetworkEvent::MessageReceived { message, .. } = event else { continue };
+
+        let direct = matches!(message.message_type, MessageType::Direct { .. });
+        if json {
+            println!("{}", serde_json::to_string(&message)?);
+        } else if direct {
+            println!("{} (DM): {}", message.sender, message.content);
+        } else if !message.is_reaction() {
+            println!("#{} {}: {}", message.room_name(), message.sender, message.content);
+        }
+    }
+    client.shutdown().await?;
+    Ok(ExitCode::SUCCESS)
+}
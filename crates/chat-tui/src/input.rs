@@ -0,0 +1,159 @@
+//! Line editing and tab completion for the input loop
+
+use crate::app::{SessionState, SharedState};
+use anyhow::Result;
+use rustyline::completion::{Completer, Pair};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use std::sync::mpsc as std_mpsc;
+use tokio::sync::mpsc;
+
+/// Commands offered when completing the first word of a line
+const COMMANDS: &[&str] = &[
+    "/peers", "/list", "/dm", "/reply", "/react", "/thread", "/history", "/mentions", "/contacts", "/verify",
+    "/forget", "/join", "/leave", "/room", "quit", "exit",
+];
+
+/// Reads lines from the terminal on its own thread, which unlike tokio's
+/// blocking pool does not hold up shutdown while waiting for input.
+///
+/// The next line is only read once asked for, so the terminal is never
+/// left in raw mode by a read that outlives the input loop.
+pub struct LineReader {
+    requests: std_mpsc::Sender<()>,
+    lines: mpsc::Receiver<String>,
+}
+
+impl LineReader {
+    pub fn spawn(state: SharedState) -> Result<Self> {
+        let mut editor: Editor<InputHelper, DefaultHistory> = Editor::new()?;
+        editor.set_helper(Some(InputHelper { state }));
+        let (requests, pending) = std_mpsc::channel();
+        let (sender, lines) = mpsc::channel(1);
+        std::thread::spawn(move || {
+            while pending.recv().is_ok() {
+                // Ctrl-C and Ctrl-D end the input like `quit`
+                let line = match editor.readline("> ") {
+                    Ok(line) => line,
+                    Err(ReadlineError::Interrupted | ReadlineError::Eof) => break,
+                    Err(e) => {
+                        tracing::warn!("Failed to read input: {}", e);
+                        break;
+                    }
+                };
+                if !line.trim().is_empty() {
+                    let _ = editor.add_history_entry(line.as_str());
+                }
+                if sender.blocking_send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Self { requests, lines })
+    }
+
+    /// Read the next line, or `None` once input has ended
+    pub async fn next_line(&mut self) -> Option<String> {
+        self.requests.send(()).ok()?;
+        self.lines.recv().await
+    }
+}
+
+/// Completes command names, and usernames and peer ids after `/dm`
+pub struct InputHelper {
+    state: SharedState,
+}
+
+impl Completer for InputHelper {
+    type Candidate = Pair;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<Pair>)> {
+        Ok(complete_line(&self.state.lock().unwrap(), &line[..pos]))
+    }
+}
+
+impl Hinter for InputHelper {
+    type Hint = String;
+}
+
+impl Highlighter for InputHelper {}
+
+impl Validator for InputHelper {}
+
+impl Helper for InputHelper {}
+
+/// Candidates for the word ending the text before the cursor, and where
+/// that word starts
+fn complete_line(state: &SessionState, line: &str) -> (usize, Vec<Pair>) {
+    let start = line.rfind(' ').map_or(0, |i| i + 1);
+    let word = &line[start..];
+    if start == 0 {
+        let commands = COMMANDS
+            .iter()
+            .filter(|command| command.starts_with(word))
+            .map(|command| Pair { display: command.to_string(), replacement: format!("{command} ") })
+            .collect();
+        return (start, commands);
+    }
+    // Only the recipient of a direct message names a peer
+    if line.strip_prefix("/dm ").is_none_or(|args| !args[..start - 4].trim().is_empty()) {
+        return (start, Vec::new());
+    }
+
+    let word = word.trim_start_matches('@');
+    let mut candidates: Vec<Pair> = Vec::new();
+    for (peer_id, username) in &state.peers {
+        let short = &peer_id[..12.min(peer_id.len())];
+        let by_name = username
+            .as_deref()
+            .filter(|name| !name.contains(char::is_whitespace))
+            .filter(|name| name.to_lowercase().starts_with(&word.to_lowercase()));
+        let candidate = match by_name {
+            Some(name) => Pair { display: format!("{name} ({short})"), replacement: format!("{name} ") },
+            None if peer_id.starts_with(word) => Pair {
+                display: match username {
+                    Some(name) => format!("{short} ({name})"),
+                    None => short.to_string(),
+                },
+                replacement: format!("{peer_id} "),
+            },
+            None => continue,
+        };
+        // Peers sharing a name complete to the same text
+        if !candidates.iter().any(|other| other.replacement == candidate.replacement) {
+            candidates.push(candidate);
+        }
+    }
+    candidates.sort_by(|a, b| a.display.cmp(&b.display));
+    (start, candidates)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_complete_commands_and_peers() {
+        let mut state = SessionState::default();
+        let alice = "12D3KooWAlice0000000000000000000000000000000000000000".to_string();
+        let bob = "12D3KooWBob000000000000000000000000000000000000000000".to_string();
+        state.saw_peer(&alice, Some("alice"));
+        state.saw_peer(&bob, None);
+
+        let replacements = |line: &str| -> Vec<String> {
+            complete_line(&state, line).1.into_iter().map(|pair| pair.replacement).collect()
+        };
+        assert_eq!(replacements("/d"), ["/dm "]);
+        assert_eq!(replacements("/dm al"), ["alice "]);
+        assert_eq!(replacements("/dm @Al"), ["alice "]);
+        assert_eq!(replacements("/dm 12D3KooWB"), [format!("{bob} ")]);
+        assert_eq!(replacements("/dm 12D3KooW").len(), 2);
+        // The message itself is not completed
+        assert!(replacements("/dm alice al").is_empty());
+        assert_eq!(complete_line(&state, "/dm al").0, 4);
+    }
+}
@@ -1,88 +1,183 @@
 mod app;
+mod cli;
+mod input;
 
 use anyhow::Result;
-use app::{handle_network_events, ChatApp};
-use chat_core::{start_chat_client, NetworkConfig};
-use clap::Parser;
-use tracing::{info, warn};
+use app::{handle_network_events, ChatApp, SessionState};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use chat_core::{config, start_chat_client, Settings, WireFormat, DEFAULT_ROOM};
+use clap::{Parser, Subcommand};
+use tracing::info;
 
 #[derive(Parser)]
 #[command(name = "chat-tui")]
 #[command(about = "A P2P chat application using DHT for peer discovery")]
 struct Args {
-    /// Port to listen on
-    #[arg(short, long, default_value = "0")]
-    port: u16,
+    /// Port to listen on (default: chosen by the OS)
+    #[arg(short, long, env = "P2P_CHAT_PORT", global = true)]
+    port: Option<u16>,
     
-    /// Your username for the chat
-    #[arg(short, long, default_value = "Anonymous")]
-    username: String,
+    /// Your username for the chat (default: Anonymous)
+    #[arg(short, long, env = "P2P_CHAT_USERNAME", global = true)]
+    username: Option<String>,
     
-    /// Bootstrap node address (optional, uses default if not provided)
-    #[arg(short, long)]
-    bootstrap: Option<String>,
+    /// Bootstrap node address; repeat for several (default: built-in bootstrap node)
+    #[arg(short, long, env = "P2P_CHAT_BOOTSTRAP", value_delimiter = ',', global = true)]
+    bootstrap: Vec<String>,
     
     /// Path to the peer keypair file (default: peer_key.dat)
-    #[arg(short, long, default_value = "peer_key.dat")]
-    key_file: String,
+    #[arg(short, long, env = "P2P_CHAT_KEY_FILE", global = true)]
+    key_file: Option<String>,
+
+    /// Settings file (default: config.toml in the user's config directory, if present)
+    #[arg(long, env = "P2P_CHAT_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile from the settings file, e.g. to run a second identity
+    #[arg(long, env = "P2P_CHAT_PROFILE", global = true)]
+    profile: Option<String>,
+
+    /// Shell command to run when someone mentions you; the sender and message
+    /// are passed in the CHAT_SENDER and CHAT_MESSAGE environment variables
+    #[arg(long)]
+    notify_command: Option<String>,
+
+    /// Send messages in the JSON envelope (for debugging); peers older than
+    /// the envelope can't read them
+    #[arg(long, global = true)]
+    json_wire: bool,
+
+    /// Send messages in the compact CBOR envelope; peers older than the
+    /// envelope can't read them
+    #[arg(long, global = true, conflicts_with = "json_wire")]
+    cbor_wire: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Non-interactive modes; without one the interactive chat starts
+#[derive(Subcommand)]
+enum Command {
+    /// Send one message and exit once it is delivered.
+    /// Exits with 2 if nobody joined the room, 3 if sending failed
+    /// and 4 if delivery was not confirmed in time
+    Send {
+        /// Room to send to
+        #[arg(short, long, default_value = DEFAULT_ROOM)]
+        room: String,
+
+        /// Seconds to wait for room members and delivery
+        #[arg(short, long, default_value = "30")]
+        timeout: u64,
+
+        /// Message text
+        message: String,
+    },
+    /// Print received messages until interrupted
+    Listen {
+        /// Room to listen to; direct messages are always shown
+        #[arg(short, long, default_value = DEFAULT_ROOM)]
+        room: String,
+
+        /// Print each message as a JSON object on its own line
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 #[tokio::main]
-async fn main() -> Result<()> {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+async fn main() -> Result<ExitCode> {
+    // Initialize tracing; logs go to stderr so stdout stays usable in scripts
+    tracing_subscriber::fmt().with_writer(std::io::stderr).init();
     
     let args = Args::parse();
     
     info!("Starting P2P Chat TUI");
-    info!("Username: {}", args.username);
-    info!("Port: {}", args.port);
-    
-    // Create network configuration
-    let mut config = NetworkConfig::default();
-    config.listen_port = args.port;
-    config.key_file = args.key_file;
+
+    // Settings file, then environment and flags on top
+    let mut flags = Settings {
+        username: args.username,
+        key_file: args.key_file,
+        port: args.port,
+        bootstrap: (!args.bootstrap.is_empty()).then_some(args.bootstrap),
+        ..Default::default()
+    };
+    if args.json_wire {
+        flags.transport.wire_format = Some(WireFormat::Json);
+    } else if args.cbor_wire {
+        flags.transport.wire_format = Some(WireFormat::Cbor);
+    }
+    let settings = config::load(args.config.as_deref(), args.profile.as_deref())?.merge(flags);
+    let username = settings.username.clone().unwrap_or_else(|| "Anonymous".to_string());
+    let mut config = settings.network_config()?;
+    info!("Username: {}", username);
     
-    // Override bootstrap node if provided
-    if let Some(bootstrap_addr) = args.bootstrap {
-        match bootstrap_addr.parse() {
-            Ok(addr) => {
-                config.dht_config.bootstrap_nodes = vec![addr];
-                info!("Using custom bootstrap node: {}", bootstrap_addr);
-            }
-            Err(e) => {
-                warn!("Invalid bootstrap address: {}. Using default.", e);
-            }
+    match args.command {
+        Some(Command::Send { room, timeout, message }) => {
+            chat_core::validate_room_name(&room)?;
+            // Nothing should linger in the outbox after a one-shot send exits
+            config.outbox_file = None;
+            return cli::send(config, username, room, message, Duration::from_secs(timeout)).await;
+        }
+        Some(Command::Listen { room, json }) => {
+            chat_core::validate_room_name(&room)?;
+            return cli::listen(config, username, room, json).await;
         }
+        None => {}
     }
-    
+
     // Start chat client with DHT
-    let (client, event_receiver) = start_chat_client(config, args.username.clone()).await?;
+    let (client, event_receiver) = start_chat_client(config, username.clone()).await?;
     
     println!("🚀 P2P Chat started!");
-    println!("Username: {}", args.username);
+    println!("Username: {}", username);
     println!("Connecting to DHT and discovering peers...");
     println!();
     println!("📖 Commands:");
     println!("  • Type messages to broadcast to all peers");
     println!("  • /peers or /list - Show connected peers");
-    println!("  • /dm <peer_id> <message> - Send direct message");
+    println!("  • /dm <name or peer id> <message> - Send direct message (Tab completes)");
+    println!("  • /reply <n> <message> - Reply to message [n]");
+    println!("  • /react <n> <emoji> - React to message [n]");
+    println!("  • /thread <n> - Show the thread containing message [n]");
+    println!("  • /history - Show all messages in causal order");
+    println!("  • /mentions - List messages that mention you");
+    println!("  • /contacts - List usernames pinned to their keys");
+    println!("  • /verify <name> [confirm] - Compare safety numbers with a contact");
+    println!("  • /forget <name> - Unpin a contact who changed keys");
+    println!("  • /join <room> - Join a room");
+    println!("  • /leave <room> - Leave a room");
+    println!("  • /room <room> <message> - Send a message to a room");
     println!("  • quit or exit - Exit the chat");
     println!();
     
     // Create chat app
-    let app = ChatApp::new(client);
+    let state = Arc::new(Mutex::new(SessionState::default()));
+    let app = ChatApp::new(client.clone(), state.clone());
     
     // Start network event handler
-    let event_handle = tokio::spawn(handle_network_events(event_receiver));
+    let event_handle = tokio::spawn(handle_network_events(event_receiver, state, args.notify_command));
     
-    // Handle user input
+    // Handle user input until the user quits or presses Ctrl-C
     println!("Chat is ready! Start typing messages:");
-    let _ = app.handle_user_input().await;
+    tokio::select! {
+        _ = app.handle_user_input() => {}
+        _ = tokio::signal::ctrl_c() => println!(),
+    }
     
-    // Cancel event handler
-    event_handle.abort();
+    // Leave cleanly, then let the event handler print what is left
+    println!("Shutting down...");
+    let result = client.shutdown().await;
+    let _ = event_handle.await;
+    if let Err(e) = result {
+        eprintln!("Network did not shut down cleanly: {}", e);
+        return Ok(ExitCode::FAILURE);
+    }
     
     println!("Goodbye!");
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }